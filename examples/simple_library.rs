@@ -1,3 +1,4 @@
+use auden::runtime::build_runtime;
 use auden::semantic_index::SemanticIndex;
 use std::fs;
 use std::path::PathBuf;
@@ -84,18 +85,5 @@ fn main() {
 }
 
 fn main() {
-    // This is required for surrealdb recursive queries
-    // https://github.com/surrealdb/surrealdb/issues/2920
-    let stack_size = 10 * 1024 * 1024;
-
-    // Stack frames are generally larger in debug mode.
-    #[cfg(debug_assertions)]
-    let stack_size = stack_size * 2;
-
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .thread_stack_size(stack_size)
-        .build()
-        .unwrap()
-        .block_on(run_example());
+    build_runtime().unwrap().block_on(run_example());
 }
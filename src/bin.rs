@@ -2,35 +2,139 @@ use anyhow::anyhow;
 use homedir::get_my_home;
 use tonic::{transport::Server, Request, Response, Status};
 
+use auden::runtime::build_runtime;
+#[cfg(feature = "onnx")]
+use auden::semantic_index::DatabaseOptions;
 use auden::semantic_index::IndexingStatus;
-use auden::semantic_index::SemanticIndex;
+use auden::semantic_index::{
+    IndexCanceller, SearchOptions, SearchResult, SemanticIndex, SimilarityMetric,
+};
 use auden_grpc::auden_server::{Auden, AudenServer};
 use auden_grpc::{
-    IndexReply, IndexRequest, SearchReply, SearchRequest, SearchResultReply, StatusReply,
-    StatusRequest,
+    CancelReply, CancelRequest, DirectoryReply, IndexReply, IndexRequest, ListDirectoriesReply,
+    ListDirectoriesRequest, MetricReply, MetricsReply, MetricsRequest, RemoveReply, RemoveRequest,
+    SearchReply, SearchRequest, SearchResultReply, StatusReply, StatusRequest,
 };
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
 
 pub mod auden_grpc {
     tonic::include_proto!("auden_grpc");
 }
 
+/// The number of streamed search results buffered ahead of a slow client.
+const STREAM_BUFFER: usize = 16;
+
+/// Options the server is started with, parsed from its command line.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerOptions {
+    /// Strip the searched directory from result paths, set with `--relative-paths`.
+    pub relative_paths: bool,
+}
+
+impl ServerOptions {
+    /// Parses the arguments following the program name, failing on any not recognised.
+    pub fn from_args(args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut options = ServerOptions::default();
+        for arg in args {
+            match arg.as_str() {
+                "--relative-paths" => options.relative_paths = true,
+                _ => return Err(anyhow!("unknown argument: {}", arg)),
+            }
+        }
+        anyhow::Ok(options)
+    }
+}
+
 pub struct AudenAgent {
     index: Arc<Mutex<SemanticIndex>>,
+    // Held outside of the index lock, which is held for the whole of a directory walk
+    canceller: IndexCanceller,
+    // Strip the indexed directory from result paths, so replies don't leak the server's
+    // filesystem layout. Clients can still request absolute paths per search.
+    relative_paths: bool,
+    // Keep indexed directories fresh, by watching them for changes once indexed
+    watch: bool,
+}
+
+fn display_path(path: &Path, directory: &Path, relative: bool) -> String {
+    if relative {
+        if let Ok(relative_path) = path.strip_prefix(directory) {
+            return relative_path.to_string_lossy().to_string();
+        }
+    }
+    path.to_string_lossy().to_string()
+}
+
+fn status_reply(status: IndexingStatus) -> StatusReply {
+    StatusReply {
+        status: status.to_string(),
+        outstanding: status.outstanding().unwrap_or(0) as i32,
+    }
+}
+
+fn search_result_reply(
+    result: &SearchResult,
+    directory: &Path,
+    relative: bool,
+) -> SearchResultReply {
+    SearchResultReply {
+        id: result.id.id.to_string(),
+        start_byte: result.start_byte as i32,
+        end_byte: result.end_byte as i32,
+        path: display_path(&result.path, directory, relative),
+        content: result.content.clone().unwrap_or_default(),
+        start_line: result.start_line.map(|line| line as i32),
+        start_col: result.start_col.map(|column| column as i32),
+        end_line: result.end_line.map(|line| line as i32),
+        end_col: result.end_col.map(|column| column as i32),
+    }
 }
 
 impl AudenAgent {
-    pub async fn new() -> anyhow::Result<Self> {
+    pub async fn new(options: ServerOptions) -> anyhow::Result<Self> {
         let database_dir = get_my_home()?
             .ok_or(anyhow!("cant find home directory"))?
             .as_path()
             .join(".auden")
             .join("db");
 
-        let index = Arc::new(Mutex::new(SemanticIndex::new(database_dir).await?));
-        anyhow::Ok(AudenAgent { index })
+        let watch = std::env::var("AUDEN_WATCH")
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        // Embed locally with an ONNX model when one is configured, rather than through OpenAI
+        #[cfg(feature = "onnx")]
+        let index = match std::env::var("AUDEN_ONNX_MODEL") {
+            Ok(model_directory) => {
+                let provider = auden::embedding::onnx::OnnxEmbeddingProvider::from_directory(
+                    Path::new(&model_directory),
+                )?;
+                SemanticIndex::new_with_provider(
+                    database_dir,
+                    Arc::new(provider),
+                    DatabaseOptions::default(),
+                )
+                .await?
+            }
+            Err(_) => SemanticIndex::new(database_dir).await?,
+        };
+        #[cfg(not(feature = "onnx"))]
+        let index = SemanticIndex::new(database_dir).await?;
+        anyhow::Ok(AudenAgent::with_index(index, options, watch))
+    }
+
+    /// Serves the given index, rather than one opened in the home directory.
+    pub fn with_index(index: SemanticIndex, options: ServerOptions, watch: bool) -> Self {
+        let canceller = index.canceller();
+        AudenAgent {
+            index: Arc::new(Mutex::new(index)),
+            canceller,
+            relative_paths: options.relative_paths,
+            watch,
+        }
     }
 }
 
@@ -44,6 +148,11 @@ impl Auden for AudenAgent {
 
         let path = PathBuf::from(request.into_inner().path);
         let indexing = index.index_directory(path.clone()).await;
+        if indexing.is_ok() && self.watch {
+            if let Err(err) = index.watch_directory(path.clone()).await {
+                log::warn!("failed to watch {:?}: {:?}", path, err);
+            }
+        }
         let reply = match indexing {
             Ok(_) => IndexReply {
                 code: 0,
@@ -58,6 +167,75 @@ impl Auden for AudenAgent {
         Ok(Response::new(reply))
     }
 
+    async fn cancel_index(
+        &self,
+        request: Request<CancelRequest>,
+    ) -> Result<Response<CancelReply>, Status> {
+        let path = PathBuf::from(request.into_inner().path);
+        let reply = match self.canceller.cancel(&path) {
+            Ok(_) => CancelReply {
+                code: 0,
+                status: format!("Cancelled indexing {:?}", path),
+            },
+            Err(err) => CancelReply {
+                code: 1,
+                status: format!("Failed to cancel indexing: {:?}", err),
+            },
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn remove_directory(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> Result<Response<RemoveReply>, Status> {
+        let mut index = self.index.lock().await;
+
+        let path = PathBuf::from(request.into_inner().path);
+        let reply = match index.remove_directory(path.clone()).await {
+            Ok(_) => RemoveReply {
+                code: 0,
+                status: format!("Removed {:?}", path),
+            },
+            Err(err) => RemoveReply {
+                code: 1,
+                status: format!("Failed to remove directory: {:?}", err),
+            },
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn list_directories(
+        &self,
+        _request: Request<ListDirectoriesRequest>,
+    ) -> Result<Response<ListDirectoriesReply>, Status> {
+        let index = self.index.lock().await;
+
+        let reply = match index.list_directories().await {
+            Ok(directories) => ListDirectoriesReply {
+                code: 0,
+                message: String::new(),
+                directories: directories
+                    .into_iter()
+                    .map(|(path, status)| DirectoryReply {
+                        path: path.to_string_lossy().to_string(),
+                        status: status.to_string(),
+                        outstanding: status.outstanding().unwrap_or(0) as i32,
+                    })
+                    .collect(),
+            },
+            Err(err) => ListDirectoriesReply {
+                code: 1,
+                message: format!("Failed to list directories: {:?}", err),
+                directories: vec![],
+            },
+        };
+
+        Ok(Response::new(reply))
+    }
+
     async fn indexing_status(
         &self,
         request: Request<StatusRequest>,
@@ -67,18 +245,29 @@ impl Auden for AudenAgent {
         let path = PathBuf::from(request.into_inner().path);
         let status = index.get_status(path.clone()).await;
 
-        let reply = match status {
-            IndexingStatus::Indexing { jobs_outstanding } => StatusReply {
-                status: status.to_string(),
-                outstanding: jobs_outstanding as i32,
-            },
-            _ => StatusReply {
-                status: status.to_string(),
-                outstanding: 0,
-            },
-        };
+        Ok(Response::new(status_reply(status)))
+    }
 
-        Ok(Response::new(reply))
+    type WatchStatusStream = ReceiverStream<Result<StatusReply, Status>>;
+
+    async fn watch_status(
+        &self,
+        request: Request<StatusRequest>,
+    ) -> Result<Response<Self::WatchStatusStream>, Status> {
+        let path = PathBuf::from(request.into_inner().path);
+        let mut statuses = self.index.lock().await.watch_status(path);
+
+        // Each change in outstanding jobs is forwarded, ending once the directory is indexed
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            while let Some(status) = statuses.recv().await {
+                if sender.send(Ok(status_reply(status))).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
     }
 
     async fn search_directory(
@@ -90,18 +279,38 @@ impl Auden for AudenAgent {
         let path = PathBuf::from(request.path);
         let n = request.n as usize;
         let search_query = request.query;
+        let relative_paths = self.relative_paths && !request.absolute_paths;
+
+        let metric = if request.metric.is_empty() {
+            SimilarityMetric::default()
+        } else {
+            match SimilarityMetric::from_name(&request.metric) {
+                Some(metric) => metric,
+                None => {
+                    return Ok(Response::new(SearchReply {
+                        code: 1,
+                        message: format!("Unknown similarity metric: {}", request.metric),
+                        result: vec![],
+                    }))
+                }
+            }
+        };
 
-        let search_results = index.search_directory(path, n, search_query.as_str()).await;
+        let options = SearchOptions {
+            min_similarity: request.min_similarity,
+            with_content: request.with_content,
+            negative: request.negative,
+            metric,
+            ..SearchOptions::default()
+        };
+        let search_results = index
+            .search_directory_with_options(path.clone(), n, search_query.as_str(), options)
+            .await;
         let reply = match search_results {
             Ok(results) => {
                 let search_results = results
                     .iter()
-                    .map(|result| SearchResultReply {
-                        id: result.id.id.to_string(),
-                        start_byte: result.start_byte as i32,
-                        end_byte: result.end_byte as i32,
-                        path: result.path.to_string_lossy().to_string(),
-                    })
+                    .map(|result| search_result_reply(result, &path, relative_paths))
                     .collect::<Vec<SearchResultReply>>();
 
                 SearchReply {
@@ -119,6 +328,81 @@ impl Auden for AudenAgent {
 
         Ok(Response::new(reply))
     }
+
+    type SearchDirectoryStreamStream = ReceiverStream<Result<SearchResultReply, Status>>;
+
+    async fn search_directory_stream(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<Self::SearchDirectoryStreamStream>, Status> {
+        let request = request.into_inner();
+        let path = PathBuf::from(request.path);
+        let n = request.n as usize;
+        let relative_paths = self.relative_paths && !request.absolute_paths;
+
+        // Streamed results are paged by cosine similarity, so other metrics can't be honoured
+        let metric = if request.metric.is_empty() {
+            SimilarityMetric::default()
+        } else {
+            SimilarityMetric::from_name(&request.metric).ok_or_else(|| {
+                Status::invalid_argument(format!("Unknown similarity metric: {}", request.metric))
+            })?
+        };
+        if metric != SimilarityMetric::Cosine {
+            return Err(Status::invalid_argument(format!(
+                "Streamed searches are ranked by cosine similarity, not {}",
+                metric.name()
+            )));
+        }
+
+        let options = SearchOptions {
+            min_similarity: request.min_similarity,
+            with_content: request.with_content,
+            negative: request.negative,
+            metric,
+            ..SearchOptions::default()
+        };
+        let mut search_results = {
+            let index = self.index.lock().await;
+            index
+                .search_directory_stream(path.clone(), n, request.query.as_str(), options)
+                .await
+                .map_err(|err| Status::internal(format!("Failed to search directory: {:?}", err)))?
+        };
+
+        // Each result is forwarded as its own message as soon as it is fetched, so clients can
+        // render results as they arrive
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            while let Some(result) = search_results.recv().await {
+                let reply = result
+                    .map(|result| search_result_reply(&result, &path, relative_paths))
+                    .map_err(|err| {
+                        Status::internal(format!("Failed to search directory: {:?}", err))
+                    });
+                if sender.send(reply).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn available_metrics(
+        &self,
+        _request: Request<MetricsRequest>,
+    ) -> Result<Response<MetricsReply>, Status> {
+        let metrics = SemanticIndex::available_metrics()
+            .into_iter()
+            .map(|metric| MetricReply {
+                name: metric.name,
+                higher_is_better: metric.higher_is_better,
+            })
+            .collect::<Vec<MetricReply>>();
+
+        Ok(Response::new(MetricsReply { metrics }))
+    }
 }
 
 // #[tokio::main]
@@ -126,30 +410,153 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     simple_logger::init_with_env().unwrap();
 
     let addr = "[::1]:50051".parse()?;
+    let options = ServerOptions::from_args(std::env::args().skip(1))?;
 
-    // This is required for surrealdb recursive queries
-    // https://github.com/surrealdb/surrealdb/issues/2920
-    let stack_size = 10 * 1024 * 1024;
-
-    // Stack frames are generally larger in debug mode.
-    #[cfg(debug_assertions)]
-    let stack_size = stack_size * 2;
-
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .thread_stack_size(stack_size)
-        .build()
-        .unwrap()
-        .block_on(async {
-            if let Some(agent) = AudenAgent::new().await.ok() {
-                let _ = Server::builder()
-                    .add_service(AudenServer::new(agent))
-                    .serve(addr)
-                    .await;
-            }
+    build_runtime().unwrap().block_on(async {
+        if let Some(agent) = AudenAgent::new(options).await.ok() {
+            let _ = Server::builder()
+                .add_service(AudenServer::new(agent))
+                .serve(addr)
+                .await;
+        }
 
-            loop {}
-        });
+        loop {}
+    });
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auden::embedding::base::BagOfWordsEmbeddingProvider;
+    use auden_grpc::auden_client::AudenClient;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_display_path() {
+        let directory = PathBuf::from("/home/user/repo");
+        let path = PathBuf::from("/home/user/repo/src/lib.rs");
+
+        assert_eq!(display_path(&path, &directory, true), "src/lib.rs");
+        assert_eq!(
+            display_path(&path, &directory, false),
+            "/home/user/repo/src/lib.rs"
+        );
+    }
+
+    #[test]
+    fn test_server_options_from_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+        let options = ServerOptions::from_args(args(&[]).into_iter()).unwrap();
+        assert!(!options.relative_paths);
+        let options = ServerOptions::from_args(args(&["--relative-paths"]).into_iter()).unwrap();
+        assert!(options.relative_paths);
+        assert!(ServerOptions::from_args(args(&["--relative"]).into_iter()).is_err());
+    }
+
+    async fn _test_search_relative_paths() {
+        let database_dir = tempdir().unwrap();
+        let index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            auden::semantic_index::DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+        let options = ServerOptions {
+            relative_paths: true,
+        };
+        let agent = AudenAgent::with_index(index, options, false);
+
+        // Serve on a free port, accepting connections from the listener already bound to it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        tokio::spawn(
+            Server::builder()
+                .add_service(AudenServer::new(agent))
+                .serve_with_incoming(incoming),
+        );
+        let mut client = AudenClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+
+        let directory = tempdir().unwrap();
+        std::fs::create_dir(directory.path().join("src")).unwrap();
+        std::fs::write(directory.path().join("src/lib.rs"), "struct Foo {}\n").unwrap();
+        let path = directory.path().to_string_lossy().to_string();
+        let reply = client
+            .index_directory(IndexRequest { path: path.clone() })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(reply.code, 0);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let status = client
+                    .indexing_status(StatusRequest { path: path.clone() })
+                    .await
+                    .unwrap()
+                    .into_inner();
+                if status.status == "Indexed" {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let search = |absolute_paths| SearchRequest {
+            path: path.clone(),
+            query: "struct Foo".to_string(),
+            n: 10,
+            absolute_paths,
+            ..SearchRequest::default()
+        };
+        let reply = client
+            .search_directory(search(false))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(reply.code, 0);
+        let paths = reply
+            .result
+            .into_iter()
+            .map(|result| result.path)
+            .collect::<Vec<String>>();
+        assert_eq!(
+            paths,
+            vec![Path::new("src").join("lib.rs").to_string_lossy()]
+        );
+
+        // Clients can still ask for absolute paths
+        let reply = client
+            .search_directory(search(true))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            reply.result[0].path,
+            directory
+                .path()
+                .join("src")
+                .join("lib.rs")
+                .to_string_lossy()
+        );
+    }
+
+    #[test]
+    fn test_search_relative_paths() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_relative_paths())
+    }
+}
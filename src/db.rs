@@ -1,21 +1,33 @@
-use crate::parsers::strategy::FileContext;
+use crate::migrations::run_migrations;
+use crate::parsers::strategy::{ContextDocument, FileContext, UNRECORDED_TEMPLATE_VERSION};
+use crate::quantization::{cosine_similarity, dot_product, euclidean_distance, QuantizedEmbedding};
 use anyhow::anyhow;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::iter::FromIterator;
+use std::ops::Range;
 use std::path::PathBuf;
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use surrealdb::engine::local::RocksDb;
 use surrealdb::opt::RecordId;
 use surrealdb::sql::Thing;
-use surrealdb::Surreal;
+use surrealdb::{Action, Notification, Surreal};
 use tokio::sync::oneshot;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, watch, Mutex};
+
+/// Shas shared by more than one span, with the number of spans sharing each.
+type DuplicateShas = Vec<(Vec<u8>, usize)>;
 
 pub(crate) enum DatabaseJob {
     GetEmbeddingsForDirectory {
         path: PathBuf,
+        template_version: u32,
         sender: oneshot::Sender<anyhow::Result<HashMap<Vec<u8>, Vec<f32>>>>,
     },
     GetOrCreateDirectory {
@@ -30,6 +42,9 @@ pub(crate) enum DatabaseJob {
         path: PathBuf,
         embedding: Vec<f32>,
         n: usize,
+        metric: SimilarityMetric,
+        test_filter: TestFilter,
+        min_similarity: Option<f32>,
         sender: oneshot::Sender<anyhow::Result<Vec<SearchResult>>>,
     },
     GetPathsForDirectory {
@@ -40,6 +55,63 @@ pub(crate) enum DatabaseJob {
         path: PathBuf,
         sender: oneshot::Sender<anyhow::Result<()>>,
     },
+    WatchPersistedFiles {
+        path: PathBuf,
+        sender: oneshot::Sender<anyhow::Result<watch::Receiver<usize>>>,
+    },
+    GetDuplicateShas {
+        sender: oneshot::Sender<anyhow::Result<DuplicateShas>>,
+    },
+    RankFileSpans {
+        path: PathBuf,
+        embedding: Vec<f32>,
+        sender: oneshot::Sender<anyhow::Result<Vec<SearchResult>>>,
+    },
+    SearchDirectoryPage {
+        path: PathBuf,
+        embedding: Vec<f32>,
+        n: usize,
+        cursor: Option<SearchCursor>,
+        sender: oneshot::Sender<anyhow::Result<SearchPage>>,
+    },
+    SetSpanDescription {
+        span_id: RecordId,
+        sha: Vec<u8>,
+        embedding: Vec<f32>,
+        sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Shutdown {
+        sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    DeleteDirectory {
+        path: PathBuf,
+        sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    GetStrategyVersionsForDirectory {
+        path: PathBuf,
+        sender: oneshot::Sender<anyhow::Result<HashMap<PathBuf, Option<Vec<u8>>>>>,
+    },
+    SwapDirectoryTemplateVersion {
+        path: PathBuf,
+        template_version: u32,
+        sender: oneshot::Sender<anyhow::Result<Option<u32>>>,
+    },
+    SearchAll {
+        embedding: Vec<f32>,
+        n: usize,
+        sender: oneshot::Sender<anyhow::Result<Vec<SearchResult>>>,
+    },
+    GetDirectories {
+        sender: oneshot::Sender<anyhow::Result<Vec<PathBuf>>>,
+    },
+    GetSymbolKindFacets {
+        path: PathBuf,
+        embedding: Vec<f32>,
+        metric: SimilarityMetric,
+        test_filter: TestFilter,
+        min_similarity: Option<f32>,
+        sender: oneshot::Sender<anyhow::Result<HashMap<String, usize>>>,
+    },
 }
 
 impl fmt::Debug for DatabaseJob {
@@ -63,23 +135,437 @@ impl fmt::Debug for DatabaseJob {
             DatabaseJob::DeletePathAndSpans { .. } => {
                 write!(f, "DatabaseJob::DeletePathAndSpans",)
             }
+            DatabaseJob::WatchPersistedFiles { .. } => {
+                write!(f, "DatabaseJob::WatchPersistedFiles",)
+            }
+            DatabaseJob::GetDuplicateShas { .. } => {
+                write!(f, "DatabaseJob::GetDuplicateShas",)
+            }
+            DatabaseJob::RankFileSpans { .. } => {
+                write!(f, "DatabaseJob::RankFileSpans",)
+            }
+            DatabaseJob::SearchDirectoryPage { .. } => {
+                write!(f, "DatabaseJob::SearchDirectoryPage",)
+            }
+            DatabaseJob::SetSpanDescription { .. } => {
+                write!(f, "DatabaseJob::SetSpanDescription",)
+            }
+            DatabaseJob::Shutdown { .. } => {
+                write!(f, "DatabaseJob::Shutdown",)
+            }
+            DatabaseJob::DeleteDirectory { .. } => {
+                write!(f, "DatabaseJob::DeleteDirectory",)
+            }
+            DatabaseJob::GetStrategyVersionsForDirectory { .. } => {
+                write!(f, "DatabaseJob::GetStrategyVersionsForDirectory",)
+            }
+            DatabaseJob::SwapDirectoryTemplateVersion { .. } => {
+                write!(f, "DatabaseJob::SwapDirectoryTemplateVersion",)
+            }
+            DatabaseJob::SearchAll { .. } => {
+                write!(f, "DatabaseJob::SearchAll",)
+            }
+            DatabaseJob::GetDirectories { .. } => {
+                write!(f, "DatabaseJob::GetDirectories",)
+            }
+            DatabaseJob::GetSymbolKindFacets { .. } => {
+                write!(f, "DatabaseJob::GetSymbolKindFacets",)
+            }
+        }
+    }
+}
+
+/// How span embeddings are persisted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EmbeddingStorage {
+    /// Store the full precision embedding, searched directly in the database.
+    #[default]
+    Full,
+    /// Store an int8 scalar quantized embedding, dequantized at search time, reducing storage
+    /// at a small cost to precision.
+    Quantized,
+}
+
+/// Whether span content is persisted alongside the index.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ContentStorage {
+    /// Only span locations are stored, content is read from the file when requested.
+    #[default]
+    Unstored,
+    /// Store each span's content in a blob keyed by the sha of the content, so spans with
+    /// identical content share a blob, and search results carry their content without reading
+    /// the file. Blobs are kept when spans are removed, as other spans may share them.
+    Blob,
+}
+
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    pub embedding_storage: EmbeddingStorage,
+    /// The capacity of the channel queueing jobs for the database executor.
+    pub channel_capacity: usize,
+    /// The number of attempts made to open the database before giving up, as opening can
+    /// transiently fail while another process releases its lock.
+    pub initialize_attempts: usize,
+    /// The delay before the first retry of a failed open, doubling after each attempt.
+    pub initialize_backoff: Duration,
+    pub content_storage: ContentStorage,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        DatabaseOptions {
+            embedding_storage: EmbeddingStorage::default(),
+            channel_capacity: 1000,
+            initialize_attempts: 5,
+            initialize_backoff: Duration::from_millis(250),
+            content_storage: ContentStorage::default(),
         }
     }
 }
 
+/// The number of items processed by a background loop between yields to the runtime, so
+/// bulk indexing doesn't starve searches on a single threaded runtime.
+pub(crate) const YIELD_INTERVAL: usize = 32;
+
+/// The fraction of the executor channel capacity remaining, below which backpressure is logged.
+const BACKPRESSURE_THRESHOLD: f32 = 0.1;
+
 #[derive(Debug, Deserialize)]
 struct EmbeddingBySha {
     sha: Vec<u8>,
     embedding: Vec<f32>,
+    quantized: Option<Vec<i8>>,
+    scale: Option<f32>,
+    offset: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShaCount {
+    sha: Vec<u8>,
+    count: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct QuantizedSearchRow {
+    id: RecordId,
+    path: PathBuf,
+    #[serde(default)]
+    path_bytes: Option<Vec<u8>>,
+    start_byte: usize,
+    end_byte: usize,
+    #[serde(default)]
+    start_line: Option<usize>,
+    #[serde(default)]
+    start_col: Option<usize>,
+    #[serde(default)]
+    end_line: Option<usize>,
+    #[serde(default)]
+    end_col: Option<usize>,
+    quantized: Vec<i8>,
+    scale: f32,
+    offset: f32,
+    symbol_kind: Option<String>,
+    #[serde(default)]
+    anchor: Option<Vec<u8>>,
+    #[serde(default)]
+    content: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(from = "SearchResultRow")]
 pub struct SearchResult {
     pub id: RecordId,
     pub path: PathBuf,
     pub start_byte: usize,
     pub end_byte: usize,
+    /// The zero-based line and byte column the span starts and ends at, for jumping to it in
+    /// an editor. Absent for spans indexed before positions were recorded.
+    pub start_line: Option<usize>,
+    pub start_col: Option<usize>,
+    pub end_line: Option<usize>,
+    pub end_col: Option<usize>,
     pub similarity: f32,
+    #[serde(default)]
+    pub symbol_kind: Option<String>,
+    /// The byte range of the most relevant line, relative to `start_byte`, when requested.
+    #[serde(default)]
+    pub highlight: Option<Range<usize>>,
+    /// A hash of the lines the span starts on, when indexed with line anchors.
+    #[serde(default)]
+    pub anchor: Option<Vec<u8>>,
+    /// The text of the span, resolved from its blob when stored with `ContentStorage::Blob`,
+    /// or otherwise read from its file when requested with `SearchOptions::with_content`.
+    #[serde(default)]
+    pub content: Option<String>,
+}
+
+/// Restricts search to, or away from, spans flagged as tests.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TestFilter {
+    #[default]
+    All,
+    ExcludeTests,
+    OnlyTests,
+}
+
+impl TestFilter {
+    /// The condition appended to a span query's `WHERE` clause. Spans indexed before tests
+    /// were flagged have no flag, so are treated as not being tests.
+    fn predicate(&self) -> &'static str {
+        match self {
+            TestFilter::All => "",
+            TestFilter::ExcludeTests => " AND is_test != true",
+            TestFilter::OnlyTests => " AND is_test = true",
+        }
+    }
+}
+
+/// The metric used to score spans against a query embedding.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SimilarityMetric {
+    #[default]
+    Cosine,
+    Euclidean,
+    /// The unnormalized dot product, which favours longer embeddings, for providers whose
+    /// embedding magnitude carries meaning.
+    Dot,
+}
+
+impl SimilarityMetric {
+    pub const ALL: [SimilarityMetric; 3] = [
+        SimilarityMetric::Cosine,
+        SimilarityMetric::Euclidean,
+        SimilarityMetric::Dot,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SimilarityMetric::Cosine => "cosine",
+            SimilarityMetric::Euclidean => "euclidean",
+            SimilarityMetric::Dot => "dot",
+        }
+    }
+
+    /// The metric with the given name, as returned by `name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        SimilarityMetric::ALL
+            .into_iter()
+            .find(|metric| metric.name() == name)
+    }
+
+    /// Whether a higher score means a closer match, similarities rank descending whereas
+    /// distances rank ascending.
+    pub fn higher_is_better(&self) -> bool {
+        match self {
+            SimilarityMetric::Cosine | SimilarityMetric::Dot => true,
+            SimilarityMetric::Euclidean => false,
+        }
+    }
+
+    fn function(&self) -> &'static str {
+        match self {
+            SimilarityMetric::Cosine => "vector::similarity::cosine",
+            SimilarityMetric::Euclidean => "vector::distance::euclidean",
+            SimilarityMetric::Dot => "vector::dot",
+        }
+    }
+
+    fn order(&self) -> &'static str {
+        if self.higher_is_better() {
+            "DESC"
+        } else {
+            "ASC"
+        }
+    }
+
+    /// The condition appended to a span query's `WHERE` clause, keeping spans scoring at
+    /// least as well as `$threshold`. For distance metrics the threshold is a maximum distance.
+    fn threshold_predicate(&self) -> String {
+        let comparison = if self.higher_is_better() { ">=" } else { "<=" };
+        format!(
+            " AND {}(embedding, $target) {} $threshold",
+            self.function(),
+            comparison
+        )
+    }
+
+    /// Whether the score is at least as good as the threshold.
+    pub(crate) fn clears(&self, score: f32, threshold: f32) -> bool {
+        if self.higher_is_better() {
+            score >= threshold
+        } else {
+            score <= threshold
+        }
+    }
+
+    fn score(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            SimilarityMetric::Cosine => cosine_similarity(a, b),
+            SimilarityMetric::Euclidean => euclidean_distance(a, b),
+            SimilarityMetric::Dot => dot_product(a, b),
+        }
+    }
+}
+
+/// A position within a ranked list of results, after which the next page begins. Ties in
+/// similarity are broken by span id, so pages remain stable as the index changes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchCursor {
+    similarity: f64,
+    id: RecordId,
+}
+
+impl SearchCursor {
+    /// Encodes the cursor as an opaque string, to be passed back to fetch the next page.
+    pub fn encode(&self) -> String {
+        format!("{:016x}:{}", self.similarity.to_bits(), self.id)
+            .bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn decode(cursor: &str) -> anyhow::Result<Self> {
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| {
+                cursor
+                    .get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| anyhow!("invalid search cursor"))?;
+        let decoded = String::from_utf8(bytes)?;
+
+        let (similarity, id) = decoded
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid search cursor"))?;
+        let similarity = f64::from_bits(u64::from_str_radix(similarity, 16)?);
+        let id = surrealdb::sql::thing(id)?;
+
+        anyhow::Ok(SearchCursor { similarity, id })
+    }
+}
+
+/// A page of search results, with the cursor for the following page if there may be more.
+#[derive(Debug)]
+pub struct SearchPage {
+    pub results: Vec<SearchResult>,
+    pub next_cursor: Option<String>,
+}
+
+impl SearchPage {
+    fn from_rows(rows: Vec<SearchPageRow>, n: usize) -> Self {
+        let next_cursor = if rows.len() == n {
+            rows.last().map(|row| {
+                SearchCursor {
+                    similarity: row.similarity,
+                    id: row.id.clone(),
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+
+        let results = rows
+            .into_iter()
+            .map(|row| SearchResult {
+                id: row.id,
+                path: resolve_path(row.path, row.path_bytes),
+                start_byte: row.start_byte,
+                end_byte: row.end_byte,
+                start_line: row.start_line,
+                start_col: row.start_col,
+                end_line: row.end_line,
+                end_col: row.end_col,
+                similarity: row.similarity as f32,
+                symbol_kind: row.symbol_kind,
+                highlight: None,
+                anchor: row.anchor,
+                content: row.content,
+            })
+            .collect();
+
+        SearchPage {
+            results,
+            next_cursor,
+        }
+    }
+}
+
+/// A search result as returned by the database, keeping the full precision similarity so that
+/// cursors compare exactly against the database's own values.
+#[derive(Debug, Deserialize)]
+struct SearchPageRow {
+    id: RecordId,
+    path: PathBuf,
+    #[serde(default)]
+    path_bytes: Option<Vec<u8>>,
+    start_byte: usize,
+    end_byte: usize,
+    #[serde(default)]
+    start_line: Option<usize>,
+    #[serde(default)]
+    start_col: Option<usize>,
+    #[serde(default)]
+    end_line: Option<usize>,
+    #[serde(default)]
+    end_col: Option<usize>,
+    similarity: f64,
+    #[serde(default)]
+    symbol_kind: Option<String>,
+    #[serde(default)]
+    anchor: Option<Vec<u8>>,
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// A search result as returned by the database, before its path is reconstructed.
+#[derive(Debug, Deserialize)]
+struct SearchResultRow {
+    id: RecordId,
+    path: PathBuf,
+    #[serde(default)]
+    path_bytes: Option<Vec<u8>>,
+    start_byte: usize,
+    end_byte: usize,
+    #[serde(default)]
+    start_line: Option<usize>,
+    #[serde(default)]
+    start_col: Option<usize>,
+    #[serde(default)]
+    end_line: Option<usize>,
+    #[serde(default)]
+    end_col: Option<usize>,
+    similarity: f32,
+    #[serde(default)]
+    symbol_kind: Option<String>,
+    #[serde(default)]
+    highlight: Option<Range<usize>>,
+    #[serde(default)]
+    anchor: Option<Vec<u8>>,
+    #[serde(default)]
+    content: Option<String>,
+}
+
+impl From<SearchResultRow> for SearchResult {
+    fn from(row: SearchResultRow) -> Self {
+        SearchResult {
+            id: row.id,
+            path: resolve_path(row.path, row.path_bytes),
+            start_byte: row.start_byte,
+            end_byte: row.end_byte,
+            start_line: row.start_line,
+            start_col: row.start_col,
+            end_line: row.end_line,
+            end_col: row.end_col,
+            similarity: row.similarity,
+            symbol_kind: row.symbol_kind,
+            highlight: row.highlight,
+            anchor: row.anchor,
+            content: row.content,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -92,18 +578,195 @@ pub struct EmbeddingResult {
 struct Span {
     start_byte: usize,
     end_byte: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_col: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_col: Option<usize>,
     sha: Vec<u8>,
     embedding: Vec<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quantized: Option<Vec<i8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scale: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    symbol_kind: Option<String>,
+    /// The span this span describes, if it holds a description rather than code.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    describes: Option<RecordId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    anchor: Option<Vec<u8>>,
+    #[serde(default)]
+    is_test: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy_version: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    template_version: Option<u32>,
+    /// The blob holding the span's content, when content is stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blob: Option<RecordId>,
+}
+
+impl Span {
+    fn new(
+        start_byte: usize,
+        end_byte: usize,
+        sha: Vec<u8>,
+        embedding: &[f32],
+        symbol_kind: &str,
+        embedding_storage: EmbeddingStorage,
+    ) -> Self {
+        match embedding_storage {
+            EmbeddingStorage::Full => Span {
+                start_byte,
+                end_byte,
+                start_line: None,
+                start_col: None,
+                end_line: None,
+                end_col: None,
+                sha,
+                embedding: embedding.to_vec(),
+                quantized: None,
+                scale: None,
+                offset: None,
+                symbol_kind: Some(symbol_kind.to_string()),
+                describes: None,
+                anchor: None,
+                is_test: false,
+                strategy_version: None,
+                template_version: None,
+                blob: None,
+            },
+            EmbeddingStorage::Quantized => {
+                let quantized = QuantizedEmbedding::quantize(embedding);
+                Span {
+                    start_byte,
+                    end_byte,
+                    start_line: None,
+                    start_col: None,
+                    end_line: None,
+                    end_col: None,
+                    sha,
+                    embedding: vec![],
+                    quantized: Some(quantized.values),
+                    scale: Some(quantized.scale),
+                    offset: Some(quantized.offset),
+                    symbol_kind: Some(symbol_kind.to_string()),
+                    describes: None,
+                    anchor: None,
+                    is_test: false,
+                    strategy_version: None,
+                    template_version: None,
+                    blob: None,
+                }
+            }
+        }
+    }
+}
+
+/// The symbol kind given to spans holding a description of another span.
+pub(crate) const DESCRIPTION_SYMBOL_KIND: &str = "description";
+
+#[derive(Debug, Deserialize)]
+struct DescribedSpan {
+    start_byte: usize,
+    end_byte: usize,
+    #[serde(default)]
+    start_line: Option<usize>,
+    #[serde(default)]
+    start_col: Option<usize>,
+    #[serde(default)]
+    end_line: Option<usize>,
+    #[serde(default)]
+    end_col: Option<usize>,
+    file: Option<RecordId>,
+    path: Option<PathBuf>,
 }
 
+/// A span along with its stable id, for writing in a batch.
 #[derive(Debug, Serialize)]
-struct Directory {
-    path: PathBuf,
+struct StableSpan {
+    id: String,
+    content: Span,
 }
 
 #[derive(Debug, Serialize)]
+struct Directory {
+    path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 struct File {
-    path: PathBuf,
+    /// The path as a string, lossy for non-UTF8 paths, used for matching in queries.
+    path: String,
+    /// The exact path as raw bytes, absent for files stored before it was recorded.
+    #[serde(default)]
+    path_bytes: Option<Vec<u8>>,
+}
+
+impl File {
+    fn new(path: &std::path::Path) -> Self {
+        File {
+            path: path.to_string_lossy().to_string(),
+            path_bytes: Some(path_to_bytes(path)),
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        resolve_path(PathBuf::from(&self.path), self.path_bytes.clone())
+    }
+}
+
+/// Encodes the path as raw bytes, which unlike its string form round-trips non-UTF8 paths.
+fn path_to_bytes(path: &std::path::Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().as_bytes().to_vec()
+    }
+}
+
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(&bytes).to_string())
+    }
+}
+
+/// Derives a span id from its file, content and position, so that re-indexing an unchanged
+/// span recreates it with the same id and external references to it stay valid.
+fn stable_span_id(path: &std::path::Path, sha: &[u8], start_byte: usize) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(path_to_bytes(path));
+    hasher.update(sha);
+    hasher.update(start_byte.to_le_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Prefers the exact path bytes when stored, falling back to the string path.
+fn resolve_path(path: PathBuf, path_bytes: Option<Vec<u8>>) -> PathBuf {
+    match path_bytes {
+        Some(bytes) if !bytes.is_empty() => path_from_bytes(bytes),
+        _ => path,
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -120,97 +783,274 @@ struct Record {
 #[derive(Clone)]
 pub(crate) struct VectorDatabase {
     executor: mpsc::Sender<DatabaseJob>,
+    /// The number of searches dropped as their caller stopped waiting on the result.
+    #[cfg(test)]
+    searches_cancelled: Arc<AtomicUsize>,
 }
 
 impl VectorDatabase {
     pub(crate) async fn initialize(database_dir: PathBuf) -> anyhow::Result<Self> {
+        VectorDatabase::initialize_with_options(database_dir, DatabaseOptions::default()).await
+    }
+
+    /// Opens the database, retrying with backoff if opening fails.
+    pub(crate) async fn initialize_with_options(
+        database_dir: PathBuf,
+        options: DatabaseOptions,
+    ) -> anyhow::Result<Self> {
+        let mut backoff = options.initialize_backoff;
+        let mut attempt = 1;
+        loop {
+            match VectorDatabase::open(database_dir.clone(), options.clone()).await {
+                Ok(db) => return anyhow::Ok(db),
+                Err(err) if attempt < options.initialize_attempts => {
+                    log::warn!(
+                        "failed to initialize database (attempt {} of {}), retrying in {:?}: {:?}",
+                        attempt,
+                        options.initialize_attempts,
+                        backoff,
+                        err
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn open(database_dir: PathBuf, options: DatabaseOptions) -> anyhow::Result<Self> {
         const DATABASE_NAME: &str = "auden";
 
-        let (executor, mut receiver) = mpsc::channel::<DatabaseJob>(1000);
-        tokio::spawn({
-            async move {
-                let location = database_dir.join("temp.db");
-                log::debug!("initializing surrealdb at {:?}", location.clone());
-
-                match Surreal::new::<RocksDb>(location).await {
-                    Ok(db) => {
-                        let _ = db.use_ns(DATABASE_NAME).use_db(DATABASE_NAME).await;
-
-                        // Create Tables
-                        db.query(
-                            "
-                            DEFINE TABLE directory SCHEMAFULL;
-                            DEFINE FIELD path ON TABLE directory TYPE string;
-                            ",
-                        )
-                        .await
-                        .unwrap();
-
-                        db.query(
-                            "
-                            DEFINE TABLE file SCHEMAFULL;
-                            DEFINE FIELD path ON TABLE file TYPE string;
-                        ",
-                        )
-                        .await
-                        .unwrap();
-
-                        db.query(
-                            "
-                            DEFINE TABLE span SCHEMAFULL;
-                            DEFINE FIELD start_byte ON TABLE span TYPE int;
-                            DEFINE FIELD end_byte ON TABLE span TYPE int;
-                            DEFINE FIELD sha ON TABLE span TYPE array<int>;
-                            DEFINE FIELD sha.* ON TABLE span TYPE int;
-                            DEFINE FIELD embedding ON TABLE span TYPE array<float>;
-                            DEFINE FIELD embedding.* ON TABLE span TYPE float;
-                            ",
+        let location = database_dir.join("temp.db");
+        log::debug!("initializing surrealdb at {:?}", location.clone());
+
+        let db = Surreal::new::<RocksDb>(location).await?;
+        db.use_ns(DATABASE_NAME).use_db(DATABASE_NAME).await?;
+        run_migrations(&db).await?;
+
+        let (executor, mut receiver) = mpsc::channel::<DatabaseJob>(options.channel_capacity);
+        #[cfg(test)]
+        let searches_cancelled = Arc::new(AtomicUsize::new(0));
+        #[cfg(test)]
+        let cancelled = searches_cancelled.clone();
+        tokio::spawn(async move {
+            let mut shutdown = None;
+            let mut processed = 0;
+            while let Some(job) = receiver.recv().await {
+                processed += 1;
+                if processed % YIELD_INTERVAL == 0 {
+                    tokio::task::yield_now().await;
+                }
+
+                match job {
+                    DatabaseJob::GetEmbeddingsForDirectory {
+                        path,
+                        template_version,
+                        sender,
+                    } => {
+                        let result =
+                            get_embeddings_for_directory(&db, &path, template_version).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::GetOrCreateDirectory { path, sender } => {
+                        let result = get_or_create_directory(&db, &path).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::CreateFileAndSpans { context, sender } => {
+                        let result = create_file_and_spans(
+                            &db,
+                            context.clone(),
+                            options.embedding_storage,
+                            options.content_storage,
                         )
-                        .await
-                        .unwrap();
-
-                        while let Some(job) = receiver.recv().await {
-                            match job {
-                                DatabaseJob::GetEmbeddingsForDirectory { path, sender } => {
-                                    let result = get_embeddings_for_directory(&db, &path).await;
-                                    let _ = sender.send(result);
-                                }
-                                DatabaseJob::GetOrCreateDirectory { path, sender } => {
-                                    let result = get_or_create_directory(&db, &path).await;
-                                    let _ = sender.send(result);
-                                }
-                                DatabaseJob::CreateFileAndSpans { context, sender } => {
-                                    let result = create_file_and_spans(&db, context.clone()).await;
-                                    let _ = sender.send(result);
+                        .await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::SearchDirectory {
+                        path,
+                        embedding,
+                        n,
+                        metric,
+                        test_filter,
+                        min_similarity,
+                        sender,
+                    } => {
+                        let search = async {
+                            match options.embedding_storage {
+                                EmbeddingStorage::Full => {
+                                    search_directory(
+                                        &db,
+                                        &path,
+                                        &embedding,
+                                        n,
+                                        metric,
+                                        test_filter,
+                                        min_similarity,
+                                    )
+                                    .await
                                 }
-                                DatabaseJob::SearchDirectory {
-                                    path,
-                                    embedding,
-                                    n,
-                                    sender,
-                                } => {
-                                    let result = search_directory(&db, &path, &embedding, n).await;
-                                    let _ = sender.send(result);
+                                EmbeddingStorage::Quantized => {
+                                    search_quantized_directory(
+                                        &db,
+                                        &path,
+                                        &embedding,
+                                        n,
+                                        metric,
+                                        test_filter,
+                                        min_similarity,
+                                    )
+                                    .await
                                 }
-                                DatabaseJob::GetPathsForDirectory { path, sender } => {
-                                    let result = get_files_for_directory(&db, &path).await;
-                                    let _ = sender.send(result);
+                            }
+                        };
+
+                        if !send_unless_abandoned(sender, search).await {
+                            log::debug!("search of {:?} abandoned, cancelling", path);
+                            #[cfg(test)]
+                            cancelled.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                    DatabaseJob::GetPathsForDirectory { path, sender } => {
+                        let result = get_files_for_directory(&db, &path).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::DeletePathAndSpans { path, sender } => {
+                        let result = delete_file_and_spans(&db, &path).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::WatchPersistedFiles { path, sender } => {
+                        let result = watch_persisted_files(&db, &path).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::GetDuplicateShas { sender } => {
+                        let result = get_duplicate_shas(&db).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::RankFileSpans {
+                        path,
+                        embedding,
+                        sender,
+                    } => {
+                        let result =
+                            rank_file_spans(&db, &path, &embedding, options.embedding_storage)
+                                .await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::SearchDirectoryPage {
+                        path,
+                        embedding,
+                        n,
+                        cursor,
+                        sender,
+                    } => {
+                        let search = async {
+                            match options.embedding_storage {
+                                EmbeddingStorage::Full => {
+                                    search_directory_page(&db, &path, &embedding, n, cursor).await
                                 }
-                                DatabaseJob::DeletePathAndSpans { path, sender } => {
-                                    let result = delete_file_and_spans(&db, &path).await;
-                                    let _ = sender.send(result);
+                                EmbeddingStorage::Quantized => {
+                                    search_quantized_directory_page(
+                                        &db, &path, &embedding, n, cursor,
+                                    )
+                                    .await
                                 }
                             }
+                        };
+                        if !send_unless_abandoned(sender, search).await {
+                            log::debug!("search page of {:?} abandoned, cancelling", path);
+                            #[cfg(test)]
+                            cancelled.fetch_add(1, Ordering::SeqCst);
                         }
                     }
-                    Err(err) => {
-                        panic!("{:?}", err);
+                    DatabaseJob::SetSpanDescription {
+                        span_id,
+                        sha,
+                        embedding,
+                        sender,
+                    } => {
+                        let result = set_span_description(
+                            &db,
+                            &span_id,
+                            sha,
+                            embedding,
+                            options.embedding_storage,
+                        )
+                        .await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::DeleteDirectory { path, sender } => {
+                        let result = delete_directory(&db, &path).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::GetStrategyVersionsForDirectory { path, sender } => {
+                        let result = get_strategy_versions_for_directory(&db, &path).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::SwapDirectoryTemplateVersion {
+                        path,
+                        template_version,
+                        sender,
+                    } => {
+                        let result =
+                            swap_directory_template_version(&db, &path, template_version).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::SearchAll {
+                        embedding,
+                        n,
+                        sender,
+                    } => {
+                        let result =
+                            search_all(&db, &embedding, n, options.embedding_storage).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::GetDirectories { sender } => {
+                        let result = get_directories(&db).await;
+                        let _ = sender.send(result);
+                    }
+                    DatabaseJob::GetSymbolKindFacets {
+                        path,
+                        embedding,
+                        metric,
+                        test_filter,
+                        min_similarity,
+                        sender,
+                    } => {
+                        let search = get_symbol_kind_facets(
+                            &db,
+                            &path,
+                            &embedding,
+                            metric,
+                            test_filter,
+                            min_similarity,
+                            options.embedding_storage,
+                        );
+                        if !send_unless_abandoned(sender, search).await {
+                            log::debug!("facet search of {:?} abandoned, cancelling", path);
+                            #[cfg(test)]
+                            cancelled.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                    DatabaseJob::Shutdown { sender } => {
+                        shutdown = Some(sender);
+                        break;
                     }
                 }
             }
+
+            drop(db);
+            if let Some(sender) = shutdown {
+                let _ = sender.send(anyhow::Ok(()));
+            }
         });
 
-        anyhow::Ok(VectorDatabase { executor })
+        anyhow::Ok(VectorDatabase {
+            executor,
+            #[cfg(test)]
+            searches_cancelled,
+        })
     }
 
     pub(crate) async fn get_files_for_directory(
@@ -242,8 +1082,32 @@ impl VectorDatabase {
 
     pub(crate) async fn queue(&self, database_job: DatabaseJob) -> anyhow::Result<()> {
         log::debug!("sending database job for execution: {:?}", database_job);
+        self.check_backpressure();
         anyhow::Ok(self.executor.send(database_job).await?)
     }
+
+    /// The number of searches cancelled because their caller dropped the search before it
+    /// completed.
+    #[cfg(test)]
+    pub(crate) fn searches_cancelled(&self) -> usize {
+        self.searches_cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Logs a warning if the executor channel is close to full, as sends will then stall the
+    /// caller until the database catches up.
+    fn check_backpressure(&self) -> bool {
+        let capacity = self.executor.max_capacity();
+        let remaining = self.executor.capacity();
+        let near_capacity = (remaining as f32) < (capacity as f32) * BACKPRESSURE_THRESHOLD;
+        if near_capacity {
+            log::warn!(
+                "database executor channel near capacity, {} of {} slots remaining",
+                remaining,
+                capacity
+            );
+        }
+        near_capacity
+    }
     pub(crate) async fn delete_file(&self, path: &PathBuf) -> anyhow::Result<()> {
         let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
         let job = DatabaseJob::DeletePathAndSpans {
@@ -254,13 +1118,18 @@ impl VectorDatabase {
         receiver.await?
     }
 
+    /// Returns the embeddings stored for the directory by sha, for reuse when re-indexing.
+    /// Only spans wrapped with the given template version are returned, as spans framed
+    /// differently aren't comparable.
     pub(crate) async fn get_embeddings_for_directory(
         &self,
         path: &PathBuf,
+        template_version: u32,
     ) -> anyhow::Result<HashMap<Vec<u8>, Vec<f32>>> {
         let (sender, receiver) = oneshot::channel::<anyhow::Result<HashMap<Vec<u8>, Vec<f32>>>>();
         let job = DatabaseJob::GetEmbeddingsForDirectory {
             path: path.clone(),
+            template_version,
             sender,
         };
 
@@ -273,12 +1142,53 @@ impl VectorDatabase {
         directory: PathBuf,
         embedding: &Vec<f32>,
         n: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        self.get_top_neighbours_with_metric(
+            directory,
+            embedding,
+            n,
+            SimilarityMetric::Cosine,
+            TestFilter::All,
+            None,
+        )
+        .await
+    }
+
+    /// Returns the top `n` spans by the metric, dropping any which score worse than
+    /// `min_similarity` when given.
+    pub(crate) async fn get_top_neighbours_with_metric(
+        &self,
+        directory: PathBuf,
+        embedding: &Vec<f32>,
+        n: usize,
+        metric: SimilarityMetric,
+        test_filter: TestFilter,
+        min_similarity: Option<f32>,
     ) -> anyhow::Result<Vec<SearchResult>> {
         let (sender, receiver) = oneshot::channel::<anyhow::Result<Vec<SearchResult>>>();
         let job = DatabaseJob::SearchDirectory {
             path: directory,
             embedding: embedding.clone(),
             n,
+            metric,
+            test_filter,
+            min_similarity,
+            sender,
+        };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Returns a receiver tracking the number of files written under the directory since the
+    /// watch began, backed by a live query so it reflects persisted writes from any process.
+    pub(crate) async fn watch_persisted_files(
+        &self,
+        directory: &PathBuf,
+    ) -> anyhow::Result<watch::Receiver<usize>> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<watch::Receiver<usize>>>();
+        let job = DatabaseJob::WatchPersistedFiles {
+            path: directory.clone(),
             sender,
         };
 
@@ -295,41 +1205,226 @@ impl VectorDatabase {
         self.queue(job).await?;
         receiver.await?
     }
-}
-
-async fn get_files_for_directory(
-    db: &Surreal<surrealdb::engine::local::Db>,
-    path: &PathBuf,
-) -> anyhow::Result<HashSet<PathBuf>> {
-    let mut resp = db
-        .query(format!(
-            "SELECT path FROM file WHERE <-owns<-(directory WHERE path = '{}')",
-            path.to_string_lossy()
-        ))
-        .await?;
 
-    let results: Vec<PathBuf> = resp.take(0)?;
-    dbg!(&results);
+    /// Returns each sha shared by more than one span, with the number of spans sharing it.
+    pub(crate) async fn duplicate_shas(&self) -> anyhow::Result<DuplicateShas> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<DuplicateShas>>();
+        let job = DatabaseJob::GetDuplicateShas { sender };
 
-    let results = HashSet::from_iter(results.iter().map(|x| x.clone()));
-
-    anyhow::Ok(results)
-}
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Returns every span in the file, ranked by similarity to the embedding.
+    pub(crate) async fn rank_file_spans(
+        &self,
+        path: &PathBuf,
+        embedding: &Vec<f32>,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<Vec<SearchResult>>>();
+        let job = DatabaseJob::RankFileSpans {
+            path: path.clone(),
+            embedding: embedding.clone(),
+            sender,
+        };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Returns the page of nearest neighbours following the cursor, or the first page if no
+    /// cursor is provided.
+    pub(crate) async fn get_top_neighbours_page(
+        &self,
+        directory: PathBuf,
+        embedding: &Vec<f32>,
+        n: usize,
+        cursor: Option<SearchCursor>,
+    ) -> anyhow::Result<SearchPage> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<SearchPage>>();
+        let job = DatabaseJob::SearchDirectoryPage {
+            path: directory,
+            embedding: embedding.clone(),
+            n,
+            cursor,
+            sender,
+        };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Stores an embedded description for the span, as an additional span at the same code
+    /// location, replacing any description previously set for it.
+    pub(crate) async fn set_span_description(
+        &self,
+        span_id: RecordId,
+        sha: Vec<u8>,
+        embedding: Vec<f32>,
+    ) -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
+        let job = DatabaseJob::SetSpanDescription {
+            span_id,
+            sha,
+            embedding,
+            sender,
+        };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Stops the executor once all previously queued jobs are complete, and closes the
+    /// database so its writes are flushed to disk. Any further jobs fail.
+    pub(crate) async fn shutdown(&self) -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
+        let job = DatabaseJob::Shutdown { sender };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Deletes the directory, along with every file and span it owns and the relations between
+    /// them.
+    pub(crate) async fn delete_directory(&self, path: &PathBuf) -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<()>>();
+        let job = DatabaseJob::DeleteDirectory {
+            path: path.clone(),
+            sender,
+        };
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Returns the strategy version each file in the directory was parsed with, or `None` for
+    /// files whose spans were parsed with differing or unrecorded versions.
+    pub(crate) async fn get_strategy_versions_for_directory(
+        &self,
+        path: &PathBuf,
+    ) -> anyhow::Result<HashMap<PathBuf, Option<Vec<u8>>>> {
+        let (sender, receiver) = oneshot::channel();
+        let job = DatabaseJob::GetStrategyVersionsForDirectory {
+            path: path.clone(),
+            sender,
+        };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Records the template version the directory is being indexed with, returning the
+    /// version it was last indexed with, if recorded.
+    pub(crate) async fn swap_directory_template_version(
+        &self,
+        path: &PathBuf,
+        template_version: u32,
+    ) -> anyhow::Result<Option<u32>> {
+        let (sender, receiver) = oneshot::channel();
+        let job = DatabaseJob::SwapDirectoryTemplateVersion {
+            path: path.clone(),
+            template_version,
+            sender,
+        };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Returns the top `n` spans by cosine similarity across every indexed directory.
+    pub(crate) async fn get_top_neighbours_all(
+        &self,
+        embedding: &Vec<f32>,
+        n: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<Vec<SearchResult>>>();
+        let job = DatabaseJob::SearchAll {
+            embedding: embedding.clone(),
+            n,
+            sender,
+        };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Returns the path of every directory persisted in the database.
+    pub(crate) async fn get_directories(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<Vec<PathBuf>>>();
+        let job = DatabaseJob::GetDirectories { sender };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+
+    /// Counts the directory's spans passing the search filters by symbol kind, over every
+    /// candidate rather than only the top results.
+    pub(crate) async fn get_symbol_kind_facets(
+        &self,
+        directory: PathBuf,
+        embedding: &Vec<f32>,
+        metric: SimilarityMetric,
+        test_filter: TestFilter,
+        min_similarity: Option<f32>,
+    ) -> anyhow::Result<HashMap<String, usize>> {
+        let (sender, receiver) = oneshot::channel::<anyhow::Result<HashMap<String, usize>>>();
+        let job = DatabaseJob::GetSymbolKindFacets {
+            path: directory,
+            embedding: embedding.clone(),
+            metric,
+            test_filter,
+            min_similarity,
+            sender,
+        };
+
+        self.queue(job).await?;
+        receiver.await?
+    }
+}
+
+async fn get_files_for_directory(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+) -> anyhow::Result<HashSet<PathBuf>> {
+    let mut resp = db
+        .query("SELECT path, path_bytes FROM file WHERE <-owns<-(directory WHERE path = $path)")
+        .bind(("path", path.to_string_lossy().to_string()))
+        .await?;
+
+    let files: Vec<File> = resp.take(0)?;
+    let results = HashSet::from_iter(files.iter().map(|file| file.path()));
+
+    anyhow::Ok(results)
+}
 
 async fn get_embeddings_for_directory(
     db: &Surreal<surrealdb::engine::local::Db>,
     path: &PathBuf,
+    template_version: u32,
 ) -> anyhow::Result<HashMap<Vec<u8>, Vec<f32>>> {
-    let mut resp = db.query(format!("SELECT sha, embedding FROM span WHERE <-contains<-file<-owns<-(directory WHERE path = '{}')", path.to_string_lossy())).await?;
+    let mut resp = db
+        .query("SELECT sha, embedding, quantized, scale, offset FROM span WHERE <-contains<-file<-owns<-(directory WHERE path = $path) AND (template_version ?? $unrecorded) = $template_version")
+        .bind(("path", path.to_string_lossy().to_string()))
+        .bind(("template_version", template_version))
+        .bind(("unrecorded", UNRECORDED_TEMPLATE_VERSION))
+        .await?;
 
     let rows: Vec<EmbeddingBySha> = resp.take(0)?;
     let mut map = HashMap::<Vec<u8>, Vec<f32>>::new();
     for row in rows {
-        map.insert(row.sha, row.embedding);
+        let embedding = match (row.quantized, row.scale, row.offset) {
+            (Some(values), Some(scale), Some(offset)) if row.embedding.is_empty() => {
+                QuantizedEmbedding {
+                    values,
+                    scale,
+                    offset,
+                }
+                .dequantize()
+            }
+            _ => row.embedding,
+        };
+        map.insert(row.sha, embedding);
     }
 
-    dbg!(&map);
-
     anyhow::Ok(map)
 }
 
@@ -339,7 +1434,7 @@ async fn get_or_create_directory(
 ) -> anyhow::Result<String> {
     if let Ok(mut paths) = db
         .query("SELECT id FROM directory WHERE path = $path")
-        .bind(("path", path.clone()))
+        .bind(("path", path.to_string_lossy().to_string()))
         .await
     {
         let id: Vec<Thing> = paths.take("id").unwrap();
@@ -348,7 +1443,9 @@ async fn get_or_create_directory(
         } else {
             let row: Vec<Record> = db
                 .create("directory")
-                .content(Directory { path: path.clone() })
+                .content(Directory {
+                    path: path.to_string_lossy().to_string(),
+                })
                 .await?;
 
             if let Some(id) = row.get(0) {
@@ -364,18 +1461,20 @@ async fn create_file(
     path: &PathBuf,
     directory_id: String,
 ) -> anyhow::Result<String> {
-    let row: Vec<Record> = db
-        .create("file")
-        .content(File { path: path.clone() })
-        .await?;
+    let row: Vec<Record> = db.create("file").content(File::new(path)).await?;
 
     let file_id = row.get(0).ok_or(anyhow!("row not created"))?.id.id.to_raw();
-    let query = format!(
-        "RELATE directory:{}->owns->file:{}",
-        directory_id,
-        file_id.clone()
-    );
-    let result = db.query(query).await?;
+
+    // Record ids are bound rather than formatted into the query, as ids which aren't valid
+    // bare record id tokens would otherwise break it
+    let result = db
+        .query("RELATE $directory->owns->$file")
+        .bind((
+            "directory",
+            Thing::from(("directory", directory_id.as_str())),
+        ))
+        .bind(("file", Thing::from(("file", file_id.as_str()))))
+        .await?;
     result.check()?;
 
     anyhow::Ok(file_id)
@@ -383,30 +1482,29 @@ async fn create_file(
 
 async fn create_span(
     db: &Surreal<surrealdb::engine::local::Db>,
+    id: String,
     span: Span,
     file_id: String,
 ) -> anyhow::Result<()> {
-    let result: Vec<Record> = db.create("span").content(&span).await?;
-
-    let id = result
-        .get(0)
-        .ok_or(anyhow!("span not created"))?
-        .id
-        .id
-        .to_raw();
+    let result: Option<Record> = db.create(("span", id.as_str())).content(&span).await?;
+    result.ok_or(anyhow!("span not created"))?;
 
     debug_assert!({
-        let result: Vec<Span> = db.select("span").range(&id..).await.unwrap();
+        let result: Option<Span> = db.select(("span", id.as_str())).await.unwrap();
         assert_eq!(
-            result.get(0).unwrap(),
+            result.as_ref().unwrap(),
             &span,
             "span written and provided are different"
         );
         true
     });
 
-    let query = format!("RELATE file:{}->contains->span:{}", file_id, id);
-    let result = db.query(query).await?;
+    // Stable ids are hex digests, which may not parse as bare record ids
+    let result = db
+        .query("RELATE $file->contains->$span")
+        .bind(("file", Thing::from(("file", file_id.as_str()))))
+        .bind(("span", Thing::from(("span", id.as_str()))))
+        .await?;
     result.check()?;
 
     anyhow::Ok(())
@@ -416,58 +1514,300 @@ async fn delete_file_and_spans(
     db: &Surreal<surrealdb::engine::local::Db>,
     path: &PathBuf,
 ) -> anyhow::Result<()> {
-    let query = format!(
-        "DELETE span WHERE <-contains<-(file WHERE path = '{}')",
-        path.to_string_lossy()
-    );
-    let result = db.query(query).await?;
-    result.check()?;
+    // Files are matched by their exact path bytes, as distinct non-UTF8 paths may share a
+    // lossy string form, falling back to the string for files stored without them
+    let path_bytes = path_to_bytes(path);
+    let path = path.to_string_lossy().to_string();
+
+    // Delete Spans, while the relations to them still exist
+    db.query("DELETE span WHERE <-contains<-(file WHERE (path_bytes = $path_bytes) OR (path_bytes = NONE AND path = $path))")
+        .bind(("path", path.clone()))
+        .bind(("path_bytes", path_bytes.clone()))
+        .await?
+        .check()?;
 
     // Delete Relations
-    let query = format!(
-        "DELETE contains WHERE in.path = '{}'",
-        path.to_string_lossy()
-    );
-    let result = db.query(query).await?;
-    result.check()?;
+    db.query("DELETE contains WHERE (in.path_bytes = $path_bytes) OR (in.path_bytes = NONE AND in.path = $path)")
+        .bind(("path", path.clone()))
+        .bind(("path_bytes", path_bytes.clone()))
+        .await?
+        .check()?;
 
     // Delete File
-    let query = format!("DELETE file WHERE path = '{}'", path.to_string_lossy());
-    let result = db.query(query).await?;
-    result.check()?;
+    db.query(
+        "DELETE file WHERE (path_bytes = $path_bytes) OR (path_bytes = NONE AND path = $path)",
+    )
+    .bind(("path", path))
+    .bind(("path_bytes", path_bytes))
+    .await?
+    .check()?;
 
     anyhow::Ok(())
 }
 
+async fn watch_persisted_files(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+) -> anyhow::Result<watch::Receiver<usize>> {
+    let (count_tx, count_rx) = watch::channel::<usize>(0);
+    let (ready_tx, ready_rx) = oneshot::channel::<anyhow::Result<()>>();
+
+    tokio::spawn({
+        let db = db.clone();
+        let path = path.clone();
+        async move {
+            let mut stream = match db.select::<Vec<File>>("file").live().await {
+                Ok(stream) => {
+                    let _ = ready_tx.send(anyhow::Ok(()));
+                    stream
+                }
+                Err(err) => {
+                    let _ = ready_tx.send(Err(anyhow!(err)));
+                    return;
+                }
+            };
+
+            while let Some(notification) = stream.next().await {
+                match notification {
+                    Ok(Notification {
+                        action: Action::Create,
+                        data,
+                        ..
+                    }) if data.path().starts_with(&path) => {
+                        count_tx.send_modify(|count| *count += 1);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::debug!("live query notification error: {:?}", err);
+                    }
+                }
+
+                if count_tx.is_closed() {
+                    break;
+                }
+            }
+        }
+    });
+
+    ready_rx.await??;
+    anyhow::Ok(count_rx)
+}
+
+/// Sends the result of the search, unless its caller stops waiting on it first, such as a
+/// disconnected client, in which case the search is dropped rather than run to completion.
+/// Returns whether the result was sent.
+async fn send_unless_abandoned<T>(
+    mut sender: oneshot::Sender<anyhow::Result<T>>,
+    search: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> bool {
+    let result = tokio::select! {
+        biased;
+        _ = sender.closed() => None,
+        result = search => Some(result),
+    };
+    match result {
+        Some(result) => {
+            let _ = sender.send(result);
+            true
+        }
+        None => false,
+    }
+}
+
 async fn create_file_and_spans(
     db: &Surreal<surrealdb::engine::local::Db>,
     context: Arc<Mutex<FileContext>>,
+    embedding_storage: EmbeddingStorage,
+    content_storage: ContentStorage,
 ) -> anyhow::Result<()> {
     let file_context = context.lock().await;
     let path = file_context.details.path.clone();
     let directory_id = file_context.details.directory_state.id.clone();
 
-    // Automatically overwriting everything currently
-    delete_file_and_spans(db, &path).await?;
+    let blobs = match content_storage {
+        ContentStorage::Unstored => vec![None; file_context.documents.len()],
+        ContentStorage::Blob => {
+            store_blobs(db, &path, &file_context.source, &file_context.documents).await?
+        }
+    };
 
     // Convert to Proper Data
-    let mut data: Vec<Span> = Vec::new();
-    for (embedding, document) in file_context.embeddings.iter().zip(&file_context.documents) {
+    let mut data: Vec<StableSpan> = Vec::new();
+    let mut ids = HashSet::new();
+    for ((embedding, document), blob) in file_context
+        .embeddings
+        .iter()
+        .zip(&file_context.documents)
+        .zip(blobs)
+    {
         debug_assert!(
             embedding.len() > 0,
             "embedding length passed to creation is empty"
         );
-        data.push(Span {
-            start_byte: document.start_byte,
-            end_byte: document.end_byte,
-            sha: document.sha.clone(),
-            embedding: embedding.clone(),
-        });
+        // Non finite values poison similarity for every search, so these spans are rejected
+        if embedding.iter().any(|value| !value.is_finite()) {
+            log::warn!(
+                "rejecting span {}..{} in {:?}, embedding contains non-finite values",
+                document.start_byte,
+                document.end_byte,
+                path
+            );
+            continue;
+        }
+
+        // The same node captured by more than one pattern is only stored once
+        let id = stable_span_id(&path, &document.sha, document.start_byte);
+        if !ids.insert(id.clone()) {
+            continue;
+        }
+
+        let mut content = Span::new(
+            document.start_byte,
+            document.end_byte,
+            document.sha.clone(),
+            embedding,
+            &document.symbol_kind,
+            embedding_storage,
+        );
+        content.start_line = Some(document.start_position.line);
+        content.start_col = Some(document.start_position.column);
+        content.end_line = Some(document.end_position.line);
+        content.end_col = Some(document.end_position.column);
+        content.anchor = document.anchor.clone();
+        content.is_test = document.is_test;
+        content.strategy_version = file_context.strategy_version.clone();
+        content.template_version = file_context.template_version;
+        content.blob = blob;
+        data.push(StableSpan { id, content });
+    }
+
+    // Files already stored only have their changed spans written, rather than being replaced
+    let mut response = db
+        .query("SELECT VALUE id FROM file WHERE (path_bytes = $path_bytes) OR (path_bytes = NONE AND path = $path)")
+        .bind(("path", path.to_string_lossy().to_string()))
+        .bind(("path_bytes", path_to_bytes(&path)))
+        .await?;
+    let file_ids: Vec<Thing> = response.take(0)?;
+    match file_ids.into_iter().next() {
+        Some(file_id) => {
+            let upsert = plan_span_upsert(db, &path, data).await?;
+            apply_span_upsert(db, &file_id, upsert).await
+        }
+        None => {
+            let file_id = create_file(db, &path, directory_id).await?;
+            for span in data {
+                create_span(db, span.id, span.content, file_id.clone()).await?;
+            }
+            anyhow::Ok(())
+        }
+    }
+}
+
+/// A span already stored for a file, matched against the file's re-parsed spans by sha.
+#[derive(Debug, Deserialize)]
+struct StoredSpan {
+    id: Thing,
+    sha: Vec<u8>,
+}
+
+/// The writes bringing a file's stored spans in line with its re-parsed spans.
+#[derive(Debug, Default)]
+struct SpanUpsert {
+    created: Vec<StableSpan>,
+    updated: Vec<StableSpan>,
+    deleted: Vec<Thing>,
+}
+
+/// Matches the file's re-parsed spans against those stored, first by id, which covers spans
+/// unchanged in place, then by sha, which covers spans moved by edits elsewhere in the file.
+/// Matched spans keep their id, and are only rewritten if they differ from what is stored.
+async fn plan_span_upsert(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+    spans: Vec<StableSpan>,
+) -> anyhow::Result<SpanUpsert> {
+    // Descriptions are tied to the span they describe, so are never matched themselves
+    let mut response = db
+        .query("SELECT id, sha FROM span WHERE <-contains<-(file WHERE (path_bytes = $path_bytes) OR (path_bytes = NONE AND path = $path)) AND describes = NONE")
+        .bind(("path", path.to_string_lossy().to_string()))
+        .bind(("path_bytes", path_to_bytes(path)))
+        .await?;
+    let stored: Vec<StoredSpan> = response.take(0)?;
+    let mut unmatched = stored
+        .into_iter()
+        .map(|span| (span.id.id.to_raw(), span.sha))
+        .collect::<HashMap<String, Vec<u8>>>();
+
+    let mut upsert = SpanUpsert::default();
+    let mut moved = Vec::new();
+    for span in spans {
+        if unmatched.remove(&span.id).is_some() {
+            let current: Option<Span> = db.select(("span", span.id.as_str())).await?;
+            if current.as_ref() != Some(&span.content) {
+                upsert.updated.push(span);
+            }
+        } else {
+            moved.push(span);
+        }
+    }
+
+    for span in moved {
+        let id = unmatched
+            .iter()
+            .find(|(_, sha)| **sha == span.content.sha)
+            .map(|(id, _)| id.clone());
+        match id {
+            Some(id) => {
+                unmatched.remove(&id);
+                upsert.updated.push(StableSpan {
+                    id,
+                    content: span.content,
+                });
+            }
+            None => upsert.created.push(span),
+        }
+    }
+
+    upsert.deleted = unmatched
+        .into_keys()
+        .map(|id| Thing::from(("span", id.as_str())))
+        .collect();
+
+    anyhow::Ok(upsert)
+}
+
+/// Writes the upsert to the file. Spans removed from the file are deleted along with their
+/// descriptions.
+async fn apply_span_upsert(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    file_id: &Thing,
+    upsert: SpanUpsert,
+) -> anyhow::Result<()> {
+    if !upsert.deleted.is_empty() {
+        db.query(
+            "
+            DELETE contains WHERE out.describes INSIDE $deleted;
+            DELETE span WHERE describes INSIDE $deleted;
+            DELETE contains WHERE out INSIDE $deleted;
+            DELETE span WHERE id INSIDE $deleted;
+            ",
+        )
+        .bind(("deleted", upsert.deleted))
+        .await?
+        .check()?;
+    }
+
+    for span in upsert.updated {
+        let result: Option<Record> = db
+            .update(("span", span.id.as_str()))
+            .content(&span.content)
+            .await?;
+        result.ok_or(anyhow!("span not updated"))?;
     }
 
-    let file_id = create_file(db, &path, directory_id).await?;
-    for span in data {
-        create_span(db, span, file_id.clone()).await?;
+    let file_id = file_id.id.to_raw();
+    for span in upsert.created {
+        create_span(db, span.id, span.content, file_id.clone()).await?;
     }
 
     anyhow::Ok(())
@@ -478,20 +1818,30 @@ async fn search_directory(
     path: &PathBuf,
     embedding: &Vec<f32>,
     n: usize,
+    metric: SimilarityMetric,
+    test_filter: TestFilter,
+    min_similarity: Option<f32>,
 ) -> anyhow::Result<Vec<SearchResult>> {
     let query = format!(
         "
-        SELECT id, array::first(<-contains<-file.path) as path, start_byte, end_byte, vector::similarity::cosine(embedding, $target) AS similarity
+        SELECT id, array::first(<-contains<-file.path) as path, (<-contains<-file)[0].path_bytes as path_bytes, start_byte, end_byte, start_line, start_col, end_line, end_col, symbol_kind, anchor, blob.content AS content, {}(embedding, $target) AS similarity
         FROM span 
-        WHERE <-contains<-file<-owns<-(directory WHERE path = '{}')
-        ORDER BY similarity DESC LIMIT $limit",
-        path.to_string_lossy()
+        WHERE <-contains<-file<-owns<-(directory WHERE path = $path){}{}
+        ORDER BY similarity {}, path ASC, start_byte ASC LIMIT $limit",
+        metric.function(),
+        test_filter.predicate(),
+        min_similarity
+            .map(|_| metric.threshold_predicate())
+            .unwrap_or_default(),
+        metric.order(),
     );
 
     let mut response = db
         .query(query)
+        .bind(("path", path.to_string_lossy().to_string()))
         .bind(("target", embedding))
         .bind(("limit", n))
+        .bind(("threshold", min_similarity.unwrap_or_default()))
         .await?;
 
     let results: Vec<SearchResult> = response.take(0)?;
@@ -499,42 +1849,1767 @@ async fn search_directory(
     anyhow::Ok(results)
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::parsers::strategy::ContextDocument;
-    use crate::semantic_index::{DirectoryState, FileDetails};
+async fn search_quantized_directory(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+    embedding: &Vec<f32>,
+    n: usize,
+    metric: SimilarityMetric,
+    test_filter: TestFilter,
+    min_similarity: Option<f32>,
+) -> anyhow::Result<Vec<SearchResult>> {
+    let query = format!(
+        "
+        SELECT id, array::first(<-contains<-file.path) as path, (<-contains<-file)[0].path_bytes as path_bytes, start_byte, end_byte, start_line, start_col, end_line, end_col, symbol_kind, anchor, blob.content AS content, quantized, scale, offset
+        FROM span
+        WHERE <-contains<-file<-owns<-(directory WHERE path = $path) AND quantized != NONE{}",
+        test_filter.predicate(),
+    );
 
-    use super::*;
-    use tempfile::tempdir;
+    let mut response = db
+        .query(query)
+        .bind(("path", path.to_string_lossy().to_string()))
+        .await?;
+    let rows: Vec<QuantizedSearchRow> = response.take(0)?;
+    let mut results = rank_quantized_rows(rows, embedding, n, metric);
+    if let Some(min_similarity) = min_similarity {
+        results.retain(|result| metric.clears(result.similarity, min_similarity));
+    }
+    anyhow::Ok(results)
+}
 
-    #[tokio::test]
-    async fn test_create_spans() {
-        let tmp_dir = tempdir().unwrap();
-        let tmp_path = PathBuf::from(tmp_dir.path());
-        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+/// Dequantizes each row and ranks them by the metric against the embedding, keeping the top
+/// `n`.
+fn rank_quantized_rows(
+    rows: Vec<QuantizedSearchRow>,
+    embedding: &Vec<f32>,
+    n: usize,
+    metric: SimilarityMetric,
+) -> Vec<SearchResult> {
+    let mut results = rows
+        .into_iter()
+        .map(|row| {
+            let dequantized = QuantizedEmbedding {
+                values: row.quantized,
+                scale: row.scale,
+                offset: row.offset,
+            }
+            .dequantize();
+            SearchResult {
+                id: row.id,
+                path: resolve_path(row.path, row.path_bytes),
+                start_byte: row.start_byte,
+                end_byte: row.end_byte,
+                start_line: row.start_line,
+                start_col: row.start_col,
+                end_line: row.end_line,
+                end_col: row.end_col,
+                similarity: metric.score(&dequantized, embedding),
+                symbol_kind: row.symbol_kind,
+                highlight: None,
+                anchor: row.anchor,
+                content: row.content,
+            }
+        })
+        .collect::<Vec<SearchResult>>();
+
+    // Ties are broken by location, so equally similar spans are returned in a stable order
+    results.sort_by(|a, b| {
+        let ordering = a.similarity.total_cmp(&b.similarity);
+        if metric.higher_is_better() {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+        .then_with(|| a.path.cmp(&b.path))
+        .then_with(|| a.start_byte.cmp(&b.start_byte))
+    });
+    results.truncate(n);
 
-        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
-        directory_state.new_job();
+    results
+}
 
-        let test_file = Arc::new(Mutex::new(FileContext {
-            details: FileDetails {
-                path: PathBuf::from("/tmp/foo"),
-                directory_state,
-            },
-            documents: vec![ContextDocument {
-                start_byte: 0,
-                end_byte: 10,
-                sha: vec![1, 2, 3],
-                content: "this is a test document".to_string(),
-            }],
-            embeddings: vec![vec![0.1, 0.2, 0.3]],
-        }));
+async fn get_duplicate_shas(
+    db: &Surreal<surrealdb::engine::local::Db>,
+) -> anyhow::Result<DuplicateShas> {
+    let mut response = db
+        .query("SELECT sha, count() AS count FROM span GROUP BY sha")
+        .await?;
+
+    let rows: Vec<ShaCount> = response.take(0)?;
+    let duplicates = rows
+        .into_iter()
+        .filter(|row| row.count > 1)
+        .map(|row| (row.sha, row.count))
+        .collect();
+
+    anyhow::Ok(duplicates)
+}
+
+async fn rank_file_spans(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+    embedding: &Vec<f32>,
+    embedding_storage: EmbeddingStorage,
+) -> anyhow::Result<Vec<SearchResult>> {
+    match embedding_storage {
+        EmbeddingStorage::Full => {
+            let mut response = db
+                .query(
+                    "
+                    SELECT id, array::first(<-contains<-file.path) as path, (<-contains<-file)[0].path_bytes as path_bytes, start_byte, end_byte, start_line, start_col, end_line, end_col, symbol_kind, anchor, blob.content AS content, vector::similarity::cosine(embedding, $target) AS similarity
+                    FROM span
+                    WHERE <-contains<-(file WHERE (path_bytes = $path_bytes) OR (path_bytes = NONE AND path = $path))
+                    ORDER BY similarity DESC, start_byte ASC",
+                )
+                .bind(("target", embedding))
+                .bind(("path", path.to_string_lossy().to_string()))
+                .bind(("path_bytes", path_to_bytes(path)))
+                .await?;
+
+            let results: Vec<SearchResult> = response.take(0)?;
+            anyhow::Ok(results)
+        }
+        EmbeddingStorage::Quantized => {
+            let mut response = db
+                .query(
+                    "
+                    SELECT id, array::first(<-contains<-file.path) as path, (<-contains<-file)[0].path_bytes as path_bytes, start_byte, end_byte, start_line, start_col, end_line, end_col, symbol_kind, anchor, blob.content AS content, quantized, scale, offset
+                    FROM span
+                    WHERE <-contains<-(file WHERE (path_bytes = $path_bytes) OR (path_bytes = NONE AND path = $path)) AND quantized != NONE",
+                )
+                .bind(("path", path.to_string_lossy().to_string()))
+                .bind(("path_bytes", path_to_bytes(path)))
+                .await?;
+
+            let rows: Vec<QuantizedSearchRow> = response.take(0)?;
+            anyhow::Ok(rank_quantized_rows(
+                rows,
+                embedding,
+                usize::MAX,
+                SimilarityMetric::Cosine,
+            ))
+        }
+    }
+}
+
+async fn search_directory_page(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+    embedding: &Vec<f32>,
+    n: usize,
+    cursor: Option<SearchCursor>,
+) -> anyhow::Result<SearchPage> {
+    let predicate = if cursor.is_some() {
+        "WHERE similarity < $cursor_similarity OR (similarity = $cursor_similarity AND id < $cursor_id)"
+    } else {
+        ""
+    };
+
+    let query = format!(
+        "
+        SELECT * FROM (
+            SELECT id, array::first(<-contains<-file.path) as path, (<-contains<-file)[0].path_bytes as path_bytes, start_byte, end_byte, start_line, start_col, end_line, end_col, symbol_kind, anchor, blob.content AS content, vector::similarity::cosine(embedding, $target) AS similarity
+            FROM span
+            WHERE <-contains<-file<-owns<-(directory WHERE path = $path)
+        )
+        {}
+        ORDER BY similarity DESC, id DESC LIMIT $limit",
+        predicate
+    );
+
+    let mut query = db
+        .query(query)
+        .bind(("path", path.to_string_lossy().to_string()))
+        .bind(("target", embedding))
+        .bind(("limit", n));
+    if let Some(cursor) = cursor {
+        query = query
+            .bind(("cursor_similarity", cursor.similarity))
+            .bind(("cursor_id", cursor.id));
+    }
+
+    let mut response = query.await?;
+    let rows: Vec<SearchPageRow> = response.take(0)?;
+
+    anyhow::Ok(SearchPage::from_rows(rows, n))
+}
+
+async fn search_quantized_directory_page(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+    embedding: &Vec<f32>,
+    n: usize,
+    cursor: Option<SearchCursor>,
+) -> anyhow::Result<SearchPage> {
+    let mut results = search_quantized_directory(
+        db,
+        path,
+        embedding,
+        usize::MAX,
+        SimilarityMetric::Cosine,
+        TestFilter::All,
+        None,
+    )
+    .await?;
+    results.sort_by(|a, b| {
+        b.similarity
+            .total_cmp(&a.similarity)
+            .then_with(|| b.id.cmp(&a.id))
+    });
+
+    let rows = results
+        .into_iter()
+        .map(|result| SearchPageRow {
+            similarity: result.similarity as f64,
+            id: result.id,
+            path: result.path,
+            path_bytes: None,
+            start_byte: result.start_byte,
+            end_byte: result.end_byte,
+            start_line: result.start_line,
+            start_col: result.start_col,
+            end_line: result.end_line,
+            end_col: result.end_col,
+            symbol_kind: result.symbol_kind,
+            anchor: result.anchor,
+            content: result.content,
+        })
+        .filter(|row| match &cursor {
+            Some(cursor) => {
+                row.similarity < cursor.similarity
+                    || (row.similarity == cursor.similarity && row.id < cursor.id)
+            }
+            None => true,
+        })
+        .take(n)
+        .collect();
+
+    anyhow::Ok(SearchPage::from_rows(rows, n))
+}
+
+async fn set_span_description(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    span_id: &RecordId,
+    sha: Vec<u8>,
+    embedding: Vec<f32>,
+    embedding_storage: EmbeddingStorage,
+) -> anyhow::Result<()> {
+    let mut response = db
+        .query("SELECT start_byte, end_byte, start_line, start_col, end_line, end_col, array::first(<-contains<-file.id) AS file, array::first(<-contains<-file.path) AS path FROM $span")
+        .bind(("span", span_id))
+        .await?;
+    let described: Option<DescribedSpan> = response.take(0)?;
+    let described = described.ok_or(anyhow!("span {} not found", span_id))?;
+    let (file_id, path) = match (described.file, described.path) {
+        (Some(file_id), Some(path)) => (file_id, path),
+        _ => return Err(anyhow!("span {} does not belong to a file", span_id)),
+    };
+
+    // Replace any existing description for the span
+    db.query("DELETE contains WHERE out.describes = $span; DELETE span WHERE describes = $span")
+        .bind(("span", span_id))
+        .await?
+        .check()?;
+
+    let id = stable_span_id(&path, &sha, described.start_byte);
+    let mut span = Span::new(
+        described.start_byte,
+        described.end_byte,
+        sha,
+        &embedding,
+        DESCRIPTION_SYMBOL_KIND,
+        embedding_storage,
+    );
+    span.start_line = described.start_line;
+    span.start_col = described.start_col;
+    span.end_line = described.end_line;
+    span.end_col = described.end_col;
+    span.describes = Some(span_id.clone());
+    create_span(db, id, span, file_id.id.to_raw()).await
+}
+
+/// The content of a span, stored once however many spans share it.
+#[derive(Debug, Serialize)]
+struct Blob {
+    id: String,
+    content: String,
+}
+
+/// Stores the content of each span in a blob keyed by the sha of the content, returning the
+/// blob for each span. Content is taken from the source the file was parsed from, so blobs
+/// match the spans embedded from it even if the file has changed since.
+async fn store_blobs(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+    source: &str,
+    documents: &[ContextDocument],
+) -> anyhow::Result<Vec<Option<RecordId>>> {
+    let mut blobs = HashMap::new();
+    let mut ids = Vec::with_capacity(documents.len());
+    for document in documents {
+        let span = source
+            .get(document.start_byte..document.end_byte)
+            .ok_or_else(|| {
+                anyhow!(
+                    "span {}..{} is outside of the content parsed from {:?}",
+                    document.start_byte,
+                    document.end_byte,
+                    path
+                )
+            })?;
+
+        let id = Sha256::digest(span)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        blobs.entry(id.clone()).or_insert_with(|| Blob {
+            id: id.clone(),
+            content: span.to_string(),
+        });
+        ids.push(Some(Thing::from(("blob", id.as_str()))));
+    }
+
+    // Blobs are content addressed, so rewriting an existing blob leaves it unchanged
+    db.query(
+        "
+        FOR $blob IN $blobs {
+            UPDATE type::thing('blob', $blob.id) SET content = $blob.content;
+        };
+        ",
+    )
+    .bind(("blobs", blobs.into_values().collect::<Vec<Blob>>()))
+    .await?
+    .check()?;
+
+    anyhow::Ok(ids)
+}
+
+/// Writes the file and its spans as a single transaction, rather than committing each
+/// statement individually.
+async fn delete_directory(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+) -> anyhow::Result<()> {
+    // Rows are deleted from the spans up, while the relations to them still exist
+    db.query(
+        "
+        BEGIN TRANSACTION;
+        DELETE span WHERE <-contains<-file<-owns<-(directory WHERE path = $path);
+        DELETE contains WHERE in IN (SELECT VALUE id FROM file WHERE <-owns<-(directory WHERE path = $path));
+        DELETE file WHERE <-owns<-(directory WHERE path = $path);
+        DELETE owns WHERE in.path = $path;
+        DELETE directory WHERE path = $path;
+        COMMIT TRANSACTION;
+        ",
+    )
+    .bind(("path", path.to_string_lossy().to_string()))
+    .await?
+    .check()?;
+
+    anyhow::Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct FileStrategyVersions {
+    path: String,
+    #[serde(default)]
+    path_bytes: Option<Vec<u8>>,
+    strategy_versions: Vec<Option<Vec<u8>>>,
+}
+
+async fn get_strategy_versions_for_directory(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+) -> anyhow::Result<HashMap<PathBuf, Option<Vec<u8>>>> {
+    let mut resp = db
+        .query(
+            "SELECT path, path_bytes, array::distinct((->contains->span).strategy_version) AS strategy_versions
+            FROM file WHERE <-owns<-(directory WHERE path = $path)",
+        )
+        .bind(("path", path.to_string_lossy().to_string()))
+        .await?;
+
+    let rows: Vec<FileStrategyVersions> = resp.take(0)?;
+    let versions = rows
+        .into_iter()
+        .map(|row| {
+            // A file only has a version if every one of its spans was parsed with it
+            let version = match row.strategy_versions.as_slice() {
+                [Some(version)] => Some(version.clone()),
+                _ => None,
+            };
+            (
+                resolve_path(PathBuf::from(row.path), row.path_bytes),
+                version,
+            )
+        })
+        .collect();
+
+    anyhow::Ok(versions)
+}
+
+async fn swap_directory_template_version(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+    template_version: u32,
+) -> anyhow::Result<Option<u32>> {
+    let mut resp = db
+        .query("SELECT VALUE template_version FROM directory WHERE path = $path")
+        .query("UPDATE directory SET template_version = $template_version WHERE path = $path")
+        .bind(("path", path.to_string_lossy().to_string()))
+        .bind(("template_version", template_version))
+        .await?
+        .check()?;
+    let previous: Vec<Option<u32>> = resp.take(0)?;
+    anyhow::Ok(previous.into_iter().next().flatten())
+}
+
+async fn search_all(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    embedding: &Vec<f32>,
+    n: usize,
+    embedding_storage: EmbeddingStorage,
+) -> anyhow::Result<Vec<SearchResult>> {
+    match embedding_storage {
+        EmbeddingStorage::Full => {
+            let mut response = db
+                .query(
+                    "
+                    SELECT id, array::first(<-contains<-file.path) as path, (<-contains<-file)[0].path_bytes as path_bytes, start_byte, end_byte, start_line, start_col, end_line, end_col, symbol_kind, anchor, blob.content AS content, vector::similarity::cosine(embedding, $target) AS similarity
+                    FROM span
+                    ORDER BY similarity DESC, path ASC, start_byte ASC LIMIT $limit",
+                )
+                .bind(("target", embedding))
+                .bind(("limit", n))
+                .await?;
+
+            let results: Vec<SearchResult> = response.take(0)?;
+            anyhow::Ok(results)
+        }
+        EmbeddingStorage::Quantized => {
+            let mut response = db
+                .query(
+                    "
+                    SELECT id, array::first(<-contains<-file.path) as path, (<-contains<-file)[0].path_bytes as path_bytes, start_byte, end_byte, start_line, start_col, end_line, end_col, symbol_kind, anchor, blob.content AS content, quantized, scale, offset
+                    FROM span
+                    WHERE quantized != NONE",
+                )
+                .await?;
+
+            let rows: Vec<QuantizedSearchRow> = response.take(0)?;
+            anyhow::Ok(rank_quantized_rows(
+                rows,
+                embedding,
+                n,
+                SimilarityMetric::Cosine,
+            ))
+        }
+    }
+}
+
+async fn get_directories(
+    db: &Surreal<surrealdb::engine::local::Db>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut response = db.query("SELECT VALUE path FROM directory").await?;
+    let paths: Vec<String> = response.take(0)?;
+    anyhow::Ok(paths.into_iter().map(PathBuf::from).collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct FacetRow {
+    #[serde(default)]
+    symbol_kind: Option<String>,
+    count: usize,
+}
+
+async fn get_symbol_kind_facets(
+    db: &Surreal<surrealdb::engine::local::Db>,
+    path: &PathBuf,
+    embedding: &Vec<f32>,
+    metric: SimilarityMetric,
+    test_filter: TestFilter,
+    min_similarity: Option<f32>,
+    embedding_storage: EmbeddingStorage,
+) -> anyhow::Result<HashMap<String, usize>> {
+    let mut facets = HashMap::new();
+
+    // Quantized spans can only be scored once dequantized, so are counted after ranking
+    if let (EmbeddingStorage::Quantized, Some(_)) = (embedding_storage, min_similarity) {
+        let results = search_quantized_directory(
+            db,
+            path,
+            embedding,
+            usize::MAX,
+            metric,
+            test_filter,
+            min_similarity,
+        )
+        .await?;
+        for symbol_kind in results.into_iter().filter_map(|result| result.symbol_kind) {
+            *facets.entry(symbol_kind).or_insert(0) += 1;
+        }
+        return anyhow::Ok(facets);
+    }
+
+    let storage_predicate = match embedding_storage {
+        EmbeddingStorage::Full => "",
+        EmbeddingStorage::Quantized => " AND quantized != NONE",
+    };
+    let query = format!(
+        "
+        SELECT symbol_kind, count() AS count
+        FROM span
+        WHERE <-contains<-file<-owns<-(directory WHERE path = $path){}{}{}
+        GROUP BY symbol_kind",
+        storage_predicate,
+        test_filter.predicate(),
+        min_similarity
+            .map(|_| metric.threshold_predicate())
+            .unwrap_or_default(),
+    );
+
+    let mut response = db
+        .query(query)
+        .bind(("path", path.to_string_lossy().to_string()))
+        .bind(("target", embedding))
+        .bind(("threshold", min_similarity.unwrap_or_default()))
+        .await?;
+    let rows: Vec<FacetRow> = response.take(0)?;
+    for row in rows {
+        if let Some(symbol_kind) = row.symbol_kind {
+            facets.insert(symbol_kind, row.count);
+        }
+    }
+
+    anyhow::Ok(facets)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::embedding::base::{
+        BagOfWordsEmbeddingProvider, EmbeddingProvider, FakeEmbeddingProvider,
+    };
+    use crate::parsers::strategy::{get_sha, LinePosition, TEMPLATE_VERSION};
+    use crate::runtime::build_runtime;
+    use crate::semantic_index::{DirectoryState, FileDetails};
+
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_create_spans() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+
+        let test_file = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo"),
+                directory_state,
+                _permit: None,
+            },
+            documents: vec![ContextDocument {
+                start_byte: 0,
+                end_byte: 10,
+                start_position: LinePosition::default(),
+                end_position: LinePosition::default(),
+                sha: vec![1, 2, 3],
+                content: "this is a test document".to_string(),
+                symbol_kind: "function_item".to_string(),
+                anchor: None,
+                is_test: false,
+            }],
+            embeddings: vec![vec![0.1, 0.2, 0.3]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+
+        let result = db.create_file_and_spans(test_file).await;
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch_persisted_files() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+        let mut persisted = db.watch_persisted_files(&directory_path).await.unwrap();
+
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        directory_state.new_job();
+
+        let test_file = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo"),
+                directory_state,
+                _permit: None,
+            },
+            documents: vec![ContextDocument {
+                start_byte: 0,
+                end_byte: 10,
+                start_position: LinePosition::default(),
+                end_position: LinePosition::default(),
+                sha: vec![1, 2, 3],
+                content: "this is a test document".to_string(),
+                symbol_kind: "function_item".to_string(),
+                anchor: None,
+                is_test: false,
+            }],
+            embeddings: vec![vec![0.1, 0.2, 0.3]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+
+        db.create_file_and_spans(test_file).await.unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), persisted.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*persisted.borrow(), 1);
+    }
+
+    async fn _test_create_spans_and_search() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        let provider = BagOfWordsEmbeddingProvider;
+
+        let files = [
+            (
+                "/tmp/foo",
+                vec![
+                    "fn open_database(path: &Path) -> Database",
+                    "fn parse_config(file: &Path) -> Config",
+                ],
+            ),
+            (
+                "/tmp/foo2",
+                vec!["fn render_results(results: Vec<SearchResult>)"],
+            ),
+        ];
+        for (path, contents) in files {
+            let documents = contents
+                .iter()
+                .enumerate()
+                .map(|(idx, content)| ContextDocument {
+                    start_byte: idx * 50,
+                    end_byte: idx * 50 + content.len(),
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: get_sha(content),
+                    content: content.to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                })
+                .collect::<Vec<ContextDocument>>();
+            let embeddings = provider
+                .embed_texts(contents.iter().map(|content| content.to_string()).collect())
+                .await
+                .unwrap();
+
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(path),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents,
+                embeddings,
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            db.create_file_and_spans(test_file).await.unwrap();
+        }
+
+        let query = provider
+            .embed_query("parse the config file".to_string())
+            .await
+            .unwrap();
+        let search_results = db
+            .get_top_neighbours(directory_path, &query, 1)
+            .await
+            .unwrap();
+
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].path, PathBuf::from("/tmp/foo"));
+        assert_eq!(search_results[0].start_byte, 50);
+        assert_eq!(search_results[0].end_byte, 88);
+    }
+
+    #[test]
+    fn test_create_spans_and_search() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_create_spans_and_search())
+    }
+
+    async fn _test_search_min_similarity() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        directory_state.new_job();
+
+        let test_file = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            documents: (0..2)
+                .map(|i| ContextDocument {
+                    start_byte: i * 20,
+                    end_byte: i * 20 + 13,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![i as u8],
+                    content: format!("fn parse_{i}() {{}}"),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                })
+                .collect(),
+            embeddings: vec![vec![1.0, 0.0], vec![0.0, 1.0]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        db.create_file_and_spans(test_file).await.unwrap();
+
+        for (metric, min_similarity, expected) in [
+            (SimilarityMetric::Cosine, None, vec![0, 20]),
+            (SimilarityMetric::Cosine, Some(0.5), vec![0]),
+            (SimilarityMetric::Cosine, Some(1.5), vec![]),
+            (SimilarityMetric::Euclidean, Some(0.5), vec![0]),
+        ] {
+            let results = db
+                .get_top_neighbours_with_metric(
+                    directory_path.clone(),
+                    &vec![1.0, 0.0],
+                    10,
+                    metric,
+                    TestFilter::All,
+                    min_similarity,
+                )
+                .await
+                .unwrap();
+            let start_bytes = results
+                .iter()
+                .map(|result| result.start_byte)
+                .collect::<Vec<usize>>();
+            assert_eq!(start_bytes, expected);
+        }
+    }
+
+    #[test]
+    fn test_search_min_similarity() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_min_similarity())
+    }
+
+    async fn _test_search_metrics() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        directory_state.new_job();
+
+        // The second span points the same way as the first, but is longer
+        let test_file = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            documents: (0..2)
+                .map(|i| ContextDocument {
+                    start_byte: i * 20,
+                    end_byte: i * 20 + 13,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![i as u8],
+                    content: format!("fn parse_{i}() {{}}"),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                })
+                .collect(),
+            embeddings: vec![vec![1.0, 0.0], vec![3.0, 0.5]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        db.create_file_and_spans(test_file).await.unwrap();
+
+        // Distances rank ascending, while similarities and dot products rank descending
+        for (metric, expected) in [
+            (SimilarityMetric::Cosine, vec![0, 20]),
+            (SimilarityMetric::Euclidean, vec![0, 20]),
+            (SimilarityMetric::Dot, vec![20, 0]),
+        ] {
+            let results = db
+                .get_top_neighbours_with_metric(
+                    directory_path.clone(),
+                    &vec![1.0, 0.0],
+                    10,
+                    metric,
+                    TestFilter::All,
+                    None,
+                )
+                .await
+                .unwrap();
+            let start_bytes = results
+                .iter()
+                .map(|result| result.start_byte)
+                .collect::<Vec<usize>>();
+            assert_eq!(start_bytes, expected, "{:?}", metric);
+        }
+
+        let results = db
+            .get_top_neighbours_with_metric(
+                directory_path.clone(),
+                &vec![1.0, 0.0],
+                10,
+                SimilarityMetric::Dot,
+                TestFilter::All,
+                Some(2.0),
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert!((results[0].similarity - 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_search_metrics() {
+        build_runtime().unwrap().block_on(_test_search_metrics())
+    }
+
+    async fn _test_search_test_filter() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        directory_state.new_job();
+
+        let test_file = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            documents: vec![
+                ContextDocument {
+                    start_byte: 0,
+                    end_byte: 13,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![1],
+                    content: "fn parse() {}".to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                },
+                ContextDocument {
+                    start_byte: 23,
+                    end_byte: 37,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![2],
+                    content: "fn parses() {}".to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: true,
+                },
+            ],
+            embeddings: vec![vec![0.1, 0.2, 0.3], vec![0.1, 0.2, 0.3]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        db.create_file_and_spans(test_file).await.unwrap();
+
+        for (test_filter, expected) in [
+            (TestFilter::All, vec![0, 23]),
+            (TestFilter::ExcludeTests, vec![0]),
+            (TestFilter::OnlyTests, vec![23]),
+        ] {
+            let results = db
+                .get_top_neighbours_with_metric(
+                    directory_path.clone(),
+                    &vec![0.1, 0.2, 0.3],
+                    10,
+                    SimilarityMetric::Cosine,
+                    test_filter,
+                    None,
+                )
+                .await
+                .unwrap();
+            let start_bytes = results
+                .iter()
+                .map(|result| result.start_byte)
+                .collect::<Vec<usize>>();
+            assert_eq!(start_bytes, expected);
+        }
+    }
+
+    #[test]
+    fn test_search_test_filter() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_test_filter())
+    }
+
+    async fn _test_search_ties_are_stable() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+
+        // The constant provider gives every span the same similarity to any query
+        let provider = FakeEmbeddingProvider;
+        for (path, start_bytes) in [("/tmp/b", vec![20, 0]), ("/tmp/a", vec![0])] {
+            let documents = start_bytes
+                .iter()
+                .map(|start_byte| ContextDocument {
+                    start_byte: *start_byte,
+                    end_byte: start_byte + 10,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![*start_byte as u8],
+                    content: format!("fn function_{start_byte}() {{}}"),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                })
+                .collect::<Vec<ContextDocument>>();
+            let embeddings = provider
+                .embed_texts(documents.iter().map(|doc| doc.content.clone()).collect())
+                .await
+                .unwrap();
+
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(path),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents,
+                embeddings,
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            db.create_file_and_spans(test_file).await.unwrap();
+        }
+
+        let query = provider.embed_query("parse".to_string()).await.unwrap();
+        for _ in 0..3 {
+            let search_results = db
+                .get_top_neighbours(directory_path.clone(), &query, 3)
+                .await
+                .unwrap();
+            let locations = search_results
+                .iter()
+                .map(|result| (result.path.clone(), result.start_byte))
+                .collect::<Vec<(PathBuf, usize)>>();
+            assert_eq!(
+                locations,
+                vec![
+                    (PathBuf::from("/tmp/a"), 0),
+                    (PathBuf::from("/tmp/b"), 0),
+                    (PathBuf::from("/tmp/b"), 20),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_search_ties_are_stable() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_ties_are_stable())
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_shas() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        for (path, shas) in [("/tmp/foo", vec![1, 2]), ("/tmp/bar", vec![1, 3])] {
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(path),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: shas
+                    .iter()
+                    .map(|sha| ContextDocument {
+                        start_byte: 0,
+                        end_byte: 10,
+                        start_position: LinePosition::default(),
+                        end_position: LinePosition::default(),
+                        sha: vec![*sha],
+                        content: "fn duplicated() {}".to_string(),
+                        symbol_kind: "function_item".to_string(),
+                        anchor: None,
+                        is_test: false,
+                    })
+                    .collect(),
+                embeddings: vec![vec![0.1, 0.2, 0.3]; 2],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            db.create_file_and_spans(test_file).await.unwrap();
+        }
+
+        let duplicates = db.duplicate_shas().await.unwrap();
+        assert_eq!(duplicates, vec![(vec![1], 2)]);
+    }
+
+    async fn _test_rank_file_spans() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+
+        let embeddings = vec![
+            vec![0.1, 0.9, 0.1],
+            vec![0.9, 0.1, 0.1],
+            vec![0.5, 0.5, 0.1],
+        ];
+        let test_file = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo"),
+                directory_state,
+                _permit: None,
+            },
+            documents: (0..3)
+                .map(|i| ContextDocument {
+                    start_byte: i * 10,
+                    end_byte: i * 10 + 9,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![i as u8],
+                    content: format!("fn function_{i}() {{}}"),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                })
+                .collect(),
+            embeddings,
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        db.create_file_and_spans(test_file).await.unwrap();
+
+        let ranked = db
+            .rank_file_spans(&PathBuf::from("/tmp/foo"), &vec![1.0, 0.0, 0.0])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            ranked
+                .iter()
+                .map(|result| result.start_byte)
+                .collect::<Vec<usize>>(),
+            vec![10, 20, 0]
+        );
+    }
+
+    #[test]
+    fn test_rank_file_spans() {
+        build_runtime().unwrap().block_on(_test_rank_file_spans())
+    }
+
+    async fn _test_search_directory_pages() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        directory_state.new_job();
+
+        // Includes identical embeddings, to check ties are split across pages consistently.
+        let embeddings = vec![
+            vec![0.9, 0.1, 0.1],
+            vec![0.5, 0.5, 0.1],
+            vec![0.5, 0.5, 0.1],
+            vec![0.1, 0.9, 0.1],
+            vec![0.5, 0.5, 0.1],
+        ];
+        let test_file = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo"),
+                directory_state,
+                _permit: None,
+            },
+            documents: (0..embeddings.len())
+                .map(|i| ContextDocument {
+                    start_byte: i * 10,
+                    end_byte: i * 10 + 9,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![i as u8],
+                    content: format!("fn function_{i}() {{}}"),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                })
+                .collect(),
+            embeddings,
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        db.create_file_and_spans(test_file).await.unwrap();
+
+        let target = vec![1.0, 0.0, 0.0];
+        let mut paged = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db
+                .get_top_neighbours_page(directory_path.clone(), &target, 2, cursor)
+                .await
+                .unwrap();
+            assert!(page.results.len() <= 2);
+            paged.extend(page.results);
+
+            match page.next_cursor {
+                Some(next_cursor) => cursor = Some(SearchCursor::decode(&next_cursor).unwrap()),
+                None => break,
+            }
+        }
+
+        let paged_ids = paged
+            .iter()
+            .map(|result| result.id.clone())
+            .collect::<Vec<_>>();
+        let unique_ids = HashSet::<RecordId>::from_iter(paged_ids.clone());
+        assert_eq!(paged_ids.len(), 5);
+        assert_eq!(unique_ids.len(), 5);
+        assert_eq!(paged[0].start_byte, 0);
+        assert_eq!(paged[4].start_byte, 30);
+    }
+
+    #[test]
+    fn test_search_directory_pages() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_directory_pages())
+    }
+
+    #[test]
+    fn test_search_cursor_round_trip() {
+        let cursor = SearchCursor {
+            similarity: 0.123456789,
+            id: surrealdb::sql::thing("span:abc123").unwrap(),
+        };
+        assert_eq!(SearchCursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(SearchCursor::decode("not a cursor").is_err());
+    }
+
+    async fn _test_set_span_description() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        directory_state.new_job();
+
+        let test_file = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo"),
+                directory_state,
+                _permit: None,
+            },
+            documents: vec![
+                ContextDocument {
+                    start_byte: 0,
+                    end_byte: 10,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![1],
+                    content: "fn parse() {}".to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                },
+                ContextDocument {
+                    start_byte: 12,
+                    end_byte: 20,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![2],
+                    content: "fn render() {}".to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                },
+            ],
+            embeddings: vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        db.create_file_and_spans(test_file).await.unwrap();
+
+        let parse_span = db
+            .get_top_neighbours(directory_path.clone(), &vec![1.0, 0.0, 0.0], 1)
+            .await
+            .unwrap()
+            .remove(0);
+
+        // Setting the description twice should replace, rather than add to, the first
+        for _ in 0..2 {
+            db.set_span_description(parse_span.id.clone(), vec![3], vec![0.0, 0.0, 1.0])
+                .await
+                .unwrap();
+        }
+
+        let results = db
+            .get_top_neighbours(directory_path, &vec![0.0, 0.0, 1.0], 3)
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].path, PathBuf::from("/tmp/foo"));
+        assert_eq!(results[0].start_byte, 0);
+        assert_eq!(results[0].end_byte, 10);
+        assert_eq!(
+            results[0].symbol_kind,
+            Some(DESCRIPTION_SYMBOL_KIND.to_string())
+        );
+    }
+
+    #[test]
+    fn test_set_span_description() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_set_span_description())
+    }
 
-        let result = db.create_file_and_spans(test_file).await;
-        result.unwrap();
+    #[cfg(unix)]
+    async fn _test_non_utf8_path_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+
+        // Latin-1 encoded, so not valid UTF8, and both with the same lossy string form
+        let file_path = PathBuf::from(OsStr::from_bytes(b"/tmp/caf\xe9.rs"));
+        let other_path = PathBuf::from(OsStr::from_bytes(b"/tmp/caf\xe8.rs"));
+        assert!(file_path.to_str().is_none());
+        assert_eq!(file_path.to_string_lossy(), other_path.to_string_lossy());
+
+        for (path, embedding) in [
+            (&file_path, vec![1.0, 0.0, 0.0]),
+            (&other_path, vec![0.0, 1.0, 0.0]),
+        ] {
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: path.clone(),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: vec![ContextDocument {
+                    start_byte: 0,
+                    end_byte: 10,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![1],
+                    content: "fn parse() {}".to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                }],
+                embeddings: vec![embedding],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            db.create_file_and_spans(test_file).await.unwrap();
+        }
+
+        let results = db
+            .get_top_neighbours(directory_path.clone(), &vec![1.0, 0.0, 0.0], 1)
+            .await
+            .unwrap();
+        assert_eq!(results[0].path, file_path);
+
+        let files = db.get_files_for_directory(&directory_path).await.unwrap();
+        assert_eq!(
+            files,
+            HashSet::from([file_path.clone(), other_path.clone()])
+        );
+
+        // Deleting one file leaves the other, despite their matching lossy paths
+        db.delete_file(&other_path).await.unwrap();
+        let files = db.get_files_for_directory(&directory_path).await.unwrap();
+        assert_eq!(files, HashSet::from([file_path]));
+        let results = db
+            .get_top_neighbours(directory_path, &vec![1.0, 0.0, 0.0], 10)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
     }
 
-    async fn _test_create_spans_and_search() {
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path_round_trip() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_non_utf8_path_round_trip())
+    }
+
+    async fn _test_get_embeddings_for_directory() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+        let other_directory_id = db
+            .get_or_create_directory(&PathBuf::from("/other"))
+            .await
+            .unwrap();
+
+        let mut expected = HashMap::new();
+        for (owner_id, path, content, embedding) in [
+            (
+                &directory_id,
+                "/tmp/foo",
+                "fn parse() {}",
+                vec![1.0, 0.0, 0.0],
+            ),
+            (
+                &directory_id,
+                "/tmp/bar",
+                "fn render() {}",
+                vec![0.0, 1.0, 0.0],
+            ),
+            (
+                &other_directory_id,
+                "/other/baz",
+                "fn other() {}",
+                vec![0.0, 0.0, 1.0],
+            ),
+        ] {
+            let directory_state = Arc::new(DirectoryState::new(owner_id.clone()));
+            directory_state.new_job();
+
+            // Keyed by the same sha computed at parse time, so unchanged spans are found
+            let sha = get_sha(content);
+            if owner_id == &directory_id {
+                expected.insert(sha.clone(), embedding.clone());
+            }
+
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(path),
+                    directory_state,
+                    _permit: None,
+                },
+                documents: vec![ContextDocument {
+                    start_byte: 0,
+                    end_byte: content.len(),
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha,
+                    content: content.to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                }],
+                embeddings: vec![embedding],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            db.create_file_and_spans(test_file).await.unwrap();
+        }
+
+        let embeddings = db
+            .get_embeddings_for_directory(&directory_path, TEMPLATE_VERSION)
+            .await
+            .unwrap();
+        assert_eq!(embeddings, expected);
+    }
+
+    #[test]
+    fn test_get_embeddings_for_directory() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_get_embeddings_for_directory())
+    }
+
+    async fn _test_writes_persist_after_shutdown() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path.clone()).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+
+        for i in 0..20 {
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(format!("/tmp/foo{i}")),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: (0..3)
+                    .map(|j| ContextDocument {
+                        start_byte: j * 10,
+                        end_byte: j * 10 + 9,
+                        start_position: LinePosition::default(),
+                        end_position: LinePosition::default(),
+                        sha: vec![i as u8, j as u8],
+                        content: format!("fn function_{i}_{j}() {{}}"),
+                        symbol_kind: "function_item".to_string(),
+                        anchor: None,
+                        is_test: false,
+                    })
+                    .collect(),
+                embeddings: vec![vec![0.1, 0.2, 0.3]; 3],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            db.create_file_and_spans(test_file).await.unwrap();
+        }
+
+        db.shutdown().await.unwrap();
+        assert!(db.get_files_for_directory(&directory_path).await.is_err());
+
+        // Everything written is present once the database is reopened
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+        let files = db.get_files_for_directory(&directory_path).await.unwrap();
+        assert_eq!(files.len(), 20);
+        let results = db
+            .get_top_neighbours(directory_path, &vec![0.1, 0.2, 0.3], 100)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 60);
+    }
+
+    #[test]
+    fn test_writes_persist_after_shutdown() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_writes_persist_after_shutdown())
+    }
+
+    async fn _test_abandoned_search_cancelled() {
+        let tmp_dir = tempdir().unwrap();
+        let db = VectorDatabase::initialize(tmp_dir.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let directory = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory).await.unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        for file in 0..10 {
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(format!("/tmp/foo{file}.rs")),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: (0..10)
+                    .map(|i| ContextDocument {
+                        start_byte: i * 10,
+                        end_byte: i * 10 + 9,
+                        start_position: LinePosition::default(),
+                        end_position: LinePosition::default(),
+                        sha: vec![file as u8, i as u8],
+                        content: format!("fn function_{i}() {{}}"),
+                        symbol_kind: "function_item".to_string(),
+                        anchor: None,
+                        is_test: false,
+                    })
+                    .collect(),
+                embeddings: vec![vec![0.1, 0.2, 0.3]; 10],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            db.create_file_and_spans(test_file).await.unwrap();
+        }
+
+        // Polling once queues the search job, which is then abandoned by dropping the future
+        let embedding = vec![0.1, 0.2, 0.3];
+        let mut search = Box::pin(db.get_top_neighbours(directory.clone(), &embedding, 5));
+        assert!(futures::poll!(search.as_mut()).is_pending());
+        drop(search);
+
+        // Paged and facet searches are abandoned alike
+        let mut page = Box::pin(db.get_top_neighbours_page(directory.clone(), &embedding, 5, None));
+        assert!(futures::poll!(page.as_mut()).is_pending());
+        drop(page);
+        let mut facets = Box::pin(db.get_symbol_kind_facets(
+            directory.clone(),
+            &embedding,
+            SimilarityMetric::default(),
+            TestFilter::All,
+            None,
+        ));
+        assert!(futures::poll!(facets.as_mut()).is_pending());
+        drop(facets);
+
+        // Jobs are executed in order, so the abandoned search has been handled by the time a
+        // later search returns
+        let results = db
+            .get_top_neighbours(directory.clone(), &embedding, 5)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 5);
+        assert_eq!(db.searches_cancelled(), 3);
+    }
+
+    #[test]
+    fn test_abandoned_search_cancelled() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_abandoned_search_cancelled())
+    }
+
+    async fn _test_reindex_preserves_span_ids() {
+        let tmp_dir = tempdir().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let db = VectorDatabase::initialize(tmp_path).await.unwrap();
+
+        let directory_path = PathBuf::from("/tmp");
+        let directory_id = db.get_or_create_directory(&directory_path).await.unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from("/tmp/foo"),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: vec![ContextDocument {
+                    start_byte: 0,
+                    end_byte: 10,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![1, 2, 3],
+                    content: "fn parse() {}".to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                }],
+                embeddings: vec![vec![0.1, 0.2, 0.3]],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            db.create_file_and_spans(test_file).await.unwrap();
+
+            let results = db
+                .get_top_neighbours(directory_path.clone(), &vec![0.1, 0.2, 0.3], 10)
+                .await
+                .unwrap();
+            assert_eq!(results.len(), 1);
+            ids.push(results[0].id.clone());
+        }
+
+        assert_eq!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn test_reindex_preserves_span_ids() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_reindex_preserves_span_ids())
+    }
+
+    async fn _test_reindex_upserts_changed_spans() {
+        let tmp_dir = tempdir().unwrap();
+        let db = Surreal::new::<RocksDb>(tmp_dir.path().join("temp.db"))
+            .await
+            .unwrap();
+        db.use_ns("auden").use_db("auden").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let directory_id = get_or_create_directory(&db, &PathBuf::from("/tmp"))
+            .await
+            .unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+
+        // The middle function is edited, growing it and moving the one after it
+        let versions = [
+            ["fn first() {}", "fn second() {}", "fn third() {}"],
+            [
+                "fn first() {}",
+                "fn second() { edited(); }",
+                "fn third() {}",
+            ],
+        ];
+        let mut span_ids = Vec::new();
+        for functions in versions {
+            directory_state.new_job();
+            let mut start_byte = 0;
+            let documents = functions
+                .iter()
+                .map(|function| {
+                    let document = ContextDocument {
+                        start_byte,
+                        end_byte: start_byte + function.len(),
+                        start_position: LinePosition::default(),
+                        end_position: LinePosition::default(),
+                        sha: get_sha(function),
+                        content: function.to_string(),
+                        symbol_kind: "function_item".to_string(),
+                        anchor: None,
+                        is_test: false,
+                    };
+                    start_byte += function.len() + 1;
+                    document
+                })
+                .collect::<Vec<ContextDocument>>();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from("/tmp/foo.rs"),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                embeddings: vec![vec![0.1, 0.2, 0.3]; documents.len()],
+                documents,
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            create_file_and_spans(
+                &db,
+                test_file,
+                EmbeddingStorage::default(),
+                ContentStorage::default(),
+            )
+            .await
+            .unwrap();
+
+            let mut response = db
+                .query("SELECT VALUE meta::id(id) FROM span; SELECT count() FROM contains GROUP ALL; SELECT count() FROM file GROUP ALL")
+                .await
+                .unwrap();
+            let ids: Vec<String> = response.take(0).unwrap();
+            let contains: Option<usize> = response.take((1, "count")).unwrap();
+            let files: Option<usize> = response.take((2, "count")).unwrap();
+            assert_eq!(ids.len(), 3);
+            assert_eq!(contains, Some(3));
+            assert_eq!(files, Some(1));
+            span_ids.push(ids.into_iter().collect::<HashSet<String>>());
+        }
+
+        // Only the edited span is replaced, the others keep their ids
+        assert_eq!(span_ids[0].intersection(&span_ids[1]).count(), 2);
+        assert_eq!(span_ids[1].difference(&span_ids[0]).count(), 1);
+        assert_eq!(span_ids[0].difference(&span_ids[1]).count(), 1);
+
+        // The moved span is updated with its new position
+        let mut response = db
+            .query("SELECT VALUE start_byte FROM span WHERE sha = $sha")
+            .bind(("sha", get_sha("fn third() {}")))
+            .await
+            .unwrap();
+        let start_bytes: Vec<usize> = response.take(0).unwrap();
+        assert_eq!(
+            start_bytes,
+            vec!["fn first() {}\nfn second() { edited(); }\n".len()]
+        );
+    }
+
+    #[test]
+    fn test_reindex_upserts_changed_spans() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_reindex_upserts_changed_spans())
+    }
+
+    #[test]
+    fn test_backpressure_warning() {
+        let (executor, _receiver) = mpsc::channel::<DatabaseJob>(10);
+        let db = VectorDatabase {
+            executor,
+            searches_cancelled: Arc::default(),
+        };
+        assert!(!db.check_backpressure());
+
+        for _ in 0..10 {
+            let (sender, _) = oneshot::channel();
+            db.executor
+                .try_send(DatabaseJob::GetPathsForDirectory {
+                    path: PathBuf::from("/tmp"),
+                    sender,
+                })
+                .unwrap();
+        }
+        assert!(db.check_backpressure());
+    }
+
+    async fn _test_non_finite_embeddings_rejected() {
         let tmp_dir = tempdir().unwrap();
         let tmp_path = PathBuf::from(tmp_dir.path());
         let db = VectorDatabase::initialize(tmp_path).await.unwrap();
@@ -549,70 +3624,444 @@ mod tests {
             details: FileDetails {
                 path: PathBuf::from("/tmp/foo"),
                 directory_state: directory_state.clone(),
+                _permit: None,
             },
             documents: vec![
                 ContextDocument {
                     start_byte: 0,
                     end_byte: 10,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
                     sha: vec![1, 2, 3],
                     content: "this is a test document".to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
                 },
                 ContextDocument {
-                    start_byte: 1,
-                    end_byte: 12,
+                    start_byte: 11,
+                    end_byte: 20,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
                     sha: vec![4, 5, 6],
-                    content: "this is a second test document".to_string(),
+                    content: "this is a poisoned test document".to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
                 },
             ],
-            embeddings: vec![vec![0.1, 0.2, 0.3], vec![0.9, 0.9, 0.1]],
+            embeddings: vec![vec![0.1, 0.2, 0.3], vec![f32::NAN, 0.2, 0.3]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
         }));
 
-        let result = db.create_file_and_spans(test_file).await;
-        result.unwrap();
+        db.create_file_and_spans(test_file).await.unwrap();
 
-        let test_file2 = Arc::new(Mutex::new(FileContext {
-            details: FileDetails {
-                path: PathBuf::from("/tmp/foo2"),
-                directory_state: directory_state.clone(),
-            },
-            documents: vec![ContextDocument {
-                start_byte: 1,
-                end_byte: 12,
-                sha: vec![4, 5, 6],
-                content: "this is a second test document".to_string(),
-            }],
-            embeddings: vec![vec![0.5, 0.2, 0.3]],
-        }));
+        let search_results = db
+            .get_top_neighbours(directory_path, &vec![0.1, 0.2, 0.3], 10)
+            .await
+            .unwrap();
 
-        directory_state.new_job();
-        let result = db.create_file_and_spans(test_file2).await;
-        result.unwrap();
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].start_byte, 0);
+        assert!(search_results[0].similarity.is_finite());
+    }
 
-        let search_results = db
-            .get_top_neighbours(directory_path, &vec![0.8, 0.9, 0.2], 1)
+    #[test]
+    fn test_non_finite_embeddings_rejected() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_non_finite_embeddings_rejected())
+    }
+
+    async fn _test_create_file_relates_unusual_ids() {
+        let tmp_dir = tempdir().unwrap();
+        let db = Surreal::new::<RocksDb>(tmp_dir.path().join("temp.db"))
+            .await
+            .unwrap();
+        db.use_ns("auden").use_db("auden").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        // Neither id is a valid bare record id token
+        for directory_id in ["my-dir.v2", "it's 1"] {
+            let _: Option<Record> = db
+                .create(("directory", directory_id))
+                .content(Directory {
+                    path: format!("/tmp/{directory_id}"),
+                })
+                .await
+                .unwrap();
+
+            let path = PathBuf::from(format!("/tmp/{directory_id}/foo.rs"));
+            let file_id = create_file(&db, &path, directory_id.to_string())
+                .await
+                .unwrap();
+
+            let mut response = db
+                .query("SELECT VALUE out FROM owns WHERE in = $directory")
+                .bind(("directory", Thing::from(("directory", directory_id))))
+                .await
+                .unwrap();
+            let owned: Vec<Thing> = response.take(0).unwrap();
+            assert_eq!(owned, vec![Thing::from(("file", file_id.as_str()))]);
+
+            // The directory's path is bound rather than quoted into the queries
+            let directory = PathBuf::from(format!("/tmp/{directory_id}"));
+            let files = get_files_for_directory(&db, &directory).await.unwrap();
+            assert_eq!(files, HashSet::from([path]));
+            let results = search_directory(
+                &db,
+                &directory,
+                &vec![1.0, 0.0],
+                10,
+                SimilarityMetric::Cosine,
+                TestFilter::All,
+                None,
+            )
             .await
             .unwrap();
+            assert!(results.is_empty());
+        }
+    }
 
-        assert_eq!(search_results[0].path, PathBuf::from("/tmp/foo"));
-        assert_eq!(search_results[0].start_byte, 1);
-        assert_eq!(search_results[0].end_byte, 12);
+    #[test]
+    fn test_create_file_relates_unusual_ids() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_create_file_relates_unusual_ids())
+    }
+
+    async fn _test_delete_file_and_spans() {
+        let tmp_dir = tempdir().unwrap();
+        let db = Surreal::new::<RocksDb>(tmp_dir.path().join("temp.db"))
+            .await
+            .unwrap();
+        db.use_ns("auden").use_db("auden").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let directory_id = get_or_create_directory(&db, &PathBuf::from("/tmp"))
+            .await
+            .unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+
+        // A quote in the path must not break the queries
+        for path in ["/tmp/foo", "/tmp/it's"] {
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(path),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: (0..3)
+                    .map(|i| ContextDocument {
+                        start_byte: i * 10,
+                        end_byte: i * 10 + 9,
+                        start_position: LinePosition::default(),
+                        end_position: LinePosition::default(),
+                        sha: vec![i as u8],
+                        content: format!("fn function_{i}() {{}}"),
+                        symbol_kind: "function_item".to_string(),
+                        anchor: None,
+                        is_test: false,
+                    })
+                    .collect(),
+                embeddings: vec![vec![0.1, 0.2, 0.3]; 3],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            create_file_and_spans(
+                &db,
+                test_file,
+                EmbeddingStorage::default(),
+                ContentStorage::default(),
+            )
+            .await
+            .unwrap();
+        }
+
+        let spans: Vec<Span> = db.select("span").await.unwrap();
+        assert_eq!(spans.len(), 6);
+
+        delete_file_and_spans(&db, &PathBuf::from("/tmp/it's"))
+            .await
+            .unwrap();
+
+        let spans: Vec<Span> = db.select("span").await.unwrap();
+        assert_eq!(spans.len(), 3);
+        let files: Vec<File> = db.select("file").await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "/tmp/foo");
+
+        // No span or relation is left behind for the deleted path
+        let mut response = db
+            .query("SELECT count() FROM span WHERE <-contains<-(file WHERE path = $path) GROUP ALL; SELECT VALUE in.path FROM contains")
+            .bind(("path", "/tmp/it's"))
+            .await
+            .unwrap();
+        let deleted: Option<usize> = response.take((0, "count")).unwrap();
+        let contained: Vec<String> = response.take(1).unwrap();
+        assert_eq!(deleted, None);
+        assert_eq!(contained, vec!["/tmp/foo".to_string(); 3]);
+
+        delete_file_and_spans(&db, &PathBuf::from("/tmp/foo"))
+            .await
+            .unwrap();
+
+        let spans: Vec<Span> = db.select("span").await.unwrap();
+        assert!(spans.is_empty());
+        let mut response = db
+            .query("SELECT count() FROM contains GROUP ALL")
+            .await
+            .unwrap();
+        let contains: Option<usize> = response.take((0, "count")).unwrap();
+        assert_eq!(contains, None);
     }
 
     #[test]
-    fn test_create_spans_and_search() {
-        // This hack is here because of the following issue with surrealdb
-        // https://github.com/surrealdb/surrealdb/issues/2920
-        let stack_size = 10 * 1024 * 1024;
-
-        // Stack frames are generally larger in debug mode.
-        #[cfg(debug_assertions)]
-        let stack_size = stack_size * 2;
-
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .thread_stack_size(stack_size)
-            .build()
+    fn test_delete_file_and_spans() {
+        build_runtime()
             .unwrap()
-            .block_on(_test_create_spans_and_search())
+            .block_on(_test_delete_file_and_spans())
+    }
+
+    async fn _test_reindex_leaves_no_orphaned_spans() {
+        let tmp_dir = tempdir().unwrap();
+        let db = Surreal::new::<RocksDb>(tmp_dir.path().join("temp.db"))
+            .await
+            .unwrap();
+        db.use_ns("auden").use_db("auden").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        let directory_id = get_or_create_directory(&db, &PathBuf::from("/tmp"))
+            .await
+            .unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+
+        // Every span changes on each reindex, so all of the previous spans are replaced
+        for version in 0..3u8 {
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from("/tmp/foo.rs"),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: (0..3)
+                    .map(|i| ContextDocument {
+                        start_byte: i * 10,
+                        end_byte: i * 10 + 9,
+                        start_position: LinePosition::default(),
+                        end_position: LinePosition::default(),
+                        sha: vec![version, i as u8],
+                        content: format!("fn function_{version}_{i}() {{}}"),
+                        symbol_kind: "function_item".to_string(),
+                        anchor: None,
+                        is_test: false,
+                    })
+                    .collect(),
+                embeddings: vec![vec![0.1, 0.2, 0.3]; 3],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            create_file_and_spans(
+                &db,
+                test_file,
+                EmbeddingStorage::default(),
+                ContentStorage::default(),
+            )
+            .await
+            .unwrap();
+
+            let spans: Vec<Span> = db.select("span").await.unwrap();
+            assert_eq!(spans.len(), 3);
+            assert!(spans.iter().all(|span| span.sha[0] == version));
+            let mut response = db
+                .query("SELECT count() FROM contains GROUP ALL")
+                .await
+                .unwrap();
+            let contains: Option<usize> = response.take((0, "count")).unwrap();
+            assert_eq!(contains, Some(3));
+        }
+    }
+
+    #[test]
+    fn test_reindex_leaves_no_orphaned_spans() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_reindex_leaves_no_orphaned_spans())
+    }
+
+    async fn _test_blob_content_storage() {
+        let tmp_dir = tempdir().unwrap();
+        let db = Surreal::new::<RocksDb>(tmp_dir.path().join("temp.db"))
+            .await
+            .unwrap();
+        db.use_ns("auden").use_db("auden").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        // Two identical spans in the same file, which has been edited since it was parsed
+        let directory = tmp_dir.path().join("src");
+        std::fs::create_dir(&directory).unwrap();
+        let path = directory.join("foo.rs");
+        std::fs::write(&path, "fn bar() {}\n").unwrap();
+
+        let directory_id = get_or_create_directory(&db, &directory).await.unwrap();
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        let file_context = |source: &str| {
+            directory_state.new_job();
+            Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: path.clone(),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: [0, 12]
+                    .into_iter()
+                    .map(|start_byte| ContextDocument {
+                        start_byte,
+                        end_byte: start_byte + 11,
+                        start_position: LinePosition::default(),
+                        end_position: LinePosition::default(),
+                        sha: vec![start_byte as u8],
+                        // Content is taken for embedding before spans are written
+                        content: String::new(),
+                        symbol_kind: "function_item".to_string(),
+                        anchor: None,
+                        is_test: false,
+                    })
+                    .collect(),
+                embeddings: vec![vec![0.1, 0.2, 0.3]; 2],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(source),
+            }))
+        };
+
+        // Spans outside of the parsed content fail to write, rather than losing their content
+        assert!(create_file_and_spans(
+            &db,
+            file_context("fn foo() {}\n"),
+            EmbeddingStorage::default(),
+            ContentStorage::Blob,
+        )
+        .await
+        .is_err());
+
+        create_file_and_spans(
+            &db,
+            file_context("fn foo() {}\nfn foo() {}\n"),
+            EmbeddingStorage::default(),
+            ContentStorage::Blob,
+        )
+        .await
+        .unwrap();
+
+        let mut response = db
+            .query("SELECT count() FROM blob GROUP ALL")
+            .await
+            .unwrap();
+        let blobs: Option<usize> = response.take((0, "count")).unwrap();
+        assert_eq!(blobs, Some(1));
+
+        let results = search_directory(
+            &db,
+            &directory,
+            &vec![0.1, 0.2, 0.3],
+            10,
+            SimilarityMetric::Cosine,
+            TestFilter::All,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|result| result.content.as_deref() == Some("fn foo() {}")));
+    }
+
+    #[test]
+    fn test_blob_content_storage() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_blob_content_storage())
+    }
+
+    async fn _test_delete_directory() {
+        let tmp_dir = tempdir().unwrap();
+        let db = Surreal::new::<RocksDb>(tmp_dir.path().join("temp.db"))
+            .await
+            .unwrap();
+        db.use_ns("auden").use_db("auden").await.unwrap();
+        run_migrations(&db).await.unwrap();
+
+        for directory in ["/tmp/a", "/tmp/b"] {
+            let directory_id = get_or_create_directory(&db, &PathBuf::from(directory))
+                .await
+                .unwrap();
+            let directory_state = Arc::new(DirectoryState::new(directory_id));
+            directory_state.new_job();
+            let test_file = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(format!("{directory}/foo.rs")),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: (0..3)
+                    .map(|i| ContextDocument {
+                        start_byte: i * 10,
+                        end_byte: i * 10 + 9,
+                        start_position: LinePosition::default(),
+                        end_position: LinePosition::default(),
+                        sha: vec![i as u8],
+                        content: format!("fn function_{i}() {{}}"),
+                        symbol_kind: "function_item".to_string(),
+                        anchor: None,
+                        is_test: false,
+                    })
+                    .collect(),
+                embeddings: vec![vec![0.1, 0.2, 0.3]; 3],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            create_file_and_spans(
+                &db,
+                test_file,
+                EmbeddingStorage::default(),
+                ContentStorage::default(),
+            )
+            .await
+            .unwrap();
+        }
+
+        delete_directory(&db, &PathBuf::from("/tmp/a"))
+            .await
+            .unwrap();
+
+        let mut response = db
+            .query("SELECT VALUE path FROM directory; SELECT VALUE out.path FROM owns; SELECT VALUE in.path FROM contains;")
+            .await
+            .unwrap();
+        let directories: Vec<String> = response.take(0).unwrap();
+        let owned: Vec<String> = response.take(1).unwrap();
+        let contained: Vec<String> = response.take(2).unwrap();
+        assert_eq!(directories, vec!["/tmp/b".to_string()]);
+        assert_eq!(owned, vec!["/tmp/b/foo.rs".to_string()]);
+        assert_eq!(contained, vec!["/tmp/b/foo.rs".to_string(); 3]);
+        let files: Vec<File> = db.select("file").await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "/tmp/b/foo.rs");
+        let spans: Vec<Span> = db.select("span").await.unwrap();
+        assert_eq!(spans.len(), 3);
+    }
+
+    #[test]
+    fn test_delete_directory() {
+        build_runtime().unwrap().block_on(_test_delete_directory())
     }
 }
@@ -0,0 +1,168 @@
+use crate::db::VectorDatabase;
+use crate::parsers::registry::ExtensionRegistry;
+use crate::parsers::strategy::{ParseOptions, ParsingStrategy};
+use crate::semantic_index::{queue_parse_job, DirectoryState, FileDetails, ParseJob};
+use ignore::gitignore::Gitignore;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+/// How long a watched directory must be quiet before its changed files are reindexed, so a
+/// file saved repeatedly in quick succession is only parsed and embedded once.
+pub(crate) const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The ignore files honoured in the watched directory and its subdirectories.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// Feeds files changed within a directory through the parsing pipeline, and removes deleted
+/// files from the database.
+pub(crate) struct DirectoryWatcher {
+    pub(crate) directory: PathBuf,
+    pub(crate) directory_state: Arc<DirectoryState>,
+    pub(crate) existing_embeddings: Arc<HashMap<Vec<u8>, Vec<f32>>>,
+    pub(crate) parsers: ExtensionRegistry,
+    pub(crate) parse_sender: mpsc::Sender<ParseJob>,
+    pub(crate) parse_options: ParseOptions,
+    pub(crate) in_flight_files: Arc<Semaphore>,
+    pub(crate) index_readme: bool,
+    pub(crate) vector_db: VectorDatabase,
+}
+
+impl DirectoryWatcher {
+    /// Starts watching the directory, returning the task reindexing its changes. The directory
+    /// is watched until the task is aborted.
+    pub(crate) fn spawn(self) -> anyhow::Result<JoinHandle<()>> {
+        let (event_tx, event_rx) = mpsc::unbounded_channel::<notify::Result<Event>>();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = event_tx.send(event);
+        })?;
+        watcher.watch(&self.directory, RecursiveMode::Recursive)?;
+
+        anyhow::Ok(tokio::spawn(async move {
+            // Events stop once the watcher is dropped, so it lives as long as the task
+            let _watcher = watcher;
+            self.run(event_rx).await;
+        }))
+    }
+
+    async fn run(self, mut event_rx: mpsc::UnboundedReceiver<notify::Result<Event>>) {
+        let mut changed = HashSet::new();
+        loop {
+            let event = if changed.is_empty() {
+                event_rx.recv().await
+            } else {
+                match tokio::time::timeout(WATCH_DEBOUNCE, event_rx.recv()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        for path in changed.drain().collect::<Vec<PathBuf>>() {
+                            if let Err(err) = self.reindex_path(path).await {
+                                log::error!("{:?}", err);
+                            }
+                        }
+                        continue;
+                    }
+                }
+            };
+
+            match event {
+                Some(Ok(event)) => {
+                    if matches!(
+                        event.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                    ) {
+                        changed.extend(
+                            event
+                                .paths
+                                .into_iter()
+                                .filter(|path| !self.is_ignored(path)),
+                        );
+                    }
+                }
+                Some(Err(err)) => {
+                    log::warn!("error watching {:?}: {:?}", self.directory, err);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Skips hidden paths, and those excluded by a `.gitignore` or `.ignore` file in any parent
+    /// within the directory, as skipped when walking the directory to index it. Ignore files
+    /// are read as changes arrive, so edits to them apply straight away.
+    fn is_ignored(&self, path: &Path) -> bool {
+        let relative = match path.strip_prefix(&self.directory) {
+            Ok(relative) => relative,
+            Err(_) => return true,
+        };
+        if relative
+            .components()
+            .any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+        {
+            return true;
+        }
+
+        let is_dir = path.is_dir();
+        path.ancestors()
+            .skip(1)
+            .take_while(|ancestor| ancestor.starts_with(&self.directory))
+            .any(|ancestor| {
+                IGNORE_FILES.iter().any(|name| {
+                    let (ignore, _) = Gitignore::new(ancestor.join(name));
+                    ignore.matched_path_or_any_parents(path, is_dir).is_ignore()
+                })
+            })
+    }
+
+    async fn reindex_path(&self, path: PathBuf) -> anyhow::Result<()> {
+        // Deleted, or renamed away
+        if !path.exists() {
+            return self.vector_db.delete_file(&path).await;
+        }
+
+        if !path.is_file() || path.is_symlink() {
+            return anyhow::Ok(());
+        }
+
+        let strategy = if self.index_readme && path == self.directory.join("README.md") {
+            ParsingStrategy::Readme
+        } else {
+            let strategy = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .and_then(|extension| {
+                    self.parsers
+                        .get_strategy_for_extension(extension.to_string())
+                        .ok()
+                });
+            match strategy {
+                Some(strategy) => strategy.clone(),
+                None => return anyhow::Ok(()),
+            }
+        };
+
+        log::debug!("reindexing changed file {:?}", path);
+        let permit = self.in_flight_files.clone().acquire_owned().await?;
+        self.directory_state.new_job();
+        let file_details = FileDetails {
+            path,
+            directory_state: self.directory_state.clone(),
+            _permit: Some(Arc::new(permit)),
+        };
+        queue_parse_job(
+            &self.parse_sender,
+            Arc::new((
+                file_details,
+                strategy,
+                self.existing_embeddings.clone(),
+                self.parse_options.clone(),
+            )),
+        )
+        .await?;
+
+        anyhow::Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use async_trait::async_trait;
+
+pub type Embedding = Vec<f32>;
+
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Embedding>>;
+    async fn embed_query(&self, query: String) -> anyhow::Result<Embedding>;
+}
+
+/// Provider returning a constant embedding for every text, useful for offline testing.
+#[derive(Debug, Clone, Default)]
+pub struct FakeEmbeddingProvider;
+
+impl FakeEmbeddingProvider {
+    fn embedding() -> Embedding {
+        vec![0.1, 0.2, 0.3]
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for FakeEmbeddingProvider {
+    async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Embedding>> {
+        anyhow::Ok(texts.iter().map(|_| Self::embedding()).collect())
+    }
+
+    async fn embed_query(&self, _query: String) -> anyhow::Result<Embedding> {
+        anyhow::Ok(Self::embedding())
+    }
+}
+
+/// Provider embedding each text as a normalized bag of its words, so texts sharing words are
+/// similar. Useful for offline testing where the ordering of results matters.
+#[derive(Debug, Clone, Default)]
+pub struct BagOfWordsEmbeddingProvider;
+
+impl BagOfWordsEmbeddingProvider {
+    const DIMENSIONS: usize = 256;
+
+    fn embedding(text: &str) -> Embedding {
+        let mut embedding = vec![0.0; Self::DIMENSIONS];
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            // FNV-1a, as the buckets must be stable across runs and platforms
+            let mut hash: u64 = 0xcbf29ce484222325;
+            for byte in word.to_lowercase().bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+            embedding[(hash % Self::DIMENSIONS as u64) as usize] += 1.0;
+        }
+
+        let norm = embedding
+            .iter()
+            .map(|value| value * value)
+            .sum::<f32>()
+            .sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|value| *value /= norm);
+        }
+        embedding
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for BagOfWordsEmbeddingProvider {
+    async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Embedding>> {
+        anyhow::Ok(texts.iter().map(|text| Self::embedding(text)).collect())
+    }
+
+    async fn embed_query(&self, query: String) -> anyhow::Result<Embedding> {
+        anyhow::Ok(Self::embedding(&query))
+    }
+}
+
+/// Provider counting the texts and queries it is asked to embed, for asserting on provider
+/// usage in tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct CountingEmbeddingProvider {
+    pub(crate) texts: std::sync::atomic::AtomicUsize,
+    pub(crate) queries: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl EmbeddingProvider for CountingEmbeddingProvider {
+    async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Embedding>> {
+        self.texts
+            .fetch_add(texts.len(), std::sync::atomic::Ordering::SeqCst);
+        anyhow::Ok(texts.iter().map(|_| vec![1.0, 0.0]).collect())
+    }
+
+    async fn embed_query(&self, _query: String) -> anyhow::Result<Embedding> {
+        self.queries
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        anyhow::Ok(vec![1.0, 0.0])
+    }
+}
+
+/// Provider taking a fixed delay to embed each batch of texts, keeping files in the pipeline
+/// long enough for tests to observe indexing while it is still in progress.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct SlowEmbeddingProvider {
+    pub(crate) delay: std::time::Duration,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl EmbeddingProvider for SlowEmbeddingProvider {
+    async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Embedding>> {
+        tokio::time::sleep(self.delay).await;
+        anyhow::Ok(texts.iter().map(|_| vec![1.0, 0.0]).collect())
+    }
+
+    async fn embed_query(&self, _query: String) -> anyhow::Result<Embedding> {
+        anyhow::Ok(vec![1.0, 0.0])
+    }
+}
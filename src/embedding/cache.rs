@@ -0,0 +1,148 @@
+use crate::embedding::base::Embedding;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+
+/// Stores embeddings keyed by the sha of the span they were embedded from, consulted before
+/// spans are sent to the embedding provider. Embeddings are only comparable if produced by
+/// the same model, so a cache should not be shared between models.
+#[async_trait]
+pub trait EmbeddingCache: Send + Sync {
+    async fn get(&self, sha: &[u8]) -> Option<Embedding>;
+    async fn insert(&self, sha: &[u8], embedding: &[f32]) -> anyhow::Result<()>;
+}
+
+/// Cache persisting each embedding to its own file under a directory, so embeddings survive
+/// restarts and are shared across every directory indexed, such as fresh clones of a repo.
+#[derive(Debug, Clone)]
+pub struct DiskEmbeddingCache {
+    directory: PathBuf,
+}
+
+impl DiskEmbeddingCache {
+    /// Opens the cache of embeddings for the model, such as `openai/text-embedding-ada-002`,
+    /// under its own subdirectory, so caches for different models can share a directory
+    /// without mixing their embeddings. Writes left partial by an interrupted process are
+    /// removed.
+    pub fn new(directory: &Path, model: &str) -> anyhow::Result<Self> {
+        let namespace = model
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        if namespace.chars().all(|c| c == '.') {
+            return Err(anyhow!("invalid embedding cache model: {:?}", model));
+        }
+
+        let directory = directory.join(namespace);
+        std::fs::create_dir_all(&directory)?;
+        for shard in std::fs::read_dir(&directory)? {
+            let shard = shard?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(&shard)? {
+                let file = file?.path();
+                if file
+                    .extension()
+                    .is_some_and(|extension| extension == "partial")
+                {
+                    std::fs::remove_file(&file)?;
+                }
+            }
+        }
+
+        anyhow::Ok(DiskEmbeddingCache { directory })
+    }
+
+    /// Files are sharded by the first byte of the sha, keeping directories a manageable size.
+    fn path(&self, sha: &[u8]) -> PathBuf {
+        let hex = sha
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        self.directory.join(&hex[..hex.len().min(2)]).join(hex)
+    }
+}
+
+#[async_trait]
+impl EmbeddingCache for DiskEmbeddingCache {
+    async fn get(&self, sha: &[u8]) -> Option<Embedding> {
+        let bytes = tokio::fs::read(self.path(sha)).await.ok()?;
+        if bytes.is_empty() || bytes.len() % 4 != 0 {
+            return None;
+        }
+
+        Some(
+            bytes
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect(),
+        )
+    }
+
+    async fn insert(&self, sha: &[u8], embedding: &[f32]) -> anyhow::Result<()> {
+        let path = self.path(sha);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Written aside and renamed into place, so a concurrent read never sees a partial file
+        let bytes = embedding
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, bytes).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        anyhow::Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::strategy::get_sha;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_disk_cache_persists_embeddings() {
+        let directory = tempdir().unwrap();
+        let sha = get_sha("struct CodeContextParser {}");
+
+        let cache = DiskEmbeddingCache::new(directory.path(), "openai/ada").unwrap();
+        assert_eq!(cache.get(&sha).await, None);
+        cache.insert(&sha, &vec![0.1, -0.2, 0.3]).await.unwrap();
+
+        // A new cache over the same directory, as after a restart, sees the embedding
+        let reopened = DiskEmbeddingCache::new(directory.path(), "openai/ada").unwrap();
+        assert_eq!(reopened.get(&sha).await, Some(vec![0.1, -0.2, 0.3]));
+        assert_eq!(reopened.get(&get_sha("fn main() {}")).await, None);
+
+        // Embeddings from another model aren't comparable, so aren't shared
+        let other = DiskEmbeddingCache::new(directory.path(), "onnx/minilm").unwrap();
+        assert_eq!(other.get(&sha).await, None);
+        assert!(DiskEmbeddingCache::new(directory.path(), "..").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_removes_partial_writes() {
+        let directory = tempdir().unwrap();
+        let sha = get_sha("struct CodeContextParser {}");
+        let cache = DiskEmbeddingCache::new(directory.path(), "openai/ada").unwrap();
+        cache.insert(&sha, &vec![0.1, -0.2, 0.3]).await.unwrap();
+
+        // A write interrupted before its rename leaves a partial file beside the embedding
+        let partial = cache.path(&sha).with_extension("partial");
+        std::fs::write(&partial, [0u8; 6]).unwrap();
+
+        let reopened = DiskEmbeddingCache::new(directory.path(), "openai/ada").unwrap();
+        assert!(!partial.exists());
+        assert_eq!(reopened.get(&sha).await, Some(vec![0.1, -0.2, 0.3]));
+    }
+}
@@ -0,0 +1,5 @@
+pub mod base;
+pub mod cache;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+pub mod openai;
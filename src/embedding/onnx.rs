@@ -0,0 +1,220 @@
+use crate::embedding::base::{Embedding, EmbeddingProvider};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use ort::session::builder::GraphOptimizationLevel;
+use ort::session::{Session, SessionInputValue};
+use ort::value::Tensor;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+/// The maximum number of tokens embedded per text, longer texts are truncated.
+const MAX_TOKENS: usize = 512;
+
+/// Provider embedding locally with a sentence-transformer model exported to ONNX, for fully
+/// offline operation. Token embeddings are mean pooled over the attention mask, and the
+/// result normalized.
+#[derive(Clone)]
+pub struct OnnxEmbeddingProvider {
+    /// Running the session needs exclusive access to it.
+    session: Arc<Mutex<Session>>,
+    tokenizer: Arc<Tokenizer>,
+    dimensions: usize,
+}
+
+impl OnnxEmbeddingProvider {
+    /// Loads the ONNX model, along with the `tokenizer.json` it was exported with.
+    pub fn new(model_path: &Path, tokenizer_path: &Path) -> anyhow::Result<Self> {
+        let session = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level1)?
+            .commit_from_file(model_path)?;
+
+        let mut tokenizer = Tokenizer::from_file(tokenizer_path).map_err(|err| anyhow!(err))?;
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: MAX_TOKENS,
+                ..Default::default()
+            }))
+            .map_err(|err| anyhow!(err))?;
+
+        let mut provider = OnnxEmbeddingProvider {
+            session: Arc::new(Mutex::new(session)),
+            tokenizer: Arc::new(tokenizer),
+            dimensions: 0,
+        };
+
+        // Exported models often leave the hidden size dynamic, so it is read off of a probe
+        provider.dimensions = provider
+            .embed(vec!["dimensions".to_string()])?
+            .first()
+            .map(|embedding| embedding.len())
+            .ok_or(anyhow!("model produced no embedding"))?;
+
+        anyhow::Ok(provider)
+    }
+
+    /// Loads `model.onnx` and `tokenizer.json` from the directory, the layout models are
+    /// typically exported with.
+    pub fn from_directory(directory: &Path) -> anyhow::Result<Self> {
+        OnnxEmbeddingProvider::new(
+            &directory.join("model.onnx"),
+            &directory.join("tokenizer.json"),
+        )
+    }
+
+    /// The length of the embeddings produced by the model.
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn embed(&self, texts: Vec<String>) -> anyhow::Result<Vec<Embedding>> {
+        if texts.is_empty() {
+            return anyhow::Ok(vec![]);
+        }
+
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts, true)
+            .map_err(|err| anyhow!(err))?;
+        let batch_size = encodings.len();
+        let tokens = encodings[0].len();
+
+        let shape = [batch_size, tokens];
+        let input_ids = encodings
+            .iter()
+            .flat_map(|encoding| encoding.get_ids().iter().map(|id| *id as i64))
+            .collect::<Vec<i64>>();
+        let attention_mask = encodings
+            .iter()
+            .flat_map(|encoding| {
+                encoding
+                    .get_attention_mask()
+                    .iter()
+                    .map(|mask| *mask as i64)
+            })
+            .collect::<Vec<i64>>();
+        let token_type_ids = encodings
+            .iter()
+            .flat_map(|encoding| encoding.get_type_ids().iter().map(|id| *id as i64))
+            .collect::<Vec<i64>>();
+
+        let mut session = self
+            .session
+            .lock()
+            .map_err(|_| anyhow!("onnx session poisoned"))?;
+
+        // Not every exported model takes token type ids
+        let mut inputs: Vec<(&str, SessionInputValue)> = vec![
+            ("input_ids", Tensor::from_array((shape, input_ids))?.into()),
+            (
+                "attention_mask",
+                Tensor::from_array((shape, attention_mask))?.into(),
+            ),
+        ];
+        if session
+            .inputs
+            .iter()
+            .any(|input| input.name == "token_type_ids")
+        {
+            inputs.push((
+                "token_type_ids",
+                Tensor::from_array((shape, token_type_ids))?.into(),
+            ));
+        }
+
+        let outputs = session.run(inputs)?;
+        if outputs.len() == 0 {
+            return Err(anyhow!("model produced no outputs"));
+        }
+        let (output_shape, hidden_states) = outputs[0].try_extract_tensor::<f32>()?;
+        let hidden_size = *output_shape
+            .last()
+            .ok_or(anyhow!("model produced a scalar output"))? as usize;
+
+        let mut embeddings = Vec::with_capacity(batch_size);
+        for (idx, sequence) in hidden_states.chunks(tokens * hidden_size).enumerate() {
+            let mask = encodings[idx].get_attention_mask();
+            let mut embedding = vec![0.0; hidden_size];
+            let mut count = 0.0;
+            for (token, hidden_state) in sequence.chunks(hidden_size).enumerate() {
+                if mask[token] == 0 {
+                    continue;
+                }
+                for (value, state) in embedding.iter_mut().zip(hidden_state.iter()) {
+                    *value += state;
+                }
+                count += 1.0;
+            }
+
+            let norm = embedding
+                .iter()
+                .map(|value| (value / count) * (value / count))
+                .sum::<f32>()
+                .sqrt()
+                .max(f32::EPSILON);
+            embeddings.push(
+                embedding
+                    .into_iter()
+                    .map(|value| value / count / norm)
+                    .collect(),
+            );
+        }
+
+        anyhow::Ok(embeddings)
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OnnxEmbeddingProvider {
+    async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Embedding>> {
+        // Inference is CPU bound, so is kept off of the async runtime
+        let provider = self.clone();
+        tokio::task::spawn_blocking(move || provider.embed(texts)).await?
+    }
+
+    async fn embed_query(&self, query: String) -> anyhow::Result<Embedding> {
+        self.embed_texts(vec![query])
+            .await?
+            .pop()
+            .ok_or(anyhow!("no embedding produced for query"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture_directory() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/onnx")
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a model exported to tests/fixtures/onnx, see its README"]
+    async fn test_onnx_embeddings_have_consistent_dimensions() {
+        let provider = OnnxEmbeddingProvider::from_directory(&fixture_directory()).unwrap();
+
+        let embeddings = provider
+            .embed_texts(vec![
+                "fn main() {}".to_string(),
+                "struct CodeContextParser { content: String }".to_string(),
+                "def parse(content): return content.split()".to_string(),
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(embeddings.len(), 3);
+        let dimensions = provider.dimensions();
+        assert!(dimensions > 0);
+        assert!(embeddings
+            .iter()
+            .all(|embedding| embedding.len() == dimensions));
+
+        let query = provider
+            .embed_query("fn main() {}".to_string())
+            .await
+            .unwrap();
+        assert_eq!(query.len(), dimensions);
+    }
+}
@@ -0,0 +1,19 @@
+use crate::embedding::base::{Embedding, EmbeddingProvider};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use llm_chain::traits::Embeddings;
+
+#[async_trait]
+impl EmbeddingProvider for llm_chain_openai::embeddings::Embeddings {
+    async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Embedding>> {
+        Embeddings::embed_texts(self, texts)
+            .await
+            .map_err(|err| anyhow!(err))
+    }
+
+    async fn embed_query(&self, query: String) -> anyhow::Result<Embedding> {
+        Embeddings::embed_query(self, query)
+            .await
+            .map_err(|err| anyhow!(err))
+    }
+}
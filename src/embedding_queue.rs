@@ -1,66 +1,423 @@
+use crate::embedding::base::EmbeddingProvider;
+use crate::embedding::cache::EmbeddingCache;
 use crate::parsers::strategy::FileContext;
 use anyhow::anyhow;
-use llm_chain::traits::Embeddings;
 use std::mem;
 use std::sync::Arc;
-use tokio::sync::{broadcast, mpsc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, oneshot, watch, Mutex};
 
 pub(crate) enum EmbeddingJob {
     Embed {
         file_context: Arc<Mutex<FileContext>>,
     },
     Flush,
+    /// Drains the queue, signalling once every file queued before it has been embedded and
+    /// forwarded on as a finished file.
+    Drain {
+        drained: oneshot::Sender<()>,
+    },
 }
 
 #[derive(Debug, Clone)]
 struct FileFragment {
     file_context: Arc<Mutex<FileContext>>,
     embeddable_ids: Vec<usize>,
+    /// Set once the fragment's batch has failed and been re-queued, so a second failure
+    /// drops it rather than retrying indefinitely.
+    requeued: bool,
+}
+
+/// The default budget for content held in the queue, above which it is flushed early.
+pub(crate) const DEFAULT_MAX_QUEUED_BYTES: usize = 4 * 1024 * 1024;
+
+/// The default number of spans embedded in each request to the provider.
+pub(crate) const DEFAULT_EMBEDDING_BATCH_SIZE: usize = 20;
+
+/// How many times a failed embedding request is attempted, and the delay before the first
+/// retry, which doubles for each further attempt.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_attempts: usize,
+    pub(crate) base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the given retry, counting from one, with up to a base delay of jitter
+    /// so that workers rate limited together don't all retry together.
+    fn delay(&self, retry: usize) -> Duration {
+        let backoff = self.base_delay * 2u32.saturating_pow(retry as u32 - 1);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.subsec_nanos())
+            .unwrap_or(0);
+        let jitter = self.base_delay.mul_f64(nanos as f64 / 1_000_000_000.0);
+        backoff + jitter
+    }
+}
+
+/// Rate limited requests wait this many times longer before retrying, giving the provider's
+/// limit window time to reset.
+const RATE_LIMIT_BACKOFF_FACTOR: u32 = 4;
+
+/// Whether the error looks to be the provider rate limiting requests, as providers surface
+/// this through their error messages rather than a common error type.
+fn is_rate_limited(err: &anyhow::Error) -> bool {
+    let message = format!("{:?}", err).to_lowercase();
+    message.contains("429") || message.contains("rate limit")
+}
+
+/// Embeds the spans, retrying with exponential backoff according to the policy.
+async fn embed_with_retry(
+    provider: &dyn EmbeddingProvider,
+    spans: &[String],
+    policy: &RetryPolicy,
+) -> anyhow::Result<Vec<Vec<f32>>> {
+    let mut attempt = 1;
+    loop {
+        match provider.embed_texts(spans.to_vec()).await {
+            Ok(embeddings) => return anyhow::Ok(embeddings),
+            Err(err) if attempt < policy.max_attempts => {
+                let delay = if is_rate_limited(&err) {
+                    policy.delay(attempt) * RATE_LIMIT_BACKOFF_FACTOR
+                } else {
+                    policy.delay(attempt)
+                };
+                log::warn!(
+                    "embedding attempt {} of {} failed, retrying in {:?}: {:?}",
+                    attempt,
+                    policy.max_attempts,
+                    delay,
+                    err
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Stores the embedding in the cache, skipping non-finite embeddings as they are rejected
+/// rather than indexed. Failing to cache is logged, as the span is still embedded.
+async fn cache_embedding(cache: &dyn EmbeddingCache, sha: &[u8], embedding: &[f32]) {
+    if !embedding.iter().all(|value| value.is_finite()) {
+        return;
+    }
+
+    if let Err(err) = cache.insert(sha, embedding).await {
+        log::warn!("failed to cache embedding: {:?}", err);
+    }
+}
+
+/// The state of a `CircuitBreaker`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BreakerState {
+    /// Batches are embedded, counting how many have failed in a row.
+    Closed { consecutive_failures: usize },
+    /// Batches are failed without calling the provider until the cooldown elapses.
+    Open { until: Instant },
+    /// The cooldown has elapsed and a single batch is probing the provider, which closes the
+    /// breaker if it succeeds or opens it again if not.
+    HalfOpen,
+}
+
+/// Stops batches being sent to a provider which is consistently failing, such as after its
+/// credentials are revoked, so that each batch doesn't retry against it in turn. Failures are
+/// counted per batch, once its retries are exhausted.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    failure_threshold: usize,
+    cooldown: Duration,
+    state: BreakerState,
+}
+
+/// The default number of batches failing in a row before the breaker opens.
+pub(crate) const DEFAULT_BREAKER_FAILURE_THRESHOLD: usize = 5;
+
+/// The default time the breaker stays open before probing the provider again.
+pub(crate) const DEFAULT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(DEFAULT_BREAKER_FAILURE_THRESHOLD, DEFAULT_BREAKER_COOLDOWN)
+    }
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(failure_threshold: usize, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            state: BreakerState::Closed {
+                consecutive_failures: 0,
+            },
+        }
+    }
+
+    /// Whether a batch may be sent to the provider. Once the cooldown has elapsed, the first
+    /// caller is let through as the probe, and the rest fail fast until it completes.
+    pub(crate) fn try_acquire(&mut self) -> bool {
+        match self.state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                self.state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen => false,
+        }
+    }
+
+    pub(crate) fn record_success(&mut self) {
+        self.state = BreakerState::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    pub(crate) fn record_failure(&mut self) {
+        let consecutive_failures = match self.state {
+            BreakerState::Closed {
+                consecutive_failures,
+            } => consecutive_failures + 1,
+            BreakerState::Open { .. } | BreakerState::HalfOpen => self.failure_threshold,
+        };
+
+        self.state = if consecutive_failures >= self.failure_threshold {
+            log::warn!(
+                "embedding provider failed {} batches in a row, failing batches for {:?}",
+                consecutive_failures,
+                self.cooldown
+            );
+            BreakerState::Open {
+                until: Instant::now() + self.cooldown,
+            }
+        } else {
+            BreakerState::Closed {
+                consecutive_failures,
+            }
+        };
+    }
+}
+
+/// Limits applied when sanitizing span content before it is sent to the embedding provider,
+/// as some providers reject control characters or pathological input. Only the text sent for
+/// embedding is sanitized, spans are still hashed from their original content.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SanitizeOptions {
+    /// Runs of spaces and tabs longer than this are cut short.
+    pub max_whitespace_run: usize,
+    /// Tokens longer than this, such as minified code or inline data, are truncated.
+    pub max_token_length: usize,
+}
+
+impl Default for SanitizeOptions {
+    fn default() -> Self {
+        SanitizeOptions {
+            max_whitespace_run: 16,
+            max_token_length: 256,
+        }
+    }
+}
+
+/// Moves the content of each fragment's spans out of its documents to send for embedding,
+/// rather than cloning it, as the file's source is kept for anything needing span content once
+/// embedded. Content is sanitized first if enabled.
+async fn take_embedding_inputs(
+    queue: &[FileFragment],
+    sanitize_options: &Option<SanitizeOptions>,
+) -> Vec<String> {
+    let mut spans = Vec::new();
+    for fragment in queue {
+        let mut unlocked = fragment.file_context.lock().await;
+        for idx in &fragment.embeddable_ids {
+            let content = mem::take(&mut unlocked.documents[*idx].content);
+            spans.push(match sanitize_options {
+                Some(options) => sanitize(&content, options),
+                None => content,
+            });
+        }
+    }
+    spans
+}
+
+/// Strips control characters other than newlines and tabs, collapses runs of blank lines and
+/// cuts overly long whitespace runs and tokens short.
+pub(crate) fn sanitize(content: &str, options: &SanitizeOptions) -> String {
+    let mut sanitized = String::with_capacity(content.len());
+    let mut newlines = 0;
+    let mut whitespace_run = 0;
+    let mut token_length = 0;
+    for c in content.chars() {
+        if c == '\n' {
+            newlines += 1;
+            whitespace_run = 0;
+            token_length = 0;
+            if newlines <= 2 {
+                sanitized.push(c);
+            }
+        } else if c == ' ' || c == '\t' {
+            whitespace_run += 1;
+            token_length = 0;
+            if whitespace_run <= options.max_whitespace_run {
+                sanitized.push(c);
+            }
+        } else if !c.is_control() {
+            newlines = 0;
+            whitespace_run = 0;
+            token_length += 1;
+            if token_length <= options.max_token_length {
+                sanitized.push(c);
+            }
+        }
+    }
+    sanitized
+}
+
+/// Drops spans whose embedding contains NaN or infinite values, which would otherwise poison
+/// similarity for every search. Only called once the file is complete, as dropping spans
+/// shifts the indices other fragments of the file refer to.
+fn reject_non_finite_embeddings(file_context: &mut FileContext) {
+    let mut idx = 0;
+    while idx < file_context.embeddings.len() {
+        if file_context.embeddings[idx]
+            .iter()
+            .all(|value| value.is_finite())
+        {
+            idx += 1;
+            continue;
+        }
+
+        let document = file_context.documents.remove(idx);
+        file_context.embeddings.remove(idx);
+        log::warn!(
+            "rejecting span {}..{} in {:?}, provider returned non-finite embedding values",
+            document.start_byte,
+            document.end_byte,
+            file_context.details.path
+        );
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct EmbeddingQueue {
     queue: Vec<FileFragment>,
+    queued_bytes: usize,
+    max_queued_bytes: usize,
+    batch_size: usize,
     embed_tx: async_channel::Sender<Vec<FileFragment>>,
     finished_files_tx: broadcast::Sender<Arc<Mutex<FileContext>>>,
+    pending_batches: Arc<watch::Sender<usize>>,
+    paused: Arc<watch::Sender<bool>>,
+    sanitize: Arc<watch::Sender<Option<SanitizeOptions>>>,
+    retry: Arc<watch::Sender<RetryPolicy>>,
+    cache: Arc<watch::Sender<Option<Arc<dyn EmbeddingCache>>>>,
+    breaker: Arc<std::sync::Mutex<CircuitBreaker>>,
 }
 
 impl EmbeddingQueue {
-    pub(crate) fn new(provider: Arc<llm_chain_openai::embeddings::Embeddings>) -> Self {
+    pub(crate) fn new(provider: Arc<dyn EmbeddingProvider>, max_queued_bytes: usize) -> Self {
         let (finished_files_tx, _) = broadcast::channel::<Arc<Mutex<FileContext>>>(10000);
         // Create a long lived task to embed and send off completed files
         let (embed_tx, receiver) = async_channel::unbounded::<Vec<FileFragment>>();
+        let pending_batches = Arc::new(watch::channel::<usize>(0).0);
+        let paused = Arc::new(watch::channel::<bool>(false).0);
+        let sanitize = Arc::new(watch::channel::<Option<SanitizeOptions>>(None).0);
+        let retry = Arc::new(watch::channel::<RetryPolicy>(RetryPolicy::default()).0);
+        let cache = Arc::new(watch::channel::<Option<Arc<dyn EmbeddingCache>>>(None).0);
+        let breaker = Arc::new(std::sync::Mutex::new(CircuitBreaker::default()));
         // let (embed_tx, mut receiver) = mpsc::channel::<Vec<FileFragment>>(10000);
         for _ in 0..num_cpus::get() {
             tokio::spawn({
                 let finished_files_tx = finished_files_tx.clone();
                 let receiver = receiver.clone();
                 let provider = provider.clone();
+                let pending_batches = pending_batches.clone();
+                let mut paused = paused.subscribe();
+                let sanitize = sanitize.subscribe();
+                let retry = retry.subscribe();
+                let cache = cache.subscribe();
+                let breaker = breaker.clone();
+                // Failed batches are re-queued, without keeping the channel open once the
+                // queue itself is dropped
+                let requeue_tx = embed_tx.downgrade();
                 async move {
                     // get spans and embed them
-                    while let Some(queue) = receiver.recv().await.ok() {
-                        let mut spans = Vec::new();
-                        for fragment in &queue {
+                    while let Some(batch) = receiver.recv().await.ok() {
+                        // Hold the batch while paused, the rest stay buffered in the channel
+                        let _ = paused.wait_for(|paused| !*paused).await;
+
+                        // Files from cancelled directories are dropped rather than embedded
+                        let mut queue = Vec::with_capacity(batch.len());
+                        for fragment in batch {
                             let unlocked = fragment.file_context.lock().await;
-                            for idx in &fragment.embeddable_ids {
-                                spans.push(unlocked.documents[*idx].content.clone());
+                            if !unlocked.details.directory_state.is_cancelled() {
+                                drop(unlocked);
+                                queue.push(fragment);
                             }
                         }
+                        if queue.is_empty() {
+                            pending_batches.send_modify(|count| *count -= 1);
+                            continue;
+                        }
+
+                        // While the provider is failing consistently batches are dropped as
+                        // failed, as with batches failing after re-queueing, rather than
+                        // hammering it
+                        if !breaker.lock().unwrap().try_acquire() {
+                            log::warn!(
+                                "embedding provider circuit open, dropping batch of {} files",
+                                queue.len()
+                            );
+                            drop(queue);
+                            pending_batches.send_modify(|count| *count -= 1);
+                            continue;
+                        }
+
+                        let sanitize_options = sanitize.borrow().clone();
+                        let spans = take_embedding_inputs(&queue, &sanitize_options).await;
 
-                        let embeddings = provider.embed_texts(spans).await;
+                        let retry_policy = retry.borrow().clone();
+                        let embeddings =
+                            embed_with_retry(provider.as_ref(), &spans, &retry_policy).await;
+                        match &embeddings {
+                            Ok(_) => breaker.lock().unwrap().record_success(),
+                            Err(_) => breaker.lock().unwrap().record_failure(),
+                        }
 
                         match embeddings {
-                            Ok(embeddings) => {
+                            Ok(mut embeddings) => {
                                 // Update File Context with Completed Embeddings
+                                let cache = cache.borrow().clone();
                                 let mut i = 0;
                                 for fragment in &queue {
                                     let mut unlocked = fragment.file_context.lock().await;
                                     for idx in &fragment.embeddable_ids {
-                                        unlocked.embeddings[*idx] = embeddings[i].clone();
+                                        let embedding = mem::take(&mut embeddings[i]);
+                                        if let Some(cache) = &cache {
+                                            cache_embedding(
+                                                cache.as_ref(),
+                                                &unlocked.documents[*idx].sha,
+                                                &embedding,
+                                            )
+                                            .await;
+                                        }
+                                        unlocked.embeddings[*idx] = embedding;
+                                        i += 1;
                                     }
-                                    i += 1;
 
                                     let complete = unlocked.complete();
+                                    if complete {
+                                        reject_non_finite_embeddings(&mut unlocked);
+                                    }
                                     drop(unlocked);
                                     if complete {
                                         let _ =
@@ -68,10 +425,52 @@ impl EmbeddingQueue {
                                     }
                                 }
                             }
+                            Err(err) if !queue.iter().any(|fragment| fragment.requeued) => {
+                                log::error!(
+                                    "embedding failed after {} attempts, re-queueing batch: {:?}",
+                                    retry_policy.max_attempts,
+                                    anyhow!(err)
+                                );
+
+                                // Content was moved out for embedding, so is put back first.
+                                // Sanitized content is unchanged by sanitizing again.
+                                let mut spans = spans.into_iter();
+                                for fragment in queue.iter_mut() {
+                                    fragment.requeued = true;
+                                    let mut unlocked = fragment.file_context.lock().await;
+                                    for idx in &fragment.embeddable_ids {
+                                        unlocked.documents[*idx].content =
+                                            spans.next().unwrap_or_default();
+                                    }
+                                }
+
+                                // The batch stays pending until it is embedded or dropped
+                                if let Some(requeue_tx) = requeue_tx.upgrade() {
+                                    if requeue_tx.send(queue).await.is_ok() {
+                                        continue;
+                                    }
+                                }
+                            }
                             Err(err) => {
-                                log::error!("{:?}", anyhow!(err));
+                                // Dropping the batch leaves its files incomplete, so they are
+                                // dropped as failed once their other fragments are released,
+                                // rather than holding the directory in indexing forever
+                                let mut paths = Vec::new();
+                                for fragment in &queue {
+                                    paths.push(
+                                        fragment.file_context.lock().await.details.path.clone(),
+                                    );
+                                }
+                                log::error!(
+                                    "embedding failed again after re-queueing, dropping {:?}: {:?}",
+                                    paths,
+                                    anyhow!(err)
+                                );
+                                drop(queue);
                             }
                         }
+
+                        pending_batches.send_modify(|count| *count -= 1);
                     }
                 }
             });
@@ -79,15 +478,78 @@ impl EmbeddingQueue {
 
         EmbeddingQueue {
             queue: Vec::new(),
+            queued_bytes: 0,
+            max_queued_bytes,
+            batch_size: DEFAULT_EMBEDDING_BATCH_SIZE,
             embed_tx,
             finished_files_tx,
+            pending_batches,
+            paused,
+            sanitize,
+            retry,
+            cache,
+            breaker,
         }
     }
 
+    /// Sets the number of spans embedded in each request to the provider.
+    pub(crate) fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Sets how many times a failed embedding request is attempted, and the delay before the
+    /// first retry. Batches still failing after the final attempt are re-queued once, and
+    /// dropped if they fail again, so their files are dropped rather than never completing.
+    pub(crate) fn with_retry(self, max_attempts: usize, base_delay: Duration) -> Self {
+        self.retry.send_replace(RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+        });
+        self
+    }
+
+    /// Sets how many batches must fail in a row, once retried, before batches are failed
+    /// without calling the provider, and for how long before a batch probes it again.
+    pub(crate) fn with_circuit_breaker(self, failure_threshold: usize, cooldown: Duration) -> Self {
+        *self.breaker.lock().unwrap() = CircuitBreaker::new(failure_threshold, cooldown);
+        self
+    }
+
+    /// Sets the cache consulted for each span's embedding before it is sent to the provider,
+    /// and which embeddings from the provider are stored in.
+    pub(crate) fn with_cache(self, cache: Option<Arc<dyn EmbeddingCache>>) -> Self {
+        self.cache.send_replace(cache);
+        self
+    }
+
+    /// Sets how span content is sanitized before embedding, or disables sanitizing if `None`.
+    pub(crate) fn with_sanitize(self, options: Option<SanitizeOptions>) -> Self {
+        self.sanitize.send_replace(options);
+        self
+    }
+
+    /// Returns the handle pausing and resuming embedding, shared by all clones of the queue.
+    pub(crate) fn paused(&self) -> Arc<watch::Sender<bool>> {
+        self.paused.clone()
+    }
+
     pub(crate) async fn flush_queue(&mut self) {
         log::debug!("flushing queue");
         let queue = mem::take(&mut self.queue);
-        let _ = self.embed_tx.send(queue).await;
+        self.queued_bytes = 0;
+        self.pending_batches.send_modify(|count| *count += 1);
+        if self.embed_tx.send(queue).await.is_err() {
+            self.pending_batches.send_modify(|count| *count -= 1);
+        }
+    }
+
+    /// Flushes the queue, and waits until all outstanding batches have been embedded and
+    /// forwarded on as finished files.
+    pub(crate) async fn drain(&mut self) {
+        self.flush_queue().await;
+        let mut pending_batches = self.pending_batches.subscribe();
+        let _ = pending_batches.wait_for(|count| *count == 0).await;
     }
 
     pub(crate) async fn finished_files_rx(
@@ -104,22 +566,58 @@ impl EmbeddingQueue {
         let mut size = self.queue_size();
         match job {
             EmbeddingJob::Embed { file_context } => {
+                if file_context
+                    .lock()
+                    .await
+                    .details
+                    .directory_state
+                    .is_cancelled()
+                {
+                    return;
+                }
+
                 log::debug!(
                     "queueing embedding job: {:?}",
                     file_context.lock().await.details.path
                 );
-                let outstanding_ids = file_context.lock().await.document_ids();
+                // Spans with a cached embedding skip the provider entirely
+                let cache = self.cache.borrow().clone();
+                let outstanding = {
+                    let mut unlocked = file_context.lock().await;
+                    let mut outstanding = Vec::new();
+                    for idx in unlocked.document_ids() {
+                        if let Some(cache) = &cache {
+                            if let Some(embedding) = cache.get(&unlocked.documents[idx].sha).await {
+                                unlocked.embeddings[idx] = embedding;
+                                continue;
+                            }
+                        }
+                        outstanding.push((idx, unlocked.documents[idx].content.len()));
+                    }
+                    outstanding
+                };
+                // Files with every embedding reused still need writing, as spans may have been
+                // removed since they were last indexed
+                if outstanding.is_empty() {
+                    let _ = self.finished_files_tx.send(file_context);
+                    return;
+                }
+
                 let mut embeddable_ids = Vec::new();
 
-                for idx in outstanding_ids {
+                for (idx, content_bytes) in outstanding {
                     size += 1;
+                    self.queued_bytes += content_bytes;
                     embeddable_ids.push(idx);
 
-                    if size == 20 {
+                    // Flush on either the batch size, or if the queued content exceeds the
+                    // memory budget
+                    if size == self.batch_size || self.queued_bytes >= self.max_queued_bytes {
                         let fragment_ids = mem::take(&mut embeddable_ids);
                         self.queue.push(FileFragment {
                             file_context: file_context.clone(),
                             embeddable_ids: fragment_ids,
+                            requeued: false,
                         });
                         self.flush_queue().await;
                         size = 0;
@@ -130,12 +628,772 @@ impl EmbeddingQueue {
                     self.queue.push(FileFragment {
                         file_context: file_context.clone(),
                         embeddable_ids,
+                        requeued: false,
                     });
                 }
             }
             EmbeddingJob::Flush => {
                 self.flush_queue().await;
             }
+            EmbeddingJob::Drain { drained } => {
+                self.drain().await;
+                let _ = drained.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedding::base::{CountingEmbeddingProvider, FakeEmbeddingProvider};
+    use crate::embedding::cache::DiskEmbeddingCache;
+    use crate::parsers::strategy::{get_sha, ContextDocument, LinePosition};
+    use crate::semantic_index::{DirectoryState, FileDetails, IndexingStatus};
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn test_drain_completes_outstanding_batches() {
+        let mut queue =
+            EmbeddingQueue::new(Arc::new(FakeEmbeddingProvider), DEFAULT_MAX_QUEUED_BYTES);
+        let mut finished_files_rx = queue.finished_files_rx().await;
+
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        for i in 0..5 {
+            directory_state.new_job();
+            let file_context = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(format!("/tmp/foo{i}")),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: vec![ContextDocument {
+                    start_byte: 0,
+                    end_byte: 10,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![i],
+                    content: format!("this is test document {i}"),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                }],
+                embeddings: vec![vec![]],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+        }
+
+        queue.drain().await;
+
+        let mut finished = 0;
+        while let Ok(file_context) = finished_files_rx.try_recv() {
+            assert!(file_context.lock().await.complete());
+            finished += 1;
+        }
+        assert_eq!(finished, 5);
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_memory_watermark() {
+        let mut queue = EmbeddingQueue::new(Arc::new(FakeEmbeddingProvider), 1000);
+        let mut finished_files_rx = queue.finished_files_rx().await;
+
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        for i in 0..2 {
+            directory_state.new_job();
+            let file_context = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(format!("/tmp/large{i}")),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: vec![ContextDocument {
+                    start_byte: 0,
+                    end_byte: 600,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![i],
+                    content: "a".repeat(600),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                }],
+                embeddings: vec![vec![]],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+        }
+
+        // Neither the batch size or idle flush have been hit, so both files are only embedded
+        // if the memory watermark triggered a flush
+        for _ in 0..2 {
+            let finished =
+                tokio::time::timeout(std::time::Duration::from_secs(5), finished_files_rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert!(finished.lock().await.complete());
+        }
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let options = SanitizeOptions {
+            max_whitespace_run: 4,
+            max_token_length: 8,
+        };
+
+        assert_eq!(
+            sanitize("fn foo()\0 {\u{1b}[31m\r\n\n\n\n\tbar();\n}", &options),
+            "fn foo() {[31m\n\n\tbar();\n}"
+        );
+        assert_eq!(sanitize("a          b", &options), "a    b");
+        assert_eq!(sanitize(&"x".repeat(100), &options), "x".repeat(8));
+    }
+
+    /// Records the texts it is asked to embed.
+    #[derive(Default)]
+    struct RecordingEmbeddingProvider {
+        texts: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EmbeddingProvider for RecordingEmbeddingProvider {
+        async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+            let embeddings = texts.iter().map(|_| vec![1.0, 0.0]).collect();
+            self.texts.lock().unwrap().extend(texts);
+            anyhow::Ok(embeddings)
+        }
+
+        async fn embed_query(&self, _query: String) -> anyhow::Result<Vec<f32>> {
+            anyhow::Ok(vec![1.0, 0.0])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_provider_receives_sanitized_content() {
+        let provider = Arc::new(RecordingEmbeddingProvider::default());
+        let mut queue = EmbeddingQueue::new(provider.clone(), DEFAULT_MAX_QUEUED_BYTES)
+            .with_sanitize(Some(SanitizeOptions::default()));
+        let mut finished_files_rx = queue.finished_files_rx().await;
+
+        let content = "fn foo() {\0\u{7}}".to_string();
+        let sha = get_sha(&content);
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+        let file_context = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            documents: vec![ContextDocument {
+                start_byte: 0,
+                end_byte: 10,
+                start_position: LinePosition::default(),
+                end_position: LinePosition::default(),
+                sha: sha.clone(),
+                content,
+                symbol_kind: "function_item".to_string(),
+                anchor: None,
+                is_test: false,
+            }],
+            embeddings: vec![vec![]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+        queue.drain().await;
+
+        assert_eq!(
+            *provider.texts.lock().unwrap(),
+            vec!["fn foo() {}".to_string()]
+        );
+
+        // The span is still stored under the sha of its original content
+        let finished = finished_files_rx.try_recv().unwrap();
+        assert_eq!(finished.lock().await.documents[0].sha, sha);
+    }
+
+    #[tokio::test]
+    async fn test_content_unsanitized_by_default() {
+        let provider = Arc::new(RecordingEmbeddingProvider::default());
+        let mut queue = EmbeddingQueue::new(provider.clone(), DEFAULT_MAX_QUEUED_BYTES);
+
+        let content = "fn foo() {\0\u{7}}".to_string();
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+        let file_context = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            documents: vec![ContextDocument {
+                start_byte: 0,
+                end_byte: 12,
+                start_position: LinePosition::default(),
+                end_position: LinePosition::default(),
+                sha: get_sha(&content),
+                content: content.clone(),
+                symbol_kind: "function_item".to_string(),
+                anchor: None,
+                is_test: false,
+            }],
+            embeddings: vec![vec![]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+        queue.drain().await;
+
+        assert_eq!(*provider.texts.lock().unwrap(), vec![content]);
+    }
+
+    #[tokio::test]
+    async fn test_cached_embeddings_skip_provider() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache: Arc<dyn EmbeddingCache> =
+            Arc::new(DiskEmbeddingCache::new(cache_dir.path(), "fake").unwrap());
+
+        let file_context = |directory_state: &Arc<DirectoryState>| {
+            let content = "fn foo() {}".to_string();
+            directory_state.new_job();
+            Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from("/tmp/foo.rs"),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: vec![ContextDocument {
+                    start_byte: 0,
+                    end_byte: 11,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: get_sha(&content),
+                    content,
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                }],
+                embeddings: vec![vec![]],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }))
+        };
+
+        // Each queue stands in for a separate run, indexing a separate clone of the file
+        for run in 0..2 {
+            let provider = Arc::new(CountingEmbeddingProvider::default());
+            let mut queue = EmbeddingQueue::new(provider.clone(), DEFAULT_MAX_QUEUED_BYTES)
+                .with_cache(Some(cache.clone()));
+            let mut finished_files_rx = queue.finished_files_rx().await;
+
+            let directory_state = Arc::new(DirectoryState::new(format!("id{run}")));
+            queue
+                .queue_job(EmbeddingJob::Embed {
+                    file_context: file_context(&directory_state),
+                })
+                .await;
+            queue.drain().await;
+
+            let finished = finished_files_rx.try_recv().unwrap();
+            assert_eq!(finished.lock().await.embeddings, vec![vec![1.0, 0.0]]);
+            let embedded = provider.texts.load(Ordering::SeqCst);
+            assert_eq!(embedded, if run == 0 { 1 } else { 0 });
+        }
+    }
+
+    /// Returns a NaN embedding for any text mentioning "poison".
+    struct PoisonedEmbeddingProvider;
+
+    #[async_trait::async_trait]
+    impl EmbeddingProvider for PoisonedEmbeddingProvider {
+        async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+            anyhow::Ok(
+                texts
+                    .iter()
+                    .map(|text| {
+                        if text.contains("poison") {
+                            vec![f32::NAN, 0.0]
+                        } else {
+                            vec![1.0, 0.0]
+                        }
+                    })
+                    .collect(),
+            )
+        }
+
+        async fn embed_query(&self, _query: String) -> anyhow::Result<Vec<f32>> {
+            anyhow::Ok(vec![1.0, 0.0])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_finite_embeddings_rejected() {
+        let mut queue = EmbeddingQueue::new(
+            Arc::new(PoisonedEmbeddingProvider),
+            DEFAULT_MAX_QUEUED_BYTES,
+        );
+        let mut finished_files_rx = queue.finished_files_rx().await;
+
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+        let file_context = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            documents: ["fn parse() {}", "fn poison() {}", "fn split() {}"]
+                .iter()
+                .enumerate()
+                .map(|(i, content)| ContextDocument {
+                    start_byte: i * 20,
+                    end_byte: i * 20 + content.len(),
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![i as u8],
+                    content: content.to_string(),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                })
+                .collect(),
+            embeddings: vec![vec![]; 3],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+        queue.drain().await;
+
+        let finished = finished_files_rx.try_recv().unwrap();
+        let finished = finished.lock().await;
+        let start_bytes = finished
+            .documents
+            .iter()
+            .map(|document| document.start_byte)
+            .collect::<Vec<usize>>();
+        assert_eq!(start_bytes, vec![0, 40]);
+        assert_eq!(finished.embeddings, vec![vec![1.0, 0.0]; 2]);
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        let provider = Arc::new(CountingEmbeddingProvider::default());
+        let mut queue = EmbeddingQueue::new(provider.clone(), DEFAULT_MAX_QUEUED_BYTES);
+        let mut finished_files_rx = queue.finished_files_rx().await;
+
+        let paused = queue.paused();
+        paused.send_replace(true);
+
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        for i in 0..3 {
+            directory_state.new_job();
+            let file_context = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(format!("/tmp/foo{i}")),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: vec![ContextDocument {
+                    start_byte: 0,
+                    end_byte: 10,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: vec![i],
+                    content: format!("this is test document {i}"),
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                }],
+                embeddings: vec![vec![]],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+            queue.flush_queue().await;
+        }
+
+        // Nothing is embedded while paused
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(provider.texts.load(Ordering::SeqCst), 0);
+        assert!(finished_files_rx.try_recv().is_err());
+
+        paused.send_replace(false);
+        queue.drain().await;
+
+        assert_eq!(provider.texts.load(Ordering::SeqCst), 3);
+        let mut finished = 0;
+        while let Ok(file_context) = finished_files_rx.try_recv() {
+            assert!(file_context.lock().await.complete());
+            finished += 1;
+        }
+        assert_eq!(finished, 3);
+    }
+
+    /// Records the size of each batch it is asked to embed.
+    #[derive(Default)]
+    struct BatchSizeEmbeddingProvider {
+        batch_sizes: std::sync::Mutex<Vec<usize>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EmbeddingProvider for BatchSizeEmbeddingProvider {
+        async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+            self.batch_sizes.lock().unwrap().push(texts.len());
+            anyhow::Ok(texts.iter().map(|_| vec![1.0, 0.0]).collect())
+        }
+
+        async fn embed_query(&self, _query: String) -> anyhow::Result<Vec<f32>> {
+            anyhow::Ok(vec![1.0, 0.0])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_size() {
+        let provider = Arc::new(BatchSizeEmbeddingProvider::default());
+        let mut queue =
+            EmbeddingQueue::new(provider.clone(), DEFAULT_MAX_QUEUED_BYTES).with_batch_size(8);
+
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+        let documents = (0..25)
+            .map(|idx| {
+                let content = format!("fn foo{idx}() {{}}");
+                ContextDocument {
+                    start_byte: 0,
+                    end_byte: content.len(),
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: get_sha(&content),
+                    content,
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                }
+            })
+            .collect::<Vec<ContextDocument>>();
+        let file_context = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            embeddings: vec![vec![]; documents.len()],
+            documents,
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+        queue.drain().await;
+
+        // Three full batches are sent as they fill, and the remainder on the final flush
+        let mut batch_sizes = provider.batch_sizes.lock().unwrap().clone();
+        batch_sizes.sort();
+        assert_eq!(batch_sizes, vec![1, 8, 8, 8]);
+    }
+
+    /// Fails the given number of requests, before embedding as normal.
+    #[derive(Default)]
+    struct FlakyEmbeddingProvider {
+        failures: std::sync::atomic::AtomicUsize,
+        calls: std::sync::atomic::AtomicUsize,
+        last_texts: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EmbeddingProvider for FlakyEmbeddingProvider {
+        async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            *self.last_texts.lock().unwrap() = texts.clone();
+            let failures = self.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::SeqCst);
+                return Err(anyhow!("429 Too Many Requests"));
+            }
+            anyhow::Ok(texts.iter().map(|_| vec![1.0, 0.0]).collect())
+        }
+
+        async fn embed_query(&self, _query: String) -> anyhow::Result<Vec<f32>> {
+            anyhow::Ok(vec![1.0, 0.0])
+        }
+    }
+
+    async fn embed_with_flaky_provider(failures: usize, max_attempts: usize) -> usize {
+        let provider = Arc::new(FlakyEmbeddingProvider::default());
+        provider.failures.store(failures, Ordering::SeqCst);
+        let mut queue = EmbeddingQueue::new(provider.clone(), DEFAULT_MAX_QUEUED_BYTES)
+            .with_retry(max_attempts, Duration::from_millis(1));
+        let mut finished_files_rx = queue.finished_files_rx().await;
+
+        let content = "fn foo() {}".to_string();
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+        let file_context = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            documents: vec![ContextDocument {
+                start_byte: 0,
+                end_byte: 11,
+                start_position: LinePosition::default(),
+                end_position: LinePosition::default(),
+                sha: get_sha(&content),
+                content,
+                symbol_kind: "function_item".to_string(),
+                anchor: None,
+                is_test: false,
+            }],
+            embeddings: vec![vec![]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+        queue.drain().await;
+
+        let finished = finished_files_rx.try_recv().unwrap();
+        assert_eq!(finished.lock().await.embeddings, vec![vec![1.0, 0.0]]);
+        // Retried and re-queued batches are still sent with their content
+        assert_eq!(
+            *provider.last_texts.lock().unwrap(),
+            vec!["fn foo() {}".to_string()]
+        );
+
+        provider.calls.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn test_embedding_retried_with_backoff() {
+        assert_eq!(embed_with_flaky_provider(2, 3).await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_requeued() {
+        // Both attempts fail, so the batch is re-queued and succeeds on its third request
+        assert_eq!(embed_with_flaky_provider(2, 2).await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_dropped() {
+        let provider = Arc::new(FlakyEmbeddingProvider::default());
+        provider.failures.store(usize::MAX, Ordering::SeqCst);
+        let mut queue = EmbeddingQueue::new(provider.clone(), DEFAULT_MAX_QUEUED_BYTES)
+            .with_retry(2, Duration::from_millis(1));
+        let mut finished_files_rx = queue.finished_files_rx().await;
+
+        let content = "fn foo() {}".to_string();
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+        let file_context = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/foo.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            documents: vec![ContextDocument {
+                start_byte: 0,
+                end_byte: 11,
+                start_position: LinePosition::default(),
+                end_position: LinePosition::default(),
+                sha: get_sha(&content),
+                content,
+                symbol_kind: "function_item".to_string(),
+                anchor: None,
+                is_test: false,
+            }],
+            embeddings: vec![vec![]],
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+        queue.drain().await;
+
+        // Attempted twice, and twice more once re-queued, before the file is dropped
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 4);
+        assert!(finished_files_rx.try_recv().is_err());
+        assert!(matches!(directory_state.status(), IndexingStatus::Indexed));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut breaker = CircuitBreaker::new(2, Duration::from_millis(20));
+
+        breaker.record_failure();
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+        assert!(matches!(breaker.state, BreakerState::Open { .. }));
+        assert!(!breaker.try_acquire());
+
+        // Once cooled down a single probe is let through, which re-opens the breaker on failure
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.try_acquire());
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        assert!(!breaker.try_acquire());
+        breaker.record_failure();
+        assert!(matches!(breaker.state, BreakerState::Open { .. }));
+
+        // And closes it on success
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.try_acquire());
+        breaker.record_success();
+        assert_eq!(
+            breaker.state,
+            BreakerState::Closed {
+                consecutive_failures: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_fails_fast() {
+        let provider = Arc::new(FlakyEmbeddingProvider::default());
+        provider.failures.store(usize::MAX, Ordering::SeqCst);
+        let mut queue = EmbeddingQueue::new(provider.clone(), DEFAULT_MAX_QUEUED_BYTES)
+            .with_retry(1, Duration::from_millis(1))
+            .with_circuit_breaker(1, Duration::from_secs(60));
+
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        for i in 0..3 {
+            let content = format!("fn foo{i}() {{}}");
+            directory_state.new_job();
+            let file_context = Arc::new(Mutex::new(FileContext {
+                details: FileDetails {
+                    path: PathBuf::from(format!("/tmp/foo{i}.rs")),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                },
+                documents: vec![ContextDocument {
+                    start_byte: 0,
+                    end_byte: content.len(),
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::default(),
+                    sha: get_sha(&content),
+                    content,
+                    symbol_kind: "function_item".to_string(),
+                    anchor: None,
+                    is_test: false,
+                }],
+                embeddings: vec![vec![]],
+                strategy_version: None,
+                template_version: None,
+                source: Arc::from(""),
+            }));
+            queue.queue_job(EmbeddingJob::Embed { file_context }).await;
+            queue.drain().await;
+        }
+
+        // Only the first batch reaches the provider, its re-queue and the later files are
+        // failed while the breaker is open, and the directory still completes
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            queue.breaker.lock().unwrap().state,
+            BreakerState::Open { .. }
+        ));
+        assert!(matches!(directory_state.status(), IndexingStatus::Indexed));
+    }
+    /// Counts the bytes allocated by each thread, for asserting on the allocations made while
+    /// preparing spans for embedding.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[tokio::test]
+    async fn test_embedding_inputs_move_span_content() {
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+
+        // A large file, with a thousand spans of a kilobyte each
+        let documents = (0..1000)
+            .map(|i| ContextDocument {
+                start_byte: i * 1024,
+                end_byte: (i + 1) * 1024,
+                start_position: LinePosition::default(),
+                end_position: LinePosition::default(),
+                content: "a".repeat(1024),
+                sha: get_sha(&i.to_string()),
+                symbol_kind: "function_item".to_string(),
+                anchor: None,
+                is_test: false,
+            })
+            .collect::<Vec<_>>();
+        let content_bytes = documents
+            .iter()
+            .map(|document| document.content.len())
+            .sum::<usize>();
+        let file_context = Arc::new(Mutex::new(FileContext {
+            details: FileDetails {
+                path: PathBuf::from("/tmp/large.rs"),
+                directory_state: directory_state.clone(),
+                _permit: None,
+            },
+            embeddings: vec![vec![]; documents.len()],
+            documents,
+            strategy_version: None,
+            template_version: None,
+            source: Arc::from(""),
+        }));
+        let queue = vec![FileFragment {
+            file_context: file_context.clone(),
+            embeddable_ids: (0..1000).collect(),
+            requeued: false,
+        }];
+
+        // Only the list of spans is allocated, the content itself is moved rather than copied
+        let before = ALLOCATED.with(|allocated| allocated.get());
+        let spans = take_embedding_inputs(&queue, &None).await;
+        let allocated = ALLOCATED.with(|allocated| allocated.get()) - before;
+        assert_eq!(spans.len(), 1000);
+        assert!(
+            allocated < content_bytes / 10,
+            "allocated {allocated} bytes for {content_bytes} bytes of content"
+        );
+
+        // Sanitizing necessarily copies the content it keeps
+        let queue = vec![FileFragment {
+            file_context: file_context.clone(),
+            embeddable_ids: (0..1000).collect(),
+            requeued: false,
+        }];
+        for (idx, span) in spans.into_iter().enumerate() {
+            file_context.lock().await.documents[idx].content = span;
         }
+        let before = ALLOCATED.with(|allocated| allocated.get());
+        let spans = take_embedding_inputs(&queue, &Some(SanitizeOptions::default())).await;
+        let allocated = ALLOCATED.with(|allocated| allocated.get()) - before;
+        assert_eq!(spans.len(), 1000);
+        assert!(allocated >= content_bytes);
     }
 }
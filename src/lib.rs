@@ -1,5 +1,11 @@
 mod db;
-// pub mod embedding;
+mod directory_watcher;
+pub mod embedding;
 mod embedding_queue;
-mod parsers;
+mod migrations;
+pub mod parsers;
+mod quantization;
+mod query_cache;
+mod rerank;
+pub mod runtime;
 pub mod semantic_index;
@@ -0,0 +1,165 @@
+use anyhow::anyhow;
+use surrealdb::engine::local::Db;
+use surrealdb::Surreal;
+
+/// Ordered schema migrations, the schema version of a database is the number of migrations
+/// which have been applied to it. New migrations must only ever be appended.
+const MIGRATIONS: &[&str] = &[
+    // v1: initial schema
+    "
+    DEFINE TABLE directory SCHEMAFULL;
+    DEFINE FIELD path ON TABLE directory TYPE string;
+
+    DEFINE TABLE file SCHEMAFULL;
+    DEFINE FIELD path ON TABLE file TYPE string;
+
+    DEFINE TABLE span SCHEMAFULL;
+    DEFINE FIELD start_byte ON TABLE span TYPE int;
+    DEFINE FIELD end_byte ON TABLE span TYPE int;
+    DEFINE FIELD sha ON TABLE span TYPE array<int>;
+    DEFINE FIELD sha.* ON TABLE span TYPE int;
+    DEFINE FIELD embedding ON TABLE span TYPE array<float>;
+    DEFINE FIELD embedding.* ON TABLE span TYPE float;
+    ",
+    // v2: quantized embedding storage
+    "
+    DEFINE FIELD quantized ON TABLE span TYPE option<array<int>>;
+    DEFINE FIELD quantized.* ON TABLE span TYPE int;
+    DEFINE FIELD scale ON TABLE span TYPE option<float>;
+    DEFINE FIELD offset ON TABLE span TYPE option<float>;
+    ",
+    // v3: symbol kind of the captured node
+    "
+    DEFINE FIELD symbol_kind ON TABLE span TYPE option<string>;
+    ",
+    // v4: descriptions attached to spans
+    "
+    DEFINE FIELD describes ON TABLE span TYPE option<record<span>>;
+    ",
+    // v5: exact path bytes, preserving non-UTF8 paths
+    "
+    DEFINE FIELD path_bytes ON TABLE file TYPE option<array<int>>;
+    DEFINE FIELD path_bytes.* ON TABLE file TYPE int;
+    ",
+    // v6: line anchors for relocating shifted spans
+    "
+    DEFINE FIELD anchor ON TABLE span TYPE option<array<int>>;
+    DEFINE FIELD anchor.* ON TABLE span TYPE int;
+    ",
+    // v7: test spans
+    "
+    DEFINE FIELD is_test ON TABLE span TYPE option<bool>;
+    ",
+    // v8: version of the parsing strategy spans were parsed with
+    "
+    DEFINE FIELD strategy_version ON TABLE span TYPE option<array<int>>;
+    DEFINE FIELD strategy_version.* ON TABLE span TYPE int;
+    ",
+    // v9: content addressed blobs holding span content
+    "
+    DEFINE TABLE blob SCHEMAFULL;
+    DEFINE FIELD content ON TABLE blob TYPE string;
+    DEFINE FIELD blob ON TABLE span TYPE option<record<blob>>;
+    ",
+    // v10: version of the templates spans were wrapped in
+    "
+    DEFINE FIELD template_version ON TABLE span TYPE option<int>;
+    DEFINE FIELD template_version ON TABLE directory TYPE option<int>;
+    ",
+    // v11: line and column positions of spans
+    "
+    DEFINE FIELD start_line ON TABLE span TYPE option<int>;
+    DEFINE FIELD start_col ON TABLE span TYPE option<int>;
+    DEFINE FIELD end_line ON TABLE span TYPE option<int>;
+    DEFINE FIELD end_col ON TABLE span TYPE option<int>;
+    ",
+];
+
+pub(crate) const CURRENT_SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+pub(crate) async fn schema_version(db: &Surreal<Db>) -> anyhow::Result<usize> {
+    let mut response = db.query("SELECT VALUE version FROM meta:schema").await?;
+    let version: Option<usize> = response.take(0)?;
+    anyhow::Ok(version.unwrap_or(0))
+}
+
+/// Applies any migrations which have not yet been run against the database, recording the
+/// schema version in the `meta` table after each one. Databases migrated by a newer version
+/// are refused rather than opened with a schema this version doesn't know.
+pub(crate) async fn run_migrations(db: &Surreal<Db>) -> anyhow::Result<()> {
+    db.query(
+        "
+        DEFINE TABLE meta SCHEMAFULL;
+        DEFINE FIELD version ON TABLE meta TYPE int;
+        ",
+    )
+    .await?
+    .check()?;
+
+    let version = schema_version(db).await?;
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(anyhow!(
+            "database schema version {} is newer than the supported version {}",
+            version,
+            CURRENT_SCHEMA_VERSION
+        ));
+    }
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let target = idx + 1;
+        log::debug!("migrating database schema to version {}", target);
+        db.query(*migration).await?.check()?;
+        db.query("UPDATE meta:schema SET version = $version")
+            .bind(("version", target))
+            .await?
+            .check()?;
+    }
+
+    anyhow::Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use surrealdb::engine::local::RocksDb;
+    use tempfile::tempdir;
+
+    #[derive(Debug, Deserialize)]
+    struct Directory {
+        path: String,
+    }
+
+    #[tokio::test]
+    async fn test_migrate_v0_database() {
+        let tmp_dir = tempdir().unwrap();
+        let db = Surreal::new::<RocksDb>(tmp_dir.path().join("temp.db"))
+            .await
+            .unwrap();
+        db.use_ns("auden").use_db("auden").await.unwrap();
+
+        // A v0 database has the original tables, but no meta table
+        db.query(
+            "
+            DEFINE TABLE directory SCHEMAFULL;
+            DEFINE FIELD path ON TABLE directory TYPE string;
+            CREATE directory:repo SET path = '/tmp/repo';
+            ",
+        )
+        .await
+        .unwrap()
+        .check()
+        .unwrap();
+        assert_eq!(schema_version(&db).await.unwrap(), 0);
+
+        run_migrations(&db).await.unwrap();
+        assert_eq!(schema_version(&db).await.unwrap(), CURRENT_SCHEMA_VERSION);
+
+        let directories: Vec<Directory> = db.select("directory").await.unwrap();
+        assert_eq!(directories.len(), 1);
+        assert_eq!(directories[0].path, "/tmp/repo");
+
+        // Running again is a no-op
+        run_migrations(&db).await.unwrap();
+        assert_eq!(schema_version(&db).await.unwrap(), CURRENT_SCHEMA_VERSION);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::parsers::strategy::ParsingStrategy;
+
+/// The number of rows grouped into each document, small enough that a group fits comfortably
+/// within the embedding model's context.
+pub(crate) const DEFAULT_ROWS_PER_DOCUMENT: usize = 20;
+
+pub(crate) fn csv_strategy() -> ParsingStrategy {
+    ParsingStrategy::Delimited {
+        format: "csv".to_string(),
+        rows_per_document: DEFAULT_ROWS_PER_DOCUMENT,
+    }
+}
+
+pub(crate) fn tsv_strategy() -> ParsingStrategy {
+    ParsingStrategy::Delimited {
+        format: "tsv".to_string(),
+        rows_per_document: DEFAULT_ROWS_PER_DOCUMENT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parsers::strategy::{get_sha, parse_content, ParseOptions, ROWS_SYMBOL_KIND};
+    use indoc::indoc;
+    use std::path::PathBuf;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_csv_parsing() {
+        let strategy = ParsingStrategy::Delimited {
+            format: "csv".to_string(),
+            rows_per_document: 2,
+        };
+
+        let content = indoc! {"
+            name,language
+            auden,rust
+            surrealdb,rust
+
+            tree-sitter,c
+            "};
+
+        let path = PathBuf::from("/tmp/foo.csv");
+
+        let parsed = parse_content(&path, content, &strategy, &ParseOptions::default()).unwrap();
+
+        let content1 = indoc! {"
+            The below is a group of rows from the '/tmp/foo.csv' file.
+            ```csv
+            name,language
+            auden,rust
+            surrealdb,rust
+            ```"}
+        .to_string();
+
+        let content2 = indoc! {"
+            The below is a group of rows from the '/tmp/foo.csv' file.
+            ```csv
+            name,language
+            tree-sitter,c
+            ```"}
+        .to_string();
+
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].content, content1);
+        assert_eq!(parsed[0].sha, get_sha(&content1));
+        assert_eq!(parsed[1].content, content2);
+        assert_eq!(parsed[1].sha, get_sha(&content2));
+
+        // The byte ranges point at the source rows, together covering every row
+        assert_eq!(
+            &content[parsed[0].start_byte..parsed[0].end_byte],
+            "auden,rust\nsurrealdb,rust"
+        );
+        assert_eq!(
+            &content[parsed[1].start_byte..parsed[1].end_byte],
+            "tree-sitter,c"
+        );
+        assert!(parsed
+            .iter()
+            .all(|document| document.symbol_kind == ROWS_SYMBOL_KIND));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::parsers::strategy::{ChunkGranularity, ParsingStrategy};
+
+pub(crate) fn go_strategy() -> ParsingStrategy {
+    ParsingStrategy::TreeSitter {
+        language: "go".to_string(),
+        query: "
+        (function_declaration) @item
+        (method_declaration) @item
+        (type_declaration) @item
+        (const_declaration) @item
+    "
+        .to_string(),
+        wrap: true,
+        granularity: ChunkGranularity::Node,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parsers::strategy::{
+        get_sha, parse_content, ContextDocument, LinePosition, ParseOptions,
+    };
+    use indoc::indoc;
+    use std::path::PathBuf;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_go_parsing() {
+        let strategy = go_strategy();
+
+        let content = indoc! {r#"
+            package main
+
+            func parse(content string) []string {
+                return strings.Fields(content)
+            }
+
+            func (p *Parser) Parse() []string {
+                return parse(p.content)
+            }
+
+            type Parser struct {
+                content string
+            }
+
+            const DefaultName = "parser"
+            "#};
+
+        let path = PathBuf::from("/tmp/foo.go");
+
+        let parsed = parse_content(&path, content, &strategy, &ParseOptions::default()).unwrap();
+
+        let content1 = indoc! {"
+            The below is a code snippet from the '/tmp/foo.go' file.
+            ```go
+            func parse(content string) []string {
+                return strings.Fields(content)
+            }
+            ```"}
+        .to_string();
+        let sha1 = get_sha(&content1);
+
+        let content2 = indoc! {"
+            The below is a code snippet from the '/tmp/foo.go' file.
+            ```go
+            func (p *Parser) Parse() []string {
+                return parse(p.content)
+            }
+            ```"}
+        .to_string();
+        let sha2 = get_sha(&content2);
+
+        let content3 = indoc! {"
+            The below is a code snippet from the '/tmp/foo.go' file.
+            ```go
+            type Parser struct {
+                content string
+            }
+            ```"}
+        .to_string();
+        let sha3 = get_sha(&content3);
+
+        let content4 = indoc! {r#"
+            The below is a code snippet from the '/tmp/foo.go' file.
+            ```go
+            const DefaultName = "parser"
+            ```"#}
+        .to_string();
+        let sha4 = get_sha(&content4);
+
+        assert_eq!(
+            parsed,
+            vec![
+                ContextDocument {
+                    start_byte: 14,
+                    end_byte: 88,
+                    start_position: LinePosition { line: 2, column: 0 },
+                    end_position: LinePosition { line: 4, column: 1 },
+                    content: content1,
+                    symbol_kind: "function_declaration".to_string(),
+                    sha: sha1,
+                    anchor: None,
+                    is_test: false,
+                },
+                ContextDocument {
+                    start_byte: 90,
+                    end_byte: 155,
+                    start_position: LinePosition { line: 6, column: 0 },
+                    end_position: LinePosition { line: 8, column: 1 },
+                    content: content2,
+                    symbol_kind: "method_declaration".to_string(),
+                    sha: sha2,
+                    anchor: None,
+                    is_test: false,
+                },
+                ContextDocument {
+                    start_byte: 157,
+                    end_byte: 198,
+                    start_position: LinePosition {
+                        line: 10,
+                        column: 0,
+                    },
+                    end_position: LinePosition {
+                        line: 12,
+                        column: 1,
+                    },
+                    content: content3,
+                    symbol_kind: "type_declaration".to_string(),
+                    sha: sha3,
+                    anchor: None,
+                    is_test: false,
+                },
+                ContextDocument {
+                    start_byte: 200,
+                    end_byte: 228,
+                    start_position: LinePosition {
+                        line: 14,
+                        column: 0,
+                    },
+                    end_position: LinePosition {
+                        line: 14,
+                        column: 28,
+                    },
+                    content: content4,
+                    symbol_kind: "const_declaration".to_string(),
+                    sha: sha4,
+                    anchor: None,
+                    is_test: false,
+                },
+            ]
+        );
+    }
+}
@@ -1,3 +1,8 @@
+pub(crate) mod delimited;
+pub(crate) mod go;
+pub(crate) mod plain_text;
+pub mod preprocessor;
+pub(crate) mod python;
 pub(crate) mod registry;
 pub(crate) mod rust;
 pub(crate) mod strategy;
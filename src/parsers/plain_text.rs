@@ -0,0 +1,85 @@
+/// The length of each window of a plain text file, in bytes, around a few hundred tokens.
+pub(crate) const DEFAULT_CHUNK_BYTES: usize = 1000;
+
+/// The bytes shared by consecutive windows, so text split at a window boundary still appears
+/// whole in one of them.
+pub(crate) const DEFAULT_OVERLAP_BYTES: usize = 200;
+
+/// The window and overlap, in bytes, of plain text windows of around a few hundred tokens, for
+/// `SemanticIndex::set_plain_text_fallback`.
+pub const DEFAULT_PLAIN_TEXT_WINDOW: (usize, usize) = (DEFAULT_CHUNK_BYTES, DEFAULT_OVERLAP_BYTES);
+
+#[cfg(test)]
+mod tests {
+
+    use crate::parsers::strategy::{
+        get_sha, parse_content, ParseOptions, ParsingStrategy, TEXT_SYMBOL_KIND,
+    };
+    use std::path::PathBuf;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_plain_text_parsing() {
+        let strategy = ParsingStrategy::PlainText {
+            chunk_bytes: 1000,
+            overlap_bytes: 200,
+        };
+
+        let content = "abcdefghi\n".repeat(500);
+        assert_eq!(content.len(), 5000);
+
+        let path = PathBuf::from("/tmp/notes.txt");
+        let parsed = parse_content(&path, &content, &strategy, &ParseOptions::default()).unwrap();
+
+        // Windows start every 800 bytes, the last reaching the end of the file
+        assert_eq!(parsed.len(), 6);
+        let ranges = parsed
+            .iter()
+            .map(|document| (document.start_byte, document.end_byte))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                (0, 1000),
+                (800, 1800),
+                (1600, 2600),
+                (2400, 3400),
+                (3200, 4200),
+                (4000, 5000)
+            ]
+        );
+
+        for document in parsed {
+            assert_eq!(document.symbol_kind, TEXT_SYMBOL_KIND);
+            assert_eq!(document.sha, get_sha(&document.content));
+            assert!(document
+                .content
+                .contains(&content[document.start_byte..document.end_byte]));
+        }
+    }
+
+    #[test]
+    fn test_plain_text_windows_respect_char_boundaries() {
+        let strategy = ParsingStrategy::PlainText {
+            chunk_bytes: 5,
+            overlap_bytes: 2,
+        };
+
+        let content = "é".repeat(10);
+        let parsed = parse_content(
+            &PathBuf::from("/tmp/accents.txt"),
+            &content,
+            &strategy,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        assert!(!parsed.is_empty());
+        assert_eq!(parsed[parsed.len() - 1].end_byte, content.len());
+        for document in parsed {
+            assert!(content.is_char_boundary(document.start_byte));
+            assert!(content.is_char_boundary(document.end_byte));
+        }
+    }
+}
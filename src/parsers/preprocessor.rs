@@ -0,0 +1,77 @@
+use std::fmt::Debug;
+
+/// Transforms the content of a span before it is wrapped for embedding and hashed.
+pub trait ContentPreprocessor: Send + Sync + Debug {
+    fn preprocess(&self, content: &str) -> String;
+}
+
+/// Default preprocessor, which leaves content untouched.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityPreprocessor;
+
+impl ContentPreprocessor for IdentityPreprocessor {
+    fn preprocess(&self, content: &str) -> String {
+        content.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parsers::rust::rust_strategy;
+    use crate::parsers::strategy::{get_sha, parse_content, ParseOptions};
+    use indoc::indoc;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use pretty_assertions::assert_eq;
+
+    #[derive(Debug)]
+    struct StripCommentsPreprocessor;
+
+    impl ContentPreprocessor for StripCommentsPreprocessor {
+        fn preprocess(&self, content: &str) -> String {
+            content
+                .lines()
+                .filter(|line| !line.trim_start().starts_with("//"))
+                .collect::<Vec<&str>>()
+                .join("\n")
+        }
+    }
+
+    #[test]
+    fn test_strip_comments_preprocessor() {
+        let strategy = rust_strategy();
+
+        let content = indoc! {"
+            struct CodeContextParser {
+                // the parsed content
+                content: String,
+            }
+            "};
+
+        let path = PathBuf::from("/tmp/foo.rs");
+
+        let options = ParseOptions {
+            preprocessor: Arc::new(StripCommentsPreprocessor),
+            ..Default::default()
+        };
+        let parsed = parse_content(&path, content, &strategy, &options).unwrap();
+
+        let expected = indoc! {"
+            The below is a code snippet from the '/tmp/foo.rs' file.
+            ```rust
+            struct CodeContextParser {
+                content: String,
+            }
+            ```"}
+        .to_string();
+
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].start_byte, 0);
+        assert_eq!(parsed[0].end_byte, content.trim_end().len());
+        assert_eq!(parsed[0].content, expected);
+        assert_eq!(parsed[0].sha, get_sha(&expected));
+    }
+}
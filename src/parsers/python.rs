@@ -0,0 +1,148 @@
+use crate::parsers::strategy::{ChunkGranularity, ParsingStrategy};
+
+pub(crate) fn python_strategy() -> ParsingStrategy {
+    ParsingStrategy::TreeSitter {
+        language: "python".to_string(),
+        query: "
+        (function_definition) @item
+        (class_definition) @item
+        (module (expression_statement (assignment) @item))
+    "
+        .to_string(),
+        wrap: true,
+        granularity: ChunkGranularity::Node,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parsers::strategy::{
+        get_sha, parse_content, ContextDocument, LinePosition, ParseOptions,
+    };
+    use indoc::indoc;
+    use std::path::PathBuf;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_python_parsing() {
+        let strategy = python_strategy();
+
+        let content = indoc! {r#"
+            def parse(content):
+                return content.split()
+
+
+            class Parser:
+                def __init__(self, content):
+                    self.content = content
+
+
+            DEFAULT_PARSER = Parser("")
+            "#};
+
+        let path = PathBuf::from("/tmp/foo.py");
+
+        let parsed = parse_content(&path, content, &strategy, &ParseOptions::default()).unwrap();
+
+        let content1 = indoc! {"
+            The below is a code snippet from the '/tmp/foo.py' file.
+            ```python
+            def parse(content):
+                return content.split()
+            ```"}
+        .to_string();
+        let sha1 = get_sha(&content1);
+
+        let content2 = indoc! {"
+            The below is a code snippet from the '/tmp/foo.py' file.
+            ```python
+            class Parser:
+                def __init__(self, content):
+                    self.content = content
+            ```"}
+        .to_string();
+        let sha2 = get_sha(&content2);
+
+        let content3 = indoc! {"
+            The below is a code snippet from the '/tmp/foo.py' file.
+            ```python
+            def __init__(self, content):
+                    self.content = content
+            ```"}
+        .to_string();
+        let sha3 = get_sha(&content3);
+
+        let content4 = indoc! {r#"
+            The below is a code snippet from the '/tmp/foo.py' file.
+            ```python
+            DEFAULT_PARSER = Parser("")
+            ```"#}
+        .to_string();
+        let sha4 = get_sha(&content4);
+
+        assert_eq!(
+            parsed,
+            vec![
+                ContextDocument {
+                    start_byte: 0,
+                    end_byte: 46,
+                    start_position: LinePosition { line: 0, column: 0 },
+                    end_position: LinePosition {
+                        line: 1,
+                        column: 26,
+                    },
+                    content: content1,
+                    symbol_kind: "function_definition".to_string(),
+                    sha: sha1,
+                    anchor: None,
+                    is_test: false,
+                },
+                ContextDocument {
+                    start_byte: 49,
+                    end_byte: 126,
+                    start_position: LinePosition { line: 4, column: 0 },
+                    end_position: LinePosition {
+                        line: 6,
+                        column: 30,
+                    },
+                    content: content2,
+                    symbol_kind: "class_definition".to_string(),
+                    sha: sha2,
+                    anchor: None,
+                    is_test: false,
+                },
+                ContextDocument {
+                    start_byte: 67,
+                    end_byte: 126,
+                    start_position: LinePosition { line: 5, column: 4 },
+                    end_position: LinePosition {
+                        line: 6,
+                        column: 30,
+                    },
+                    content: content3,
+                    symbol_kind: "function_definition".to_string(),
+                    sha: sha3,
+                    anchor: None,
+                    is_test: false,
+                },
+                ContextDocument {
+                    start_byte: 129,
+                    end_byte: 156,
+                    start_position: LinePosition { line: 9, column: 0 },
+                    end_position: LinePosition {
+                        line: 9,
+                        column: 27,
+                    },
+                    content: content4,
+                    symbol_kind: "assignment".to_string(),
+                    sha: sha4,
+                    anchor: None,
+                    is_test: false,
+                },
+            ]
+        );
+    }
+}
@@ -1,17 +1,41 @@
+use crate::parsers::delimited::{csv_strategy, tsv_strategy};
+use crate::parsers::go::go_strategy;
+use crate::parsers::python::python_strategy;
 use crate::parsers::rust::rust_strategy;
-use crate::parsers::strategy::ParsingStrategy;
+use crate::parsers::strategy::{validate_strategy, ChunkGranularity, ParsingStrategy};
 use anyhow::anyhow;
+use serde::Deserialize;
 use std::collections::HashMap;
+use std::path::Path;
 
-#[derive(Debug)]
+/// A user configured strategy for an extension, as loaded from an extension config file.
+#[derive(Debug, Deserialize)]
+struct ExtensionConfig {
+    language: String,
+    query: String,
+    #[serde(default = "default_wrap")]
+    wrap: bool,
+    /// One of `"node"`, `"whole_file"` or `{ "fixed_lines": n }`, defaulting to `"node"`.
+    #[serde(default)]
+    granularity: ChunkGranularity,
+}
+
+fn default_wrap() -> bool {
+    true
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct ExtensionRegistry {
     extension_strategies: HashMap<String, ParsingStrategy>,
+    /// The strategy for extensions without one registered, which are otherwise not indexed.
+    fallback: Option<ParsingStrategy>,
 }
 
 impl ExtensionRegistry {
     fn new() -> Self {
         ExtensionRegistry {
             extension_strategies: HashMap::new(),
+            fallback: None,
         }
     }
     fn register(&mut self, extension: String, strategy: ParsingStrategy) {
@@ -23,13 +47,133 @@ impl ExtensionRegistry {
     ) -> anyhow::Result<&ParsingStrategy> {
         self.extension_strategies
             .get(&extension)
+            .or(self.fallback.as_ref())
             .ok_or(anyhow!("strategy not found for extension {}", extension))
     }
+
+    /// Sets the strategy used for every extension without one registered, or clears it.
+    pub(crate) fn set_fallback(&mut self, fallback: Option<ParsingStrategy>) -> anyhow::Result<()> {
+        if let Some(strategy) = &fallback {
+            validate_strategy(strategy)?;
+        }
+        self.fallback = fallback;
+        anyhow::Ok(())
+    }
+
+    /// Loads extension mappings from a json config file, of the form
+    /// `{ "ext": { "language": "rust", "query": "(struct_item) @item" } }`, merging them over
+    /// the currently registered strategies.
+    pub(crate) fn load_config(&mut self, path: &Path) -> anyhow::Result<()> {
+        let content = std::fs::read_to_string(path)?;
+        let configs: HashMap<String, ExtensionConfig> = serde_json::from_str(&content)?;
+
+        for (extension, config) in configs {
+            let strategy = ParsingStrategy::TreeSitter {
+                language: config.language,
+                query: config.query,
+                wrap: config.wrap,
+                granularity: config.granularity,
+            };
+            validate_strategy(&strategy)
+                .map_err(|err| anyhow!("invalid strategy for extension {}: {}", extension, err))?;
+            self.register(extension, strategy);
+        }
+
+        anyhow::Ok(())
+    }
 }
 
 pub(crate) fn load_extensions() -> ExtensionRegistry {
     let mut registry = ExtensionRegistry::new();
     registry.register("rs".to_string(), rust_strategy());
+    registry.register("py".to_string(), python_strategy());
+    registry.register("go".to_string(), go_strategy());
+    registry.register("csv".to_string(), csv_strategy());
+    registry.register("tsv".to_string(), tsv_strategy());
 
     registry
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parsers::plain_text::{DEFAULT_CHUNK_BYTES, DEFAULT_OVERLAP_BYTES};
+    use crate::parsers::strategy::{parse_content, ParseOptions};
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_load_config() {
+        let tmp_dir = tempdir().unwrap();
+        let config_path = tmp_dir.path().join("extensions.json");
+        std::fs::write(
+            &config_path,
+            r#"{ "rs2": { "language": "rust", "query": "(struct_item) @item" } }"#,
+        )
+        .unwrap();
+
+        let mut registry = load_extensions();
+        registry.load_config(&config_path).unwrap();
+
+        let strategy = registry
+            .get_strategy_for_extension("rs2".to_string())
+            .unwrap();
+        let parsed = parse_content(
+            &PathBuf::from("/tmp/foo.rs2"),
+            "struct Foo {}",
+            strategy,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(parsed.len(), 1);
+    }
+
+    #[test]
+    fn test_load_config_invalid_query() {
+        let tmp_dir = tempdir().unwrap();
+        let config_path = tmp_dir.path().join("extensions.json");
+        std::fs::write(
+            &config_path,
+            r#"{ "rs2": { "language": "rust", "query": "(not_a_node) @item" } }"#,
+        )
+        .unwrap();
+
+        let mut registry = load_extensions();
+        assert!(registry.load_config(&config_path).is_err());
+    }
+
+    #[test]
+    fn test_plain_text_fallback() {
+        let mut registry = load_extensions();
+        assert!(registry
+            .get_strategy_for_extension("txt".to_string())
+            .is_err());
+
+        let fallback = ParsingStrategy::PlainText {
+            chunk_bytes: DEFAULT_CHUNK_BYTES,
+            overlap_bytes: DEFAULT_OVERLAP_BYTES,
+        };
+        registry.set_fallback(Some(fallback)).unwrap();
+        assert!(matches!(
+            registry.get_strategy_for_extension("txt".to_string()),
+            Ok(ParsingStrategy::PlainText { .. })
+        ));
+
+        // Registered extensions keep their own strategy
+        assert!(matches!(
+            registry.get_strategy_for_extension("rs".to_string()),
+            Ok(ParsingStrategy::TreeSitter { .. })
+        ));
+
+        let invalid = ParsingStrategy::PlainText {
+            chunk_bytes: 100,
+            overlap_bytes: 100,
+        };
+        assert!(registry.set_fallback(Some(invalid)).is_err());
+        registry.set_fallback(None).unwrap();
+        assert!(registry
+            .get_strategy_for_extension("txt".to_string())
+            .is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::parsers::strategy::ParsingStrategy;
+use crate::parsers::strategy::{ChunkGranularity, ParsingStrategy};
 
 pub(crate) fn rust_strategy() -> ParsingStrategy {
     ParsingStrategy::TreeSitter {
@@ -9,6 +9,8 @@ pub(crate) fn rust_strategy() -> ParsingStrategy {
         (impl_item) @item
     "
         .to_string(),
+        wrap: true,
+        granularity: ChunkGranularity::Node,
     }
 }
 
@@ -16,7 +18,9 @@ pub(crate) fn rust_strategy() -> ParsingStrategy {
 mod tests {
 
     use super::*;
-    use crate::parsers::strategy::{get_sha, parse_content, ContextDocument};
+    use crate::parsers::strategy::{
+        get_sha, parse_content, ContextDocument, LinePosition, ParseOptions,
+    };
     use indoc::indoc;
     use std::path::PathBuf;
 
@@ -38,7 +42,7 @@ mod tests {
 
         let path = PathBuf::from("/tmp/foo.rs");
 
-        let parsed = parse_content(&path, content, &strategy).unwrap();
+        let parsed = parse_content(&path, content, &strategy, &ParseOptions::default()).unwrap();
 
         let content1 = indoc! {"The below is a code snippet from the '/tmp/foo.rs' file.\n```rust\nstruct CodeContextParser {}\n```"}.to_string();
         let sha1 = get_sha(&content1);
@@ -60,16 +64,109 @@ mod tests {
                 ContextDocument {
                     start_byte: 0,
                     end_byte: 27,
+                    start_position: LinePosition { line: 0, column: 0 },
+                    end_position: LinePosition {
+                        line: 0,
+                        column: 27,
+                    },
                     content: content1,
+                    symbol_kind: "struct_item".to_string(),
                     sha: sha1,
+                    anchor: None,
+                    is_test: false,
                 },
                 ContextDocument {
                     start_byte: 29,
                     end_byte: 134,
+                    start_position: LinePosition { line: 2, column: 0 },
+                    end_position: LinePosition { line: 6, column: 1 },
                     content: content2,
+                    symbol_kind: "impl_item".to_string(),
                     sha: sha2,
+                    anchor: None,
+                    is_test: false,
                 }
             ]
         );
     }
+
+    #[test]
+    fn test_rust_parsing_unwrapped() {
+        let strategy = match rust_strategy() {
+            ParsingStrategy::TreeSitter {
+                language,
+                query,
+                granularity,
+                ..
+            } => ParsingStrategy::TreeSitter {
+                language,
+                query,
+                wrap: false,
+                granularity,
+            },
+            strategy => strategy,
+        };
+
+        let content = indoc! {"
+            struct CodeContextParser {}
+
+            enum Kind { Struct, Impl }
+            "};
+
+        let path = PathBuf::from("/tmp/foo.rs");
+
+        let parsed = parse_content(&path, content, &strategy, &ParseOptions::default()).unwrap();
+
+        assert_eq!(parsed.len(), 2);
+        for document in parsed {
+            let slice = &content[document.start_byte..document.end_byte];
+            assert_eq!(document.content, slice);
+            assert_eq!(document.sha, get_sha(slice));
+        }
+    }
+
+    #[test]
+    fn test_rust_parsing_with_imports() {
+        let strategy = rust_strategy();
+
+        let content = indoc! {"
+            use std::fs;
+
+            impl CodeContextParser {
+                fn read(&self) -> String {
+                    fs::read_to_string(&self.path).unwrap()
+                }
+            }
+            "};
+
+        let path = PathBuf::from("/tmp/foo.rs");
+
+        let options = ParseOptions {
+            include_imports: true,
+            ..Default::default()
+        };
+        let parsed = parse_content(&path, content, &strategy, &options).unwrap();
+
+        let expected = indoc! {"
+            The below is a code snippet from the '/tmp/foo.rs' file.
+            ```rust
+            use std::fs;
+
+            impl CodeContextParser {
+                fn read(&self) -> String {
+                    fs::read_to_string(&self.path).unwrap()
+                }
+            }
+            ```"}
+        .to_string();
+
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].start_byte, 14);
+        assert_eq!(parsed[0].content, expected);
+        assert_eq!(parsed[0].sha, get_sha(&expected));
+
+        // Imports are left out by default
+        let parsed = parse_content(&path, content, &strategy, &ParseOptions::default()).unwrap();
+        assert!(!parsed[0].content.contains("use std::fs;"));
+    }
 }
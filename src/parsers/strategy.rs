@@ -1,13 +1,140 @@
 use anyhow::anyhow;
+use serde::Deserialize;
 use sha2::{Digest, Sha256};
 use std::path::PathBuf;
-use tree_sitter::{Language, Parser, Query, QueryCursor};
+use std::sync::Arc;
+use tree_sitter::{Language, Node, Parser, Point, Query, QueryCursor};
 
+use crate::parsers::preprocessor::{ContentPreprocessor, IdentityPreprocessor};
 use crate::semantic_index::FileDetails;
 
+/// How finely a treesitter strategy splits a file into spans.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChunkGranularity {
+    /// A span for each node matched by the strategy's query.
+    #[default]
+    Node,
+    /// A single span covering the whole file, suiting small or documentation heavy files.
+    WholeFile,
+    /// Spans of the given number of lines, regardless of the file's syntax.
+    FixedLines(usize),
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum ParsingStrategy {
-    TreeSitter { language: String, query: String },
+    TreeSitter {
+        language: String,
+        query: String,
+        /// Whether to wrap each span in a prompt naming its file and language before embedding.
+        /// Instruction following embedding models benefit from the wrapper, whereas raw code
+        /// embedding models are better served by the bare source.
+        wrap: bool,
+        granularity: ChunkGranularity,
+    },
+    /// Splits a README into its markdown sections, tagging each with the `readme` symbol kind.
+    Readme,
+    /// Groups the rows of a delimited data file, prepending the header row to each group so
+    /// the columns stay labelled.
+    Delimited {
+        /// The format, ie. `csv` or `tsv`, used to label the snippet.
+        format: String,
+        rows_per_document: usize,
+    },
+    /// Splits any text into overlapping windows of bytes, regardless of its format, for files
+    /// without a dedicated strategy.
+    PlainText {
+        chunk_bytes: usize,
+        /// The bytes each window shares with the one before it.
+        overlap_bytes: usize,
+    },
+}
+
+impl ParsingStrategy {
+    /// A hash of everything determining how the strategy splits a file, which changes
+    /// whenever, for example, a language's query is edited.
+    pub(crate) fn version(&self) -> Vec<u8> {
+        let definition = match self {
+            // Node granularity is left out, keeping the versions of existing indexes stable
+            ParsingStrategy::TreeSitter {
+                language,
+                query,
+                wrap,
+                granularity: ChunkGranularity::Node,
+            } => format!("treesitter\n{language}\n{query}\n{wrap}"),
+            ParsingStrategy::TreeSitter {
+                language,
+                query,
+                wrap,
+                granularity,
+            } => format!("treesitter\n{language}\n{query}\n{wrap}\n{granularity:?}"),
+            ParsingStrategy::Readme => "readme".to_string(),
+            ParsingStrategy::Delimited {
+                format,
+                rows_per_document,
+            } => format!("delimited\n{format}\n{rows_per_document}"),
+            ParsingStrategy::PlainText {
+                chunk_bytes,
+                overlap_bytes,
+            } => format!("plaintext\n{chunk_bytes}\n{overlap_bytes}"),
+        };
+        get_sha(&definition)
+    }
+}
+
+/// The symbol kind given to spans parsed from a directory's README.
+pub(crate) const README_SYMBOL_KIND: &str = "readme";
+
+/// The symbol kind given to row groups parsed from delimited data files.
+pub(crate) const ROWS_SYMBOL_KIND: &str = "rows";
+
+/// The symbol kind given to windows split from plain text files.
+pub(crate) const TEXT_SYMBOL_KIND: &str = "text";
+
+/// The symbol kind given to spans split by `ChunkGranularity::FixedLines`.
+pub(crate) const LINES_SYMBOL_KIND: &str = "lines";
+
+/// The version of the templates spans are wrapped in before embedding, such as the snippet
+/// preamble naming the file. Bump this whenever a template changes, so spans embedded with
+/// the old framing are re-embedded rather than compared against spans framed the new way.
+pub(crate) const TEMPLATE_VERSION: u32 = 1;
+
+/// The template version of spans and directories indexed before versions were recorded.
+pub(crate) const UNRECORDED_TEMPLATE_VERSION: u32 = 1;
+
+/// Files with a line longer than this are skipped, as minified or generated single line
+/// files tend to choke both treesitter and the embedding provider.
+pub(crate) const DEFAULT_MAX_LINE_LENGTH: usize = 10_000;
+
+/// Files parsing into more spans than this are truncated, as generated files with many tiny
+/// items would otherwise consume a disproportionate share of the embedding budget.
+pub(crate) const DEFAULT_MAX_SPANS_PER_FILE: usize = 1_000;
+
+#[derive(Debug, Clone)]
+pub(crate) struct ParseOptions {
+    pub(crate) preprocessor: Arc<dyn ContentPreprocessor>,
+    pub(crate) max_line_length: usize,
+    /// Prepend the file's import statements to each span, so references to imported types
+    /// and traits carry their meaning into the embedding.
+    pub(crate) include_imports: bool,
+    /// Anchor each span by the lines it starts on, so it can be relocated after edits elsewhere
+    /// in the file shift its byte offsets.
+    pub(crate) line_anchors: bool,
+    pub(crate) max_spans_per_file: usize,
+    pub(crate) template_version: u32,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            preprocessor: Arc::new(IdentityPreprocessor),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            include_imports: false,
+            line_anchors: false,
+            max_spans_per_file: DEFAULT_MAX_SPANS_PER_FILE,
+            template_version: TEMPLATE_VERSION,
+        }
+    }
 }
 
 pub(crate) fn get_sha(content: &str) -> Vec<u8> {
@@ -16,9 +143,47 @@ pub(crate) fn get_sha(content: &str) -> Vec<u8> {
     hasher.finalize()[..].to_vec()
 }
 
+/// The number of lines, from the first line of a span, hashed into its line anchor.
+const ANCHOR_LINES: usize = 3;
+
+fn anchor_window<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<u8> {
+    // Lines are trimmed, so the anchor also survives re-indentation
+    let window = lines
+        .take(ANCHOR_LINES)
+        .map(str::trim)
+        .collect::<Vec<&str>>()
+        .join("\n");
+    get_sha(&window)
+}
+
+/// Hashes the lines a span starts on, which stay the same for a span that is moved but not
+/// changed.
+pub(crate) fn line_anchor(content: &str, start_byte: usize) -> Vec<u8> {
+    let line_start = content[..start_byte]
+        .rfind('\n')
+        .map(|idx| idx + 1)
+        .unwrap_or(0);
+    anchor_window(content[line_start..].lines())
+}
+
+/// Finds the span with the given line anchor, returning the byte offset of the first non
+/// whitespace character of the line it starts on.
+pub(crate) fn locate_line_anchor(content: &str, anchor: &[u8]) -> Option<usize> {
+    let mut line_start = 0;
+    for line in content.split_inclusive('\n') {
+        if anchor_window(content[line_start..].lines()) == anchor {
+            return Some(line_start + line.len() - line.trim_start().len());
+        }
+        line_start += line.len();
+    }
+    None
+}
+
 fn get_treesitter_language(language_name: &str) -> anyhow::Result<Language> {
     match language_name {
         "rust" => anyhow::Ok(tree_sitter_rust::language()),
+        "python" => anyhow::Ok(tree_sitter_python::language()),
+        "go" => anyhow::Ok(tree_sitter_go::language()),
         _ => Err(anyhow!(
             "no treesitter parser available for {}",
             language_name
@@ -26,11 +191,130 @@ fn get_treesitter_language(language_name: &str) -> anyhow::Result<Language> {
     }
 }
 
+/// The query capturing import statements for the language, if imports can be extracted.
+fn get_imports_query(language_name: &str) -> Option<&'static str> {
+    match language_name {
+        "rust" => Some("(use_declaration) @import"),
+        "python" => Some("(import_statement) @import (import_from_statement) @import"),
+        "go" => Some("(import_declaration) @import"),
+        _ => None,
+    }
+}
+
+/// Collects the file's import statements, one per line.
+fn collect_imports(
+    content: &str,
+    language: Language,
+    language_name: &str,
+    tree: &tree_sitter::Tree,
+) -> anyhow::Result<String> {
+    let Some(imports_query) = get_imports_query(language_name) else {
+        return anyhow::Ok(String::new());
+    };
+
+    let query = Query::new(language, imports_query)?;
+    let mut query_cursor = QueryCursor::new();
+    let imports = query_cursor
+        .matches(&query, tree.root_node(), content.as_bytes())
+        .flat_map(|m| m.captures.iter().map(|capture| capture.node.byte_range()))
+        .map(|range| &content[range])
+        .collect::<Vec<&str>>();
+
+    anyhow::Ok(imports.join("\n"))
+}
+
+/// Whether the attribute marks a Rust item as a test, or as only compiled for tests.
+fn is_test_attribute(attribute: &str) -> bool {
+    let attribute = attribute
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    attribute == "#[test]" || attribute.ends_with("::test]") || attribute.contains("cfg(test)")
+}
+
+/// Whether the node is a test, either by the naming convention of the language's test
+/// frameworks, or for Rust by a test attribute on the item or any item it is nested within.
+fn is_test_node(node: Node, language_name: &str, content: &str) -> bool {
+    let name = node
+        .child_by_field_name("name")
+        .map(|name| &content[name.byte_range()])
+        .unwrap_or_default();
+    let named_test = match language_name {
+        "python" => name.starts_with("test_") || name.starts_with("Test"),
+        "go" => ["Test", "Benchmark", "Fuzz", "Example"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix)),
+        _ => name.starts_with("test_"),
+    };
+    if named_test {
+        return true;
+    }
+
+    if language_name == "rust" {
+        // Attributes are the siblings preceding an item, rather than its children
+        let mut item = Some(node);
+        while let Some(current) = item {
+            let mut sibling = current.prev_named_sibling();
+            while let Some(attribute) = sibling.filter(|sibling| sibling.kind() == "attribute_item")
+            {
+                if is_test_attribute(&content[attribute.byte_range()]) {
+                    return true;
+                }
+                sibling = attribute.prev_named_sibling();
+            }
+            item = current.parent();
+        }
+    }
+
+    false
+}
+
+/// Checks that the strategy can be used for parsing, ie. that its language is available and its
+/// query compiles.
+pub(crate) fn validate_strategy(strategy: &ParsingStrategy) -> anyhow::Result<()> {
+    match strategy {
+        ParsingStrategy::TreeSitter {
+            language,
+            query,
+            granularity,
+            ..
+        } => {
+            if *granularity == ChunkGranularity::FixedLines(0) {
+                return Err(anyhow!("lines per chunk must be greater than zero"));
+            }
+            let language = get_treesitter_language(language)?;
+            Query::new(language, query)?;
+            anyhow::Ok(())
+        }
+        ParsingStrategy::Readme => anyhow::Ok(()),
+        ParsingStrategy::Delimited {
+            rows_per_document, ..
+        } => {
+            if *rows_per_document == 0 {
+                return Err(anyhow!("rows per document must be greater than zero"));
+            }
+            anyhow::Ok(())
+        }
+        ParsingStrategy::PlainText {
+            chunk_bytes,
+            overlap_bytes,
+        } => {
+            if overlap_bytes >= chunk_bytes {
+                return Err(anyhow!("chunk bytes must be greater than overlap bytes"));
+            }
+            anyhow::Ok(())
+        }
+    }
+}
+
 fn parse_treesitter(
     content: &str,
     language_name: &str,
     query: &str,
     path: &str,
+    wrap: bool,
+    granularity: ChunkGranularity,
+    options: &ParseOptions,
 ) -> anyhow::Result<Vec<ContextDocument>> {
     // Get Treesitter Parser
     let language = get_treesitter_language(language_name)?;
@@ -40,21 +324,108 @@ fn parse_treesitter(
 
     let tree = parser.parse(&content, None).expect("");
 
+    let imports = if options.include_imports {
+        collect_imports(content, language, language_name, &tree)?
+    } else {
+        String::new()
+    };
+
+    let fill = |span: String| {
+        if wrap {
+            format!(
+                "The below is a code snippet from the '{path}' file.\n```{language_name}\n{span}\n```"
+            )
+        } else {
+            span
+        }
+    };
+
     let mut documents = Vec::new();
+    match granularity {
+        ChunkGranularity::Node => {}
+        ChunkGranularity::WholeFile => {
+            let end_byte = content.trim_end().len();
+            if !content[..end_byte].trim().is_empty() {
+                let filled = fill(options.preprocessor.preprocess(&content[..end_byte]));
+                let sha = get_sha(&filled);
+                documents.push(ContextDocument {
+                    start_byte: 0,
+                    end_byte,
+                    start_position: LinePosition::default(),
+                    end_position: LinePosition::of_byte(content, end_byte),
+                    content: filled,
+                    sha,
+                    symbol_kind: tree.root_node().kind().to_string(),
+                    anchor: None,
+                    is_test: false,
+                });
+            }
+            return anyhow::Ok(documents);
+        }
+        ChunkGranularity::FixedLines(lines_per_chunk) => {
+            let mut lines = Vec::new();
+            let mut offset = 0;
+            for line in content.split_inclusive('\n') {
+                lines.push(offset..offset + line.len());
+                offset += line.len();
+            }
+
+            for group in lines.chunks(lines_per_chunk.max(1)) {
+                let start_byte = group[0].start;
+                let end_byte = start_byte
+                    + content[start_byte..group[group.len() - 1].end]
+                        .trim_end()
+                        .len();
+                if content[start_byte..end_byte].trim().is_empty() {
+                    continue;
+                }
+
+                let mut span = options
+                    .preprocessor
+                    .preprocess(&content[start_byte..end_byte]);
+                if !imports.is_empty() {
+                    span = format!("{imports}\n\n{span}");
+                }
+                let filled = fill(span);
+                let sha = get_sha(&filled);
+                documents.push(ContextDocument {
+                    start_byte,
+                    end_byte,
+                    start_position: LinePosition::of_byte(content, start_byte),
+                    end_position: LinePosition::of_byte(content, end_byte),
+                    content: filled,
+                    sha,
+                    symbol_kind: LINES_SYMBOL_KIND.to_string(),
+                    anchor: None,
+                    is_test: false,
+                });
+            }
+            return anyhow::Ok(documents);
+        }
+    }
+
     let mut query_cursor = QueryCursor::new();
     for m in query_cursor.matches(&query, tree.root_node(), content.as_bytes()) {
         for capture in m.captures {
             if capture.index == 0 {
-                let span = &content[capture.node.start_byte()..capture.node.end_byte()];
-                let filled = format!(
-                    "The below is a code snippet from the '{path}' file.\n```{language_name}\n{span}\n```"
-                );
+                let mut span = options
+                    .preprocessor
+                    .preprocess(&content[capture.node.start_byte()..capture.node.end_byte()]);
+                if !imports.is_empty() {
+                    span = format!("{imports}\n\n{span}");
+                }
+                let filled = fill(span);
                 let sha = get_sha(&filled);
                 documents.push(ContextDocument {
                     start_byte: capture.node.start_byte(),
                     end_byte: capture.node.end_byte(),
-                    content: filled.to_string(),
+                    start_position: capture.node.start_position().into(),
+                    end_position: capture.node.end_position().into(),
+                    content: filled,
                     sha,
+                    symbol_kind: capture.node.kind().to_string(),
+                    anchor: None,
+                    is_test: is_test_node(capture.node, language_name, content),
                 });
             }
         }
@@ -63,12 +434,206 @@ fn parse_treesitter(
     anyhow::Ok(documents)
 }
 
+fn parse_readme(
+    content: &str,
+    path: &str,
+    preprocessor: &dyn ContentPreprocessor,
+) -> Vec<ContextDocument> {
+    // Find the byte ranges of each section, split at each heading
+    let mut sections = Vec::new();
+    let mut section_start = 0;
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        if line.starts_with('#') && offset > section_start {
+            sections.push(section_start..offset);
+            section_start = offset;
+        }
+        offset += line.len();
+    }
+    sections.push(section_start..offset);
+
+    let mut documents = Vec::new();
+    for section in sections {
+        let span = content[section.clone()].trim_end();
+        if span.trim().is_empty() {
+            continue;
+        }
+
+        let span = preprocessor.preprocess(span);
+        let filled =
+            format!("The below is a section from the '{path}' file.\n```markdown\n{span}\n```");
+        let sha = get_sha(&filled);
+        let end_byte = section.start + content[section.clone()].trim_end().len();
+        documents.push(ContextDocument {
+            start_byte: section.start,
+            end_byte,
+            start_position: LinePosition::of_byte(content, section.start),
+            end_position: LinePosition::of_byte(content, end_byte),
+            content: filled,
+            sha,
+            symbol_kind: README_SYMBOL_KIND.to_string(),
+            anchor: None,
+            is_test: false,
+        });
+    }
+
+    documents
+}
+
+fn parse_delimited(
+    content: &str,
+    path: &str,
+    format: &str,
+    rows_per_document: usize,
+    preprocessor: &dyn ContentPreprocessor,
+) -> Vec<ContextDocument> {
+    let mut lines = content.split_inclusive('\n');
+    let Some(header_line) = lines.next() else {
+        return Vec::new();
+    };
+    let header = header_line.trim_end();
+
+    // Find the byte ranges of each row after the header
+    let mut rows = Vec::new();
+    let mut offset = header_line.len();
+    for line in lines {
+        if !line.trim().is_empty() {
+            rows.push(offset..offset + line.trim_end().len());
+        }
+        offset += line.len();
+    }
+
+    let mut documents = Vec::new();
+    for group in rows.chunks(rows_per_document.max(1)) {
+        let start_byte = group[0].start;
+        let end_byte = group[group.len() - 1].end;
+
+        let span =
+            preprocessor.preprocess(&format!("{header}\n{}", &content[start_byte..end_byte]));
+        let filled = format!(
+            "The below is a group of rows from the '{path}' file.\n```{format}\n{span}\n```"
+        );
+        let sha = get_sha(&filled);
+        documents.push(ContextDocument {
+            start_byte,
+            end_byte,
+            start_position: LinePosition::of_byte(content, start_byte),
+            end_position: LinePosition::of_byte(content, end_byte),
+            content: filled,
+            sha,
+            symbol_kind: ROWS_SYMBOL_KIND.to_string(),
+            anchor: None,
+            is_test: false,
+        });
+    }
+
+    documents
+}
+
+/// A zero-based line, and column in bytes within the line, locating a span in its file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct LinePosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl LinePosition {
+    /// The position of the byte offset within the content, for spans not taken from a node.
+    pub(crate) fn of_byte(content: &str, byte: usize) -> Self {
+        let before = &content.as_bytes()[..byte];
+        let line_start = before
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |idx| idx + 1);
+        LinePosition {
+            line: before.iter().filter(|byte| **byte == b'\n').count(),
+            column: byte - line_start,
+        }
+    }
+}
+
+impl From<Point> for LinePosition {
+    fn from(point: Point) -> Self {
+        LinePosition {
+            line: point.row,
+            column: point.column,
+        }
+    }
+}
+
+fn parse_plain_text(
+    content: &str,
+    path: &str,
+    chunk_bytes: usize,
+    overlap_bytes: usize,
+    preprocessor: &dyn ContentPreprocessor,
+) -> Vec<ContextDocument> {
+    // Windows always advance, even if the strategy wasn't validated
+    let step = chunk_bytes.saturating_sub(overlap_bytes).max(1);
+
+    let mut documents = Vec::new();
+    let mut start_byte = 0;
+    while start_byte < content.len() {
+        // Boundaries are moved back onto character boundaries, so windows are valid strings
+        let mut end_byte = (start_byte + chunk_bytes.max(1)).min(content.len());
+        while !content.is_char_boundary(end_byte) {
+            end_byte -= 1;
+        }
+        if end_byte <= start_byte {
+            end_byte = content[start_byte..]
+                .chars()
+                .next()
+                .map_or(content.len(), |c| start_byte + c.len_utf8());
+        }
+
+        if !content[start_byte..end_byte].trim().is_empty() {
+            let span = preprocessor.preprocess(&content[start_byte..end_byte]);
+            let filled =
+                format!("The below is an excerpt from the '{path}' file.\n```\n{span}\n```");
+            let sha = get_sha(&filled);
+            documents.push(ContextDocument {
+                start_byte,
+                end_byte,
+                start_position: LinePosition::of_byte(content, start_byte),
+                end_position: LinePosition::of_byte(content, end_byte),
+                content: filled,
+                sha,
+                symbol_kind: TEXT_SYMBOL_KIND.to_string(),
+                anchor: None,
+                is_test: false,
+            });
+        }
+
+        if end_byte == content.len() {
+            break;
+        }
+        let mut next_start = (start_byte + step).min(end_byte);
+        while !content.is_char_boundary(next_start) {
+            next_start -= 1;
+        }
+        start_byte = if next_start > start_byte {
+            next_start
+        } else {
+            end_byte
+        };
+    }
+
+    documents
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) struct ContextDocument {
     pub start_byte: usize,
     pub end_byte: usize,
+    pub start_position: LinePosition,
+    pub end_position: LinePosition,
     pub content: String,
     pub sha: Vec<u8>,
+    pub symbol_kind: String,
+    /// A hash of the lines the span starts on, set when line anchors are enabled.
+    pub anchor: Option<Vec<u8>>,
+    /// Whether the span is a test, or sits within test only code.
+    pub is_test: bool,
 }
 
 #[derive(Debug)]
@@ -76,6 +641,13 @@ pub(crate) struct FileContext {
     pub(crate) details: FileDetails,
     pub(crate) documents: Vec<ContextDocument>,
     pub(crate) embeddings: Vec<Vec<f32>>,
+    /// The version of the strategy the file was parsed with, stored alongside its spans.
+    pub(crate) strategy_version: Option<Vec<u8>>,
+    /// The version of the templates its spans were wrapped in, stored alongside its spans.
+    pub(crate) template_version: Option<u32>,
+    /// The content the file was parsed from, shared by its spans until they are written, as
+    /// their own content is moved out for embedding.
+    pub(crate) source: Arc<str>,
 }
 
 impl FileContext {
@@ -88,6 +660,20 @@ impl FileContext {
         }
         ids
     }
+
+    /// Drops spans beyond the limit, keeping those earliest in the file. Returns the number of
+    /// spans the file parsed into if any were dropped.
+    pub(crate) fn truncate_spans(&mut self, max_spans: usize) -> Option<usize> {
+        let spans = self.documents.len();
+        if spans <= max_spans {
+            return None;
+        }
+
+        self.documents.truncate(max_spans);
+        self.embeddings.truncate(max_spans);
+        Some(spans)
+    }
+
     pub(crate) fn complete(&self) -> bool {
         let complete = !self.embeddings.iter().any(|embed| embed.is_empty());
         complete
@@ -102,16 +688,42 @@ impl Drop for FileContext {
 pub(crate) async fn parse_file(
     details: FileDetails,
     strategy: &ParsingStrategy,
+    options: &ParseOptions,
 ) -> anyhow::Result<FileContext> {
-    let content = tokio::fs::read_to_string(&details.path).await?;
+    let content: Arc<str> = tokio::fs::read_to_string(&details.path).await?.into();
+
+    if let Some(line_length) = content
+        .lines()
+        .map(|line| line.len())
+        .find(|length| *length > options.max_line_length)
+    {
+        log::warn!(
+            "skipping {:?}, line length {} exceeds maximum of {}",
+            details.path,
+            line_length,
+            options.max_line_length
+        );
+        return Err(anyhow!(
+            "line length exceeds maximum of {}",
+            options.max_line_length
+        ));
+    }
 
-    let documents = parse_content(&details.path, content.as_str(), strategy)?;
+    let mut documents = parse_content(&details.path, &content, strategy, options)?;
+    if options.line_anchors {
+        for document in documents.iter_mut() {
+            document.anchor = Some(line_anchor(&content, document.start_byte));
+        }
+    }
     let embeddings = documents.iter().map(|_| vec![]).collect::<Vec<Vec<f32>>>();
 
     anyhow::Ok(FileContext {
         details,
         documents,
         embeddings,
+        strategy_version: Some(strategy.version()),
+        template_version: Some(options.template_version),
+        source: content,
     })
 }
 
@@ -119,14 +731,257 @@ pub(crate) fn parse_content(
     path: &PathBuf,
     content: &str,
     strategy: &ParsingStrategy,
+    options: &ParseOptions,
 ) -> anyhow::Result<Vec<ContextDocument>> {
     match strategy {
-        ParsingStrategy::TreeSitter { language, query } => parse_treesitter(
+        ParsingStrategy::TreeSitter {
+            language,
+            query,
+            wrap,
+            granularity,
+        } => parse_treesitter(
             content,
             language,
             query,
             path.to_str()
                 .ok_or(anyhow!("failed to parse path to string"))?,
+            *wrap,
+            *granularity,
+            options,
         ),
+        ParsingStrategy::Readme => anyhow::Ok(parse_readme(
+            content,
+            path.to_str()
+                .ok_or(anyhow!("failed to parse path to string"))?,
+            options.preprocessor.as_ref(),
+        )),
+        ParsingStrategy::Delimited {
+            format,
+            rows_per_document,
+        } => anyhow::Ok(parse_delimited(
+            content,
+            path.to_str()
+                .ok_or(anyhow!("failed to parse path to string"))?,
+            format,
+            *rows_per_document,
+            options.preprocessor.as_ref(),
+        )),
+        ParsingStrategy::PlainText {
+            chunk_bytes,
+            overlap_bytes,
+        } => anyhow::Ok(parse_plain_text(
+            content,
+            path.to_str()
+                .ok_or(anyhow!("failed to parse path to string"))?,
+            *chunk_bytes,
+            *overlap_bytes,
+            options.preprocessor.as_ref(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parsers::rust::rust_strategy;
+    use crate::semantic_index::DirectoryState;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_parse_file_skips_long_lines() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("minified.rs");
+        let content = format!("struct Minified {{ {} }}", "a: u8, ".repeat(5_000));
+        std::fs::write(&path, content).unwrap();
+
+        let details = FileDetails {
+            path,
+            directory_state: Arc::new(DirectoryState::new("id0".to_string())),
+            _permit: None,
+        };
+
+        let options = ParseOptions {
+            max_line_length: 1_000,
+            ..ParseOptions::default()
+        };
+
+        let parsed = parse_file(details, &rust_strategy(), &options).await;
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn test_chunk_granularity() {
+        let strategy = |granularity| ParsingStrategy::TreeSitter {
+            language: "rust".to_string(),
+            query: "(function_item) @item".to_string(),
+            wrap: true,
+            granularity,
+        };
+        let path = PathBuf::from("/tmp/foo.rs");
+        let content = "fn parse() {}\n\nfn tokenize() {}\n\nfn index() {}\n";
+        let parse = |granularity| {
+            parse_content(
+                &path,
+                content,
+                &strategy(granularity),
+                &ParseOptions::default(),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|document| (document.start_byte..document.end_byte, document.symbol_kind))
+            .collect::<Vec<(std::ops::Range<usize>, String)>>()
+        };
+
+        assert_eq!(parse(ChunkGranularity::Node).len(), 3);
+        assert_eq!(
+            parse(ChunkGranularity::WholeFile),
+            vec![(0..content.trim_end().len(), "source_file".to_string())]
+        );
+        assert_eq!(
+            parse(ChunkGranularity::FixedLines(2)),
+            vec![
+                (0..13, LINES_SYMBOL_KIND.to_string()),
+                (15..31, LINES_SYMBOL_KIND.to_string()),
+                (33..46, LINES_SYMBOL_KIND.to_string()),
+            ]
+        );
+
+        // The whole file is wrapped with the same preamble as individual nodes
+        let whole_file = parse_content(
+            &path,
+            content,
+            &strategy(ChunkGranularity::WholeFile),
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            whole_file[0].content,
+            format!(
+                "The below is a code snippet from the '/tmp/foo.rs' file.\n```rust\n{}\n```",
+                content.trim_end()
+            )
+        );
+
+        // Changing granularity changes the version, prompting files to be re-parsed, while
+        // node granularity keeps the version strategies had before granularity was added
+        assert_eq!(
+            strategy(ChunkGranularity::Node).version(),
+            get_sha("treesitter\nrust\n(function_item) @item\ntrue")
+        );
+        assert_ne!(
+            strategy(ChunkGranularity::Node).version(),
+            strategy(ChunkGranularity::WholeFile).version()
+        );
+        assert!(validate_strategy(&strategy(ChunkGranularity::FixedLines(0))).is_err());
+    }
+
+    #[test]
+    fn test_rust_tests_flagged() {
+        let strategy = ParsingStrategy::TreeSitter {
+            language: "rust".to_string(),
+            query: "(function_item) @item".to_string(),
+            wrap: true,
+            granularity: ChunkGranularity::Node,
+        };
+        let content = "fn parse() {}\n\n#[test]\nfn parses() {}\n\n#[cfg(test)]\nmod tests {\n    fn fixture() {}\n}\n";
+
+        let parsed = parse_content(
+            &PathBuf::from("/tmp/foo.rs"),
+            content,
+            &strategy,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        let flags = parsed
+            .iter()
+            .map(|document| {
+                let name = content[document.start_byte..document.end_byte]
+                    .trim_start_matches("fn ")
+                    .split('(')
+                    .next()
+                    .unwrap();
+                (name, document.is_test)
+            })
+            .collect::<Vec<(&str, bool)>>();
+        assert_eq!(
+            flags,
+            vec![("parse", false), ("parses", true), ("fixture", true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_line_anchor_relocates_shifted_span() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("foo.rs");
+        let content =
+            "struct Foo {}\n\nimpl Foo {\n    fn new() -> Self {\n        Foo {}\n    }\n}\n";
+        std::fs::write(&path, content).unwrap();
+
+        let details = FileDetails {
+            path,
+            directory_state: Arc::new(DirectoryState::new("id0".to_string())),
+            _permit: None,
+        };
+        let options = ParseOptions {
+            line_anchors: true,
+            ..ParseOptions::default()
+        };
+        let parsed = parse_file(details, &rust_strategy(), &options)
+            .await
+            .unwrap();
+        let document = parsed
+            .documents
+            .iter()
+            .find(|document| document.symbol_kind == "impl_item")
+            .unwrap();
+        let anchor = document.anchor.clone().unwrap();
+
+        // A blank line inserted above shifts the span, but leaves it unchanged
+        let shifted = format!("\n{content}");
+        assert_eq!(
+            locate_line_anchor(&shifted, &anchor),
+            Some(document.start_byte + 1)
+        );
+        assert_eq!(locate_line_anchor("struct Bar {}\n", &anchor), None);
+    }
+
+    #[test]
+    fn test_parse_readme() {
+        let content = "# auden\n\nyet another retrieval engine\n\n## Usage\n\nindex a directory\n";
+        let parsed = parse_content(
+            &PathBuf::from("/tmp/README.md"),
+            content,
+            &ParsingStrategy::Readme,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(
+            &content[parsed[0].start_byte..parsed[0].end_byte],
+            "# auden\n\nyet another retrieval engine"
+        );
+        assert_eq!(
+            &content[parsed[1].start_byte..parsed[1].end_byte],
+            "## Usage\n\nindex a directory"
+        );
+        assert!(parsed
+            .iter()
+            .all(|document| document.symbol_kind == README_SYMBOL_KIND));
+
+        // Sections are located by line as well as byte
+        assert_eq!(
+            parsed[1].start_position,
+            LinePosition { line: 4, column: 0 }
+        );
+        assert_eq!(
+            parsed[1].end_position,
+            LinePosition {
+                line: 6,
+                column: 17
+            }
+        );
     }
 }
@@ -0,0 +1,124 @@
+use crate::embedding::base::Embedding;
+
+/// An embedding scalar quantized to int8, stored with the scale and offset needed to
+/// approximately reconstruct the original values.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct QuantizedEmbedding {
+    pub(crate) values: Vec<i8>,
+    pub(crate) scale: f32,
+    pub(crate) offset: f32,
+}
+
+impl QuantizedEmbedding {
+    pub(crate) fn quantize(embedding: &[f32]) -> Self {
+        if embedding.is_empty() {
+            return QuantizedEmbedding {
+                values: vec![],
+                scale: 0.0,
+                offset: 0.0,
+            };
+        }
+
+        let min = embedding.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = embedding.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let scale = (max - min) / 255.0;
+        let values = embedding
+            .iter()
+            .map(|value| {
+                let step = if scale > 0.0 {
+                    ((value - min) / scale).round()
+                } else {
+                    0.0
+                };
+                (step - 128.0).clamp(-128.0, 127.0) as i8
+            })
+            .collect();
+
+        QuantizedEmbedding {
+            values,
+            scale,
+            offset: min,
+        }
+    }
+
+    pub(crate) fn dequantize(&self) -> Embedding {
+        self.values
+            .iter()
+            .map(|value| (*value as f32 + 128.0) * self.scale + self.offset)
+            .collect()
+    }
+}
+
+pub(crate) fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+pub(crate) fn dot_product(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+pub(crate) fn euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f32>()
+        .sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rank(query: &[f32], embeddings: &[Vec<f32>]) -> Vec<(usize, f32)> {
+        let mut ranked = embeddings
+            .iter()
+            .enumerate()
+            .map(|(idx, embedding)| (idx, cosine_similarity(query, embedding)))
+            .collect::<Vec<(usize, f32)>>();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+    }
+
+    #[test]
+    fn test_quantized_ranking_matches_full_precision() {
+        let embeddings = vec![
+            vec![0.9, 0.1, -0.2, 0.4, 0.0, -0.7],
+            vec![-0.5, 0.8, 0.3, -0.1, 0.6, 0.2],
+            vec![0.2, -0.9, 0.7, 0.1, -0.3, 0.5],
+            vec![0.1, 0.1, 0.1, 0.9, -0.8, 0.0],
+        ];
+        let query = vec![0.8, 0.2, -0.1, 0.5, 0.1, -0.6];
+
+        let dequantized = embeddings
+            .iter()
+            .map(|embedding| QuantizedEmbedding::quantize(embedding).dequantize())
+            .collect::<Vec<Vec<f32>>>();
+
+        let full = rank(&query, &embeddings);
+        let quantized = rank(&query, &dequantized);
+
+        assert_eq!(
+            full.iter().map(|(idx, _)| *idx).collect::<Vec<usize>>(),
+            quantized
+                .iter()
+                .map(|(idx, _)| *idx)
+                .collect::<Vec<usize>>()
+        );
+        for ((_, a), (_, b)) in full.iter().zip(quantized.iter()) {
+            assert!((a - b).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_quantize_constant_embedding() {
+        let quantized = QuantizedEmbedding::quantize(&[0.5, 0.5, 0.5]);
+        assert_eq!(quantized.dequantize(), vec![0.5, 0.5, 0.5]);
+    }
+}
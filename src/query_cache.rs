@@ -0,0 +1,89 @@
+use crate::embedding::base::{Embedding, EmbeddingProvider};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// The number of query embeddings held before the oldest are evicted.
+pub(crate) const DEFAULT_QUERY_CACHE_CAPACITY: usize = 256;
+
+/// Caches query embeddings, so repeated or prefetched queries skip the embedding provider.
+#[derive(Debug)]
+pub(crate) struct QueryEmbeddingCache {
+    capacity: usize,
+    entries: Mutex<(HashMap<String, Embedding>, VecDeque<String>)>,
+}
+
+impl QueryEmbeddingCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        QueryEmbeddingCache {
+            capacity,
+            entries: Mutex::new((HashMap::new(), VecDeque::new())),
+        }
+    }
+
+    pub(crate) fn get(&self, query: &str) -> Option<Embedding> {
+        let entries = self.entries.lock().unwrap();
+        entries.0.get(query).cloned()
+    }
+
+    /// Inserts the embedding, evicting the oldest queries once over capacity.
+    pub(crate) fn insert(&self, query: String, embedding: Embedding) {
+        let mut entries = self.entries.lock().unwrap();
+        let (embeddings, order) = &mut *entries;
+        if embeddings.insert(query.clone(), embedding).is_none() {
+            order.push_back(query);
+        }
+
+        while order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                embeddings.remove(&oldest);
+            }
+        }
+    }
+
+    /// Returns the cached embedding for the query, embedding and caching it on a miss.
+    pub(crate) async fn get_or_embed(
+        &self,
+        query: &str,
+        provider: &dyn EmbeddingProvider,
+    ) -> anyhow::Result<Embedding> {
+        if let Some(embedding) = self.get(query) {
+            return anyhow::Ok(embedding);
+        }
+
+        let embedding = provider.embed_query(query.to_string()).await?;
+        self.insert(query.to_string(), embedding.clone());
+        anyhow::Ok(embedding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedding::base::CountingEmbeddingProvider;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn test_prefetched_query_embedded_once() {
+        let provider = CountingEmbeddingProvider::default();
+        let cache = QueryEmbeddingCache::new(DEFAULT_QUERY_CACHE_CAPACITY);
+
+        // Prefetch, then search with the same query
+        cache.get_or_embed("parse file", &provider).await.unwrap();
+        let embedding = cache.get_or_embed("parse file", &provider).await.unwrap();
+
+        assert_eq!(embedding, vec![1.0, 0.0]);
+        assert_eq!(provider.queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_evicts_oldest_query() {
+        let cache = QueryEmbeddingCache::new(2);
+        cache.insert("a".to_string(), vec![1.0]);
+        cache.insert("b".to_string(), vec![2.0]);
+        cache.insert("c".to_string(), vec![3.0]);
+
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(vec![2.0]));
+        assert_eq!(cache.get("c"), Some(vec![3.0]));
+    }
+}
@@ -0,0 +1,261 @@
+use crate::db::SearchResult;
+use crate::parsers::strategy::{line_anchor, locate_line_anchor, LinePosition};
+use crate::quantization::cosine_similarity;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Reads the source content for each search result, returning an empty string for any span
+/// which can no longer be read.
+pub(crate) async fn read_span_contents(results: &[SearchResult]) -> Vec<String> {
+    let mut files = HashMap::<PathBuf, Option<Vec<u8>>>::new();
+    let mut contents = Vec::with_capacity(results.len());
+    for result in results {
+        if !files.contains_key(&result.path) {
+            let bytes = tokio::fs::read(&result.path).await.ok();
+            files.insert(result.path.clone(), bytes);
+        }
+
+        let content = files
+            .get(&result.path)
+            .and_then(|bytes| bytes.as_ref())
+            .and_then(|bytes| bytes.get(result.start_byte..result.end_byte))
+            .map(|span| String::from_utf8_lossy(span).to_string())
+            .unwrap_or_default();
+        contents.push(content);
+    }
+    contents
+}
+
+/// Moves anchored results whose file has shifted since it was indexed to the current location
+/// of their line anchor. Results which are still in place, or can't be found, are left as is.
+pub(crate) async fn relocate_anchored_spans(mut results: Vec<SearchResult>) -> Vec<SearchResult> {
+    let mut files = HashMap::<PathBuf, Option<String>>::new();
+    for result in results.iter_mut() {
+        let Some(anchor) = &result.anchor else {
+            continue;
+        };
+        if !files.contains_key(&result.path) {
+            let content = tokio::fs::read_to_string(&result.path).await.ok();
+            files.insert(result.path.clone(), content);
+        }
+        let Some(content) = files.get(&result.path).and_then(|content| content.as_ref()) else {
+            continue;
+        };
+
+        let in_place = content.is_char_boundary(result.start_byte)
+            && &line_anchor(content, result.start_byte) == anchor;
+        if in_place {
+            continue;
+        }
+
+        if let Some(start_byte) = locate_line_anchor(content, anchor) {
+            log::debug!(
+                "relocating span in {:?} from {} to {}",
+                result.path,
+                result.start_byte,
+                start_byte
+            );
+            result.end_byte = start_byte + (result.end_byte - result.start_byte);
+            result.start_byte = start_byte;
+
+            // Positions are moved along with the span, if the span still fits the file
+            if result.start_line.is_some() && result.end_byte <= content.len() {
+                let start = LinePosition::of_byte(content, result.start_byte);
+                let end = LinePosition::of_byte(content, result.end_byte);
+                result.start_line = Some(start.line);
+                result.start_col = Some(start.column);
+                result.end_line = Some(end.line);
+                result.end_col = Some(end.column);
+            }
+        }
+    }
+    results
+}
+
+/// The ratio of unique tokens to total tokens, generated or repetitive content tends to score
+/// lower than hand written code.
+pub(crate) fn token_diversity(content: &str) -> f32 {
+    let tokens = content
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|token| !token.is_empty())
+        .collect::<Vec<&str>>();
+    if tokens.is_empty() {
+        return 0.0;
+    }
+
+    let unique = tokens.iter().collect::<HashSet<&&str>>().len();
+    unique as f32 / tokens.len() as f32
+}
+
+/// Reranks results, penalizing low diversity spans by `weight` scaled by how repetitive they are.
+pub(crate) fn penalize_low_entropy(
+    results: Vec<SearchResult>,
+    contents: &[String],
+    weight: f32,
+) -> Vec<SearchResult> {
+    let mut scored = results
+        .into_iter()
+        .zip(contents)
+        .map(|(result, content)| {
+            let score = result.similarity - weight * (1.0 - token_diversity(content));
+            (score, result)
+        })
+        .collect::<Vec<(f32, SearchResult)>>();
+
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Reranks results, boosting the score of spans whose file path contains `path_query`.
+pub(crate) fn boost_matching_paths(
+    results: Vec<SearchResult>,
+    path_query: &str,
+    boost: f32,
+) -> Vec<SearchResult> {
+    let mut scored = results
+        .into_iter()
+        .map(|result| {
+            let matches = result.path.to_string_lossy().contains(path_query);
+            let score = if matches {
+                result.similarity + boost
+            } else {
+                result.similarity
+            };
+            (score, result)
+        })
+        .collect::<Vec<(f32, SearchResult)>>();
+
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Reranks results, boosting the score of spans with the given symbol kind.
+pub(crate) fn boost_symbol_kind(
+    results: Vec<SearchResult>,
+    symbol_kind: &str,
+    boost: f32,
+) -> Vec<SearchResult> {
+    let mut scored = results
+        .into_iter()
+        .map(|result| {
+            let matches = result.symbol_kind.as_deref() == Some(symbol_kind);
+            let score = if matches {
+                result.similarity + boost
+            } else {
+                result.similarity
+            };
+            (score, result)
+        })
+        .collect::<Vec<(f32, SearchResult)>>();
+
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().map(|(_, result)| result).collect()
+}
+
+/// The byte ranges of each non blank line in the content.
+pub(crate) fn line_ranges(content: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_end_matches(['\n', '\r']);
+        if !trimmed.trim().is_empty() {
+            ranges.push(start..start + trimmed.len());
+        }
+        start += line.len();
+    }
+    ranges
+}
+
+/// Picks the line range whose embedding is most similar to the query embedding.
+pub(crate) fn best_line(
+    query_embedding: &[f32],
+    ranges: &[Range<usize>],
+    line_embeddings: &[Vec<f32>],
+) -> Option<Range<usize>> {
+    ranges
+        .iter()
+        .zip(line_embeddings)
+        .map(|(range, embedding)| (cosine_similarity(query_embedding, embedding), range))
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, range)| range.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use surrealdb::sql::Thing;
+
+    fn search_result(path: &str, similarity: f32) -> SearchResult {
+        SearchResult {
+            id: Thing::from(("span", path)),
+            path: PathBuf::from(path),
+            start_byte: 0,
+            end_byte: 10,
+            start_line: None,
+            start_col: None,
+            end_line: None,
+            end_col: None,
+            similarity,
+            symbol_kind: None,
+            highlight: None,
+            anchor: None,
+            content: None,
+        }
+    }
+
+    #[test]
+    fn test_low_entropy_span_ranked_lower() {
+        let results = vec![
+            search_result("/tmp/mod.rs", 0.8),
+            search_result("/tmp/parser.rs", 0.8),
+        ];
+        let contents = vec![
+            "pub mod a; pub mod a; pub mod a; pub mod a;".to_string(),
+            "fn parse(content: &str) -> Result<Tree> { parser.parse(content) }".to_string(),
+        ];
+
+        let reranked = penalize_low_entropy(results, &contents, 0.5);
+        assert_eq!(reranked[0].path, PathBuf::from("/tmp/parser.rs"));
+        assert_eq!(reranked[1].path, PathBuf::from("/tmp/mod.rs"));
+    }
+
+    #[test]
+    fn test_path_query_boost() {
+        let results = vec![
+            search_result("/repo/auth/login.rs", 0.8),
+            search_result("/repo/billing/auth.rs", 0.8),
+        ];
+
+        let reranked = boost_matching_paths(results, "billing", 0.1);
+        assert_eq!(reranked[0].path, PathBuf::from("/repo/billing/auth.rs"));
+        assert_eq!(reranked[1].path, PathBuf::from("/repo/auth/login.rs"));
+    }
+
+    #[test]
+    fn test_best_line_highlight() {
+        let content = "fn parse(content: &str) {\n\n    let tree = parser.parse(content);\n}";
+        let ranges = line_ranges(content);
+        assert_eq!(ranges.len(), 3);
+
+        let line_embeddings = vec![vec![0.5, 0.5], vec![1.0, 0.0], vec![0.0, 1.0]];
+        let highlight = best_line(&[0.9, 0.1], &ranges, &line_embeddings).unwrap();
+
+        assert!(highlight.end <= content.len());
+        assert_eq!(&content[highlight], "    let tree = parser.parse(content);");
+    }
+
+    #[test]
+    fn test_readme_boost() {
+        let results = vec![
+            search_result("/repo/src/lib.rs", 0.8),
+            SearchResult {
+                symbol_kind: Some("readme".to_string()),
+                ..search_result("/repo/README.md", 0.75)
+            },
+        ];
+
+        let reranked = boost_symbol_kind(results, "readme", 0.1);
+        assert_eq!(reranked[0].path, PathBuf::from("/repo/README.md"));
+    }
+}
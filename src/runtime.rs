@@ -0,0 +1,76 @@
+use tokio::runtime::Runtime;
+
+/// The stack size given to each runtime worker thread, in release builds.
+pub const STACK_SIZE: usize = 10 * 1024 * 1024;
+
+/// Builds a multi threaded tokio runtime able to drive a `SemanticIndex`.
+///
+/// SurrealDB recursive queries can overflow the default worker thread stack, so workers are
+/// given `STACK_SIZE`, doubled in debug builds where stack frames are generally larger.
+/// https://github.com/surrealdb/surrealdb/issues/2920
+pub fn build_runtime() -> std::io::Result<Runtime> {
+    let stack_size = STACK_SIZE;
+
+    #[cfg(debug_assertions)]
+    let stack_size = stack_size * 2;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_stack_size(stack_size)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedding::base::FakeEmbeddingProvider;
+    use crate::semantic_index::{DatabaseOptions, SemanticIndex};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    async fn _test_build_runtime() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        // Enough nested directories and files for the search to recurse through
+        let directory = tempdir().unwrap();
+        let mut nested = directory.path().to_path_buf();
+        for depth in 0..10 {
+            nested = nested.join(format!("depth{depth}"));
+            std::fs::create_dir(&nested).unwrap();
+            for i in 0..5 {
+                std::fs::write(
+                    nested.join(format!("foo{i}.rs")),
+                    format!("struct CodeContextParser{i} {{}}\n"),
+                )
+                .unwrap();
+            }
+        }
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(30), notify.notified())
+            .await
+            .unwrap();
+
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 50, "parser")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 50);
+    }
+
+    #[test]
+    fn test_build_runtime() {
+        build_runtime().unwrap().block_on(_test_build_runtime())
+    }
+}
@@ -1,28 +1,102 @@
-use crate::db::{SearchResult, VectorDatabase};
-use crate::embedding_queue::{EmbeddingJob, EmbeddingQueue};
+pub use crate::db::{
+    ContentStorage, DatabaseOptions, EmbeddingStorage, SearchCursor, SearchPage, SearchResult,
+    SimilarityMetric, TestFilter,
+};
+pub use crate::embedding_queue::SanitizeOptions;
+pub use crate::parsers::plain_text::DEFAULT_PLAIN_TEXT_WINDOW;
+
+use crate::db::{VectorDatabase, YIELD_INTERVAL};
+use crate::directory_watcher::DirectoryWatcher;
+use crate::embedding::base::{Embedding, EmbeddingProvider};
+use crate::embedding::cache::EmbeddingCache;
+use crate::embedding_queue::{
+    EmbeddingJob, EmbeddingQueue, RetryPolicy, DEFAULT_BREAKER_COOLDOWN,
+    DEFAULT_BREAKER_FAILURE_THRESHOLD, DEFAULT_EMBEDDING_BATCH_SIZE, DEFAULT_MAX_QUEUED_BYTES,
+};
+use crate::parsers::preprocessor::ContentPreprocessor;
 use crate::parsers::registry::{load_extensions, ExtensionRegistry};
-use crate::parsers::strategy::{parse_file, ParsingStrategy};
+use crate::parsers::strategy::{
+    get_sha, parse_file, ParseOptions, ParsingStrategy, README_SYMBOL_KIND,
+    UNRECORDED_TEMPLATE_VERSION,
+};
+use crate::query_cache::{QueryEmbeddingCache, DEFAULT_QUERY_CACHE_CAPACITY};
+use crate::rerank::{
+    best_line, boost_matching_paths, boost_symbol_kind, line_ranges, penalize_low_entropy,
+    read_span_contents, relocate_anchored_spans,
+};
 use anyhow::anyhow;
-use llm_chain::traits::Embeddings;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use futures::StreamExt;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, watch, Mutex, Notify};
+use surrealdb::opt::RecordId;
+use tokio::sync::{
+    broadcast, mpsc, oneshot, watch, Mutex, Notify, OwnedSemaphorePermit, Semaphore,
+};
+use tokio::task::JoinHandle;
 use tokio::time::Duration;
-use walkdir::{DirEntry, WalkDir};
 
 #[derive(Debug, Clone)]
 pub(crate) struct FileDetails {
     pub(crate) path: PathBuf,
     pub(crate) directory_state: Arc<DirectoryState>,
+    /// Held for as long as the file is in the pipeline, released once it has been written.
+    pub(crate) _permit: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+/// A file queued for parsing, with its strategy, the embeddings already stored for its
+/// directory keyed by sha, and the options to parse it with.
+pub(crate) type ParseJob = Arc<(
+    FileDetails,
+    ParsingStrategy,
+    Arc<HashMap<Vec<u8>, Vec<f32>>>,
+    ParseOptions,
+)>;
+
+/// Queues the file for parsing, failing if the parse task has stopped, as it does once the
+/// embedding task has. The file was counted as a job for its directory, so is dropped on
+/// failure rather than holding the directory in indexing.
+pub(crate) async fn queue_parse_job(
+    parse_sender: &mpsc::Sender<ParseJob>,
+    job: ParseJob,
+) -> anyhow::Result<()> {
+    let directory_state = job.0.directory_state.clone();
+    parse_sender.send(job).await.map_err(|_| {
+        directory_state.job_dropped();
+        anyhow!("indexing pipeline has stopped, files can no longer be parsed or embedded")
+    })
 }
 
+/// The maximum number of files which can be parsed but not yet written at once.
+const DEFAULT_MAX_IN_FLIGHT_FILES: usize = 1000;
+
+/// The maximum number of directories which can be walking or embedding at once.
+const DEFAULT_MAX_CONCURRENT_DIRECTORIES: usize = 4;
+
+/// The number of results fetched per page when streaming search results.
+const STREAM_PAGE_SIZE: usize = 10;
+
+/// The number of status updates buffered ahead of a slow watcher.
+const STATUS_BUFFER: usize = 16;
+
+/// How long the embedding queue waits for more files before flushing a partial batch.
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+
 #[derive(Debug)]
 pub(crate) struct DirectoryState {
     pub(crate) id: String,
     pub(crate) job_count_tx: watch::Sender<usize>,
     pub(crate) job_count_rx: watch::Receiver<usize>,
     pub(crate) notify: Arc<Notify>,
+    /// Held while the directory is actively walking or embedding, limiting how many
+    /// directories can be indexed at once.
+    slot: std::sync::Mutex<Option<OwnedSemaphorePermit>>,
+    /// Set once indexing is cancelled, after which the directory's outstanding jobs are
+    /// dropped as they reach each stage of the pipeline.
+    cancelled: AtomicBool,
 }
 
 impl DirectoryState {
@@ -34,25 +108,64 @@ impl DirectoryState {
             job_count_tx,
             job_count_rx,
             notify,
+            slot: std::sync::Mutex::new(None),
+            cancelled: AtomicBool::new(false),
         }
     }
 
+    /// Cancels indexing, releasing the directory's slot and waking anything waiting on the
+    /// directory, as jobs which are dropped before being parsed are never counted.
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.release_slot();
+        self.notify.notify_one();
+
+        // Wake anything watching the job count, so it sees the cancellation
+        self.job_count_tx.send_modify(|_| {});
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn hold_slot(&self, permit: OwnedSemaphorePermit) {
+        *self.slot.lock().unwrap() = Some(permit);
+    }
+
+    pub(crate) fn release_slot(&self) {
+        self.slot.lock().unwrap().take();
+    }
+
     pub fn new_job(&self) {
-        let current_count = self.job_count_rx.borrow().clone();
-        self.job_count_tx.send_replace(current_count + 1);
+        // Counts are updated in place, as a separate read and write could lose concurrent
+        // updates from the parse and embedding tasks
+        self.job_count_tx.send_modify(|count| *count += 1);
     }
 
     pub fn job_dropped(&self) {
-        let current_count = self.job_count_rx.borrow().clone();
-        let new_count = current_count - 1;
-        self.job_count_tx.send_replace(new_count);
+        let mut new_count = 0;
+        self.job_count_tx.send_modify(|count| {
+            if *count == 0 {
+                log::warn!(
+                    "job dropped for directory {} with no outstanding jobs",
+                    self.id
+                );
+            }
+            *count = count.saturating_sub(1);
+            new_count = *count;
+        });
 
         if new_count == 0 {
+            self.release_slot();
             self.notify.notify_one();
         }
     }
 
     pub fn status(&self) -> IndexingStatus {
+        if self.is_cancelled() {
+            return IndexingStatus::Cancelled;
+        }
+
         let jobs_outstanding = self.job_count_rx.borrow().clone();
         if jobs_outstanding == 0 {
             IndexingStatus::Indexed
@@ -62,19 +175,144 @@ impl DirectoryState {
     }
 }
 
+/// Returned when indexing a directory which is still being indexed.
+#[derive(Debug)]
+pub struct AlreadyIndexing {
+    pub directory: PathBuf,
+}
+
+impl std::fmt::Display for AlreadyIndexing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "directory {:?} is already being indexed", self.directory)
+    }
+}
+
+impl std::error::Error for AlreadyIndexing {}
+
+/// A summary of the work done indexing a single directory.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexSummary {
+    pub files_queued: usize,
+    pub files_removed: usize,
+    /// Files left as indexed, as their strategy is unchanged, when indexed with
+    /// `IndexOptions::reparse_if_strategy_changed`.
+    pub files_skipped: usize,
+}
+
+/// Directories skipped by default when indexing, which typically hold vendored dependencies,
+/// build output or caches rather than a project's own code.
+pub const DEFAULT_EXCLUDED_DIRECTORIES: [&str; 5] =
+    ["node_modules", "vendor", "dist", ".venv", "__pycache__"];
+
+/// The default size above which files are skipped when indexing, as source files are rarely
+/// this large, whereas minified bundles and generated files can be far larger.
+pub const DEFAULT_MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Options controlling which files are parsed by `index_directory_with_options`.
+#[derive(Debug, Clone)]
+pub struct IndexOptions {
+    /// Only re-parse files whose spans were parsed with a different version of their parsing
+    /// strategy, such as after a language's query is edited, along with any new files. Files
+    /// parsed with the current strategy are skipped, even if their content has changed.
+    pub reparse_if_strategy_changed: bool,
+    /// Walk hidden files and directories, which are skipped by default. Files excluded by
+    /// `.gitignore`, `.ignore` or global gitignore rules are skipped either way.
+    pub include_hidden: bool,
+    /// Glob patterns, matched against paths relative to the directory, a file must match one
+    /// of to be indexed. Empty includes every file with a supported extension.
+    pub include: Vec<String>,
+    /// Glob patterns, matched against paths relative to the directory, excluding any file
+    /// matching one from the index, even if it is included, such as `**/*.min.js`. Directories
+    /// matching one, such as `**/generated`, are not walked at all.
+    pub exclude: Vec<String>,
+    /// Names of directories skipped wherever they appear beneath the directory, without being
+    /// walked. Defaults to `DEFAULT_EXCLUDED_DIRECTORIES`, clear it to index them.
+    pub excluded_directories: Vec<String>,
+    /// Files larger than this many bytes are skipped rather than read and parsed, and removed
+    /// from the index if previously indexed. Defaults to `DEFAULT_MAX_FILE_BYTES`, `None`
+    /// indexes files of any size.
+    pub max_file_bytes: Option<u64>,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        IndexOptions {
+            reparse_if_strategy_changed: false,
+            include_hidden: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            excluded_directories: DEFAULT_EXCLUDED_DIRECTORIES
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            max_file_bytes: Some(DEFAULT_MAX_FILE_BYTES),
+        }
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    anyhow::Ok(builder.build()?)
+}
+
+/// A summary of the spans re-embedded by `reembed_directory`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReembedSummary {
+    pub files_reembedded: usize,
+    pub spans_reembedded: usize,
+}
+
+/// Events reported by long running index operations, received through
+/// `SemanticIndex::subscribe_events`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexEvent {
+    /// Reported after each file is re-embedded, with the running count of spans re-embedded
+    /// out of the total for the directory.
+    ReembedProgress {
+        directory: PathBuf,
+        spans_reembedded: usize,
+        total: usize,
+    },
+    /// Reported once a file's spans have been written to the index.
+    FileWritten { path: PathBuf },
+    /// Reported when a file parses into more spans than `max_spans_per_file`, so only the
+    /// first spans of the file are indexed.
+    SpansTruncated {
+        path: PathBuf,
+        spans: usize,
+        max_spans_per_file: usize,
+    },
+}
+
+/// The number of events buffered for each subscriber before the oldest are dropped.
+const EVENT_CAPACITY: usize = 1000;
+
 #[derive(Debug)]
 pub enum IndexingStatus {
-    Indexing { jobs_outstanding: usize },
+    Indexing {
+        jobs_outstanding: usize,
+    },
+    /// Indexing with embedding paused, outstanding files stay queued until it is resumed.
+    Paused {
+        jobs_outstanding: usize,
+    },
     Indexed,
     NotIndexed,
+    /// Indexing was cancelled, files written before cancellation are kept.
+    Cancelled,
 }
 
 impl ToString for IndexingStatus {
     fn to_string(&self) -> String {
         match self {
             IndexingStatus::Indexing { .. } => "Indexing",
+            IndexingStatus::Paused { .. } => "Paused",
             IndexingStatus::Indexed => "Indexed",
             IndexingStatus::NotIndexed => "Not Indexed",
+            IndexingStatus::Cancelled => "Cancelled",
         }
         .to_string()
     }
@@ -83,70 +321,379 @@ impl ToString for IndexingStatus {
 impl IndexingStatus {
     pub fn outstanding(&self) -> Option<usize> {
         match self {
-            IndexingStatus::Indexing { jobs_outstanding } => Some(*jobs_outstanding),
+            IndexingStatus::Indexing { jobs_outstanding }
+            | IndexingStatus::Paused { jobs_outstanding } => Some(*jobs_outstanding),
             _ => None,
         }
     }
 }
 
-pub struct SemanticIndex {
-    vector_db: VectorDatabase,
-    parsers: ExtensionRegistry,
-    parse_sender: mpsc::Sender<
-        Arc<(
-            FileDetails,
-            ParsingStrategy,
-            Arc<HashMap<Vec<u8>, Vec<f32>>>,
-        )>,
-    >,
-    directory_state: HashMap<PathBuf, Arc<DirectoryState>>,
-    embedding_provider: Arc<llm_chain_openai::embeddings::Embeddings>,
+/// Options controlling how results are selected and post-processed in `search_directory`.
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// Drop results whose file no longer exists on disk, which can happen if a file is
+    /// deleted before the directory is re-indexed.
+    pub filter_missing_files: bool,
+    /// When set, down-weights low entropy spans such as generated code or re-exports, by up
+    /// to the given weight subtracted from the similarity.
+    pub entropy_penalty: Option<f32>,
+    /// When set, boosts spans whose file path contains the given substring.
+    pub path_query: Option<String>,
+    /// Locate the most relevant line within each result, by embedding each line individually.
+    /// This embeds every line of every result, so is considerably more expensive.
+    pub highlight_lines: bool,
+    /// Relocate results indexed with line anchors to where their span now starts, for files
+    /// edited since they were indexed. This reads the file of every anchored result.
+    pub relocate_spans: bool,
+    /// Restricts results to, or away from, spans flagged as tests when indexed.
+    pub test_filter: TestFilter,
+    /// Read each result's span from its file, returning its text in `SearchResult::content`.
+    /// This reads the file of every result, so is skipped unless requested. Results already
+    /// carrying content from `ContentStorage::Blob` are not read.
+    pub with_content: bool,
+    /// Drops results scoring worse than this, so queries without a good match return nothing
+    /// rather than noise. For distance metrics this is the maximum distance.
+    pub min_similarity: Option<f32>,
+    /// The metric results are scored and ranked by. The entropy penalty and boosts assume
+    /// higher scores are better, so are skipped for distance metrics.
+    pub metric: SimilarityMetric,
+    /// A term to steer results away from, such as "tests" when searching for "database code
+    /// but not tests". Its embedding is subtracted from the query's, penalizing spans in
+    /// proportion to their similarity to the term.
+    pub negative: Option<String>,
+    /// The order the selected results are returned in. The top `n` are always selected by
+    /// similarity, and only then reordered.
+    pub sort: SortOrder,
 }
 
-impl SemanticIndex {
-    pub async fn new(database_dir: PathBuf) -> anyhow::Result<Self> {
-        let embedding_provider = Arc::new(llm_chain_openai::embeddings::Embeddings::default());
+/// The order search results are returned in.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SortOrder {
+    /// Most relevant first.
+    #[default]
+    Relevance,
+    /// Grouped by file, in the order spans appear within it, for reading results in context.
+    FileThenPosition,
+}
+
+/// Describes a similarity metric available for search, so clients can interpret its scores.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricInfo {
+    pub name: String,
+    pub higher_is_better: bool,
+}
+
+/// The boost added to the similarity of spans matching `SearchOptions::path_query`.
+const PATH_QUERY_BOOST: f32 = 0.1;
+
+/// The weight the embedding of `SearchOptions::negative` is subtracted from the query's with.
+const NEGATIVE_QUERY_WEIGHT: f32 = 0.5;
+
+/// The boost added to the similarity of spans from an indexed README.
+const README_BOOST: f32 = 0.05;
+
+/// Counts the results for each symbol kind, for faceted filtering of search results.
+pub fn symbol_kind_facets(results: &[SearchResult]) -> HashMap<String, usize> {
+    let mut facets = HashMap::new();
+    for result in results {
+        if let Some(symbol_kind) = &result.symbol_kind {
+            *facets.entry(symbol_kind.clone()).or_insert(0) += 1;
+        }
+    }
+    facets
+}
+
+/// Subtracts the weighted negative embedding from the query embedding, so that a span's
+/// similarity to the result is lowered by its similarity to the negative.
+fn exclude_negative(query: &[f32], negative: &[f32], weight: f32) -> Vec<f32> {
+    query
+        .iter()
+        .zip(negative)
+        .map(|(query, negative)| query - weight * negative)
+        .collect()
+}
+
+async fn filter_missing_files(results: Vec<SearchResult>) -> Vec<SearchResult> {
+    let mut filtered = Vec::with_capacity(results.len());
+    for result in results {
+        if tokio::fs::try_exists(&result.path).await.unwrap_or(false) {
+            filtered.push(result);
+        } else {
+            log::debug!("filtering search result for missing file {:?}", result.path);
+        }
+    }
+    filtered
+}
+
+fn find_directory_state<'a>(
+    directory_states: &'a HashMap<PathBuf, Arc<DirectoryState>>,
+    directory: &PathBuf,
+) -> Option<&'a Arc<DirectoryState>> {
+    directory
+        .ancestors()
+        .find_map(|ancestor| directory_states.get(ancestor))
+}
+
+type DirectoryStates = Arc<std::sync::Mutex<HashMap<PathBuf, Arc<DirectoryState>>>>;
+
+/// Cancels indexing independently of the index, so indexing can be cancelled while a long
+/// running directory walk holds the index.
+#[derive(Clone)]
+pub struct IndexCanceller {
+    directory_state: DirectoryStates,
+}
+
+impl IndexCanceller {
+    /// Cancels indexing of the directory, dropping its outstanding jobs. Files already written
+    /// are kept, and the directory reports `Cancelled` until it is indexed again.
+    pub fn cancel(&self, directory: &PathBuf) -> anyhow::Result<()> {
+        let directory_states = self.directory_state.lock().unwrap();
+        let directory_state = directory_states
+            .get(directory)
+            .ok_or(anyhow!("directory {:?} has not been indexed", directory))?;
+        directory_state.cancel();
+        anyhow::Ok(())
+    }
+}
+
+/// Builds a `SemanticIndex`, for configuring settings which are fixed once the index is
+/// created, such as the embedding provider and how spans are batched for embedding.
+pub struct SemanticIndexBuilder {
+    database_dir: PathBuf,
+    embedding_provider: Option<Arc<dyn EmbeddingProvider>>,
+    database_options: DatabaseOptions,
+    embedding_batch_size: usize,
+    flush_interval: Duration,
+    max_queued_bytes: usize,
+    sanitize: Option<SanitizeOptions>,
+    embedding_retry: RetryPolicy,
+    breaker_failure_threshold: usize,
+    breaker_cooldown: Duration,
+    embedding_cache: Option<Arc<dyn EmbeddingCache>>,
+    max_in_flight_files: usize,
+    max_concurrent_directories: usize,
+    index_readme: bool,
+}
+
+impl SemanticIndexBuilder {
+    fn new(database_dir: PathBuf) -> Self {
+        SemanticIndexBuilder {
+            database_dir,
+            embedding_provider: None,
+            database_options: DatabaseOptions::default(),
+            embedding_batch_size: DEFAULT_EMBEDDING_BATCH_SIZE,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            max_queued_bytes: DEFAULT_MAX_QUEUED_BYTES,
+            sanitize: None,
+            embedding_retry: RetryPolicy::default(),
+            breaker_failure_threshold: DEFAULT_BREAKER_FAILURE_THRESHOLD,
+            breaker_cooldown: DEFAULT_BREAKER_COOLDOWN,
+            embedding_cache: None,
+            max_in_flight_files: DEFAULT_MAX_IN_FLIGHT_FILES,
+            max_concurrent_directories: DEFAULT_MAX_CONCURRENT_DIRECTORIES,
+            index_readme: false,
+        }
+    }
+
+    /// Embeds with the given provider, rather than OpenAI.
+    pub fn embedding_provider(mut self, embedding_provider: Arc<dyn EmbeddingProvider>) -> Self {
+        self.embedding_provider = Some(embedding_provider);
+        self
+    }
+
+    pub fn database_options(mut self, database_options: DatabaseOptions) -> Self {
+        self.database_options = database_options;
+        self
+    }
+
+    /// Sets the number of spans sent to the embedding provider in each request.
+    pub fn embedding_batch_size(mut self, embedding_batch_size: usize) -> Self {
+        self.embedding_batch_size = embedding_batch_size.max(1);
+        self
+    }
+
+    /// Sets how long the embedding queue waits for more files before flushing a partial
+    /// batch.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Sets the budget for span content held in the embedding queue, above which a batch is
+    /// flushed early.
+    pub fn max_queued_bytes(mut self, max_queued_bytes: usize) -> Self {
+        self.max_queued_bytes = max_queued_bytes;
+        self
+    }
+
+    /// Sets how span content is sanitized before it is sent to the embedding provider, or
+    /// sends it as is if `None`, the default. Pass `Some(SanitizeOptions::default())` for the
+    /// default limits.
+    pub fn sanitize_embedding_input(mut self, sanitize: Option<SanitizeOptions>) -> Self {
+        self.sanitize = sanitize;
+        self
+    }
+
+    /// Sets how many times a failed embedding request is attempted before its batch is
+    /// re-queued, and the delay before the first retry, which doubles for each further
+    /// attempt. Defaults to 3 attempts from a 500ms delay.
+    pub fn embedding_retry(mut self, max_attempts: usize, base_delay: Duration) -> Self {
+        self.embedding_retry = RetryPolicy {
+            max_attempts,
+            base_delay,
+        };
+        self
+    }
+
+    /// Sets how many batches must fail in a row, once retried, before the embedding provider
+    /// is considered down. Batches are then failed without calling it, dropping their files,
+    /// until the cooldown elapses and a single batch probes it again. Defaults to 5 batches
+    /// and a 30s cooldown.
+    pub fn embedding_circuit_breaker(
+        mut self,
+        failure_threshold: usize,
+        cooldown: Duration,
+    ) -> Self {
+        self.breaker_failure_threshold = failure_threshold;
+        self.breaker_cooldown = cooldown;
+        self
+    }
+
+    /// Consults the cache for each span's embedding before sending it to the embedding
+    /// provider, such as a `DiskEmbeddingCache` persisting embeddings across directories and
+    /// restarts.
+    pub fn embedding_cache(mut self, embedding_cache: Arc<dyn EmbeddingCache>) -> Self {
+        self.embedding_cache = Some(embedding_cache);
+        self
+    }
+
+    /// See `SemanticIndex::set_max_in_flight_files`.
+    pub fn max_in_flight_files(mut self, max_in_flight_files: usize) -> Self {
+        self.max_in_flight_files = max_in_flight_files.max(1);
+        self
+    }
+
+    /// See `SemanticIndex::set_max_concurrent_directories`.
+    pub fn max_concurrent_directories(mut self, max_concurrent_directories: usize) -> Self {
+        self.max_concurrent_directories = max_concurrent_directories.max(1);
+        self
+    }
+
+    /// See `SemanticIndex::set_index_readme`.
+    pub fn index_readme(mut self, index_readme: bool) -> Self {
+        self.index_readme = index_readme;
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<SemanticIndex> {
+        let embedding_provider = self
+            .embedding_provider
+            .clone()
+            .unwrap_or_else(|| Arc::new(llm_chain_openai::embeddings::Embeddings::default()));
 
         let (embedding_sender, mut embedding_receiver) = mpsc::channel::<EmbeddingJob>(10000);
+        let drain_sender = embedding_sender.clone();
+
+        let events = broadcast::channel(EVENT_CAPACITY).0;
 
         // Create a long-lived background task, which parses files
-        let (parse_sender, mut parse_receiver) = mpsc::channel::<
-            Arc<(
-                FileDetails,
-                ParsingStrategy,
-                Arc<HashMap<Vec<u8>, Vec<f32>>>,
-            )>,
-        >(10000);
+        let (parse_sender, mut parse_receiver) = mpsc::channel::<ParseJob>(10000);
+        let parse_events = events.clone();
         tokio::spawn(async move {
-            while let Some(file_to_parse) = parse_receiver.recv().await {
-                if let Ok(mut context) = parse_file(file_to_parse.0.clone(), &file_to_parse.1) {
-                    context.details.directory_state.new_job();
-
-                    // Update embeddings if the shas are already available
-                    for (idx, document) in context.documents.iter().enumerate() {
-                        if let Some(embedding) = file_to_parse.2.get(&document.sha) {
-                            context.embeddings[idx] = embedding.clone();
-                        }
+            let mut parsed = 0;
+            loop {
+                // Parsing stops once the embedding task has, so that further index calls fail
+                // rather than parsing files which can never be embedded
+                let file_to_parse = tokio::select! {
+                    file_to_parse = parse_receiver.recv() => file_to_parse,
+                    _ = embedding_sender.closed() => {
+                        log::error!("embedding task has stopped, no further files can be indexed");
+                        None
                     }
+                };
+                let Some(file_to_parse) = file_to_parse else {
+                    break;
+                };
+
+                parsed += 1;
+                if parsed % YIELD_INTERVAL == 0 {
+                    tokio::task::yield_now().await;
+                }
+
+                // Files are counted as jobs when queued, so those never parsed must be dropped
+                let directory_state = file_to_parse.0.directory_state.clone();
+                if directory_state.is_cancelled() {
+                    directory_state.job_dropped();
+                    continue;
+                }
+
+                match parse_file(file_to_parse.0.clone(), &file_to_parse.1, &file_to_parse.3).await
+                {
+                    Ok(mut context) => {
+                        let max_spans_per_file = file_to_parse.3.max_spans_per_file;
+                        if let Some(spans) = context.truncate_spans(max_spans_per_file) {
+                            log::warn!(
+                                "truncating {:?} to {} of its {} spans",
+                                context.details.path,
+                                max_spans_per_file,
+                                spans
+                            );
+                            let _ = parse_events.send(IndexEvent::SpansTruncated {
+                                path: context.details.path.clone(),
+                                spans,
+                                max_spans_per_file,
+                            });
+                        }
+
+                        // Update embeddings if the shas are already available
+                        for (idx, document) in context.documents.iter().enumerate() {
+                            if let Some(embedding) = file_to_parse.2.get(&document.sha) {
+                                context.embeddings[idx] = embedding.clone();
+                            }
+                        }
 
-                    let _ = embedding_sender
-                        .send(EmbeddingJob::Embed {
-                            file_context: Arc::new(Mutex::new(context)),
-                        })
-                        .await;
+                        let sent = embedding_sender
+                            .send(EmbeddingJob::Embed {
+                                file_context: Arc::new(Mutex::new(context)),
+                            })
+                            .await;
+                        if sent.is_err() {
+                            log::error!(
+                                "embedding task has stopped, no further files can be indexed"
+                            );
+                            break;
+                        }
+                    }
+                    Err(_) => directory_state.job_dropped(),
                 }
             }
+
+            // Files still queued were counted as jobs, so are dropped to let their directories
+            // complete
+            parse_receiver.close();
+            while let Ok(file_to_parse) = parse_receiver.try_recv() {
+                file_to_parse.0.directory_state.job_dropped();
+            }
         });
 
         // Create a long-lived background task, which queues files for embedding
-        let mut embedding_queue = EmbeddingQueue::new(embedding_provider.clone());
+        let mut embedding_queue =
+            EmbeddingQueue::new(embedding_provider.clone(), self.max_queued_bytes)
+                .with_batch_size(self.embedding_batch_size)
+                .with_sanitize(self.sanitize.clone())
+                .with_retry(
+                    self.embedding_retry.max_attempts,
+                    self.embedding_retry.base_delay,
+                )
+                .with_circuit_breaker(self.breaker_failure_threshold, self.breaker_cooldown)
+                .with_cache(self.embedding_cache.clone());
         let mut long_lived_embedding_queue = embedding_queue.clone(); // I dont really like this
+        let embedding_paused = embedding_queue.paused();
+        let flush_interval = self.flush_interval;
         tokio::spawn(async move {
             let mut new_values = false;
             loop {
-                match tokio::time::timeout(Duration::from_millis(250), embedding_receiver.recv())
-                    .await
-                {
+                match tokio::time::timeout(flush_interval, embedding_receiver.recv()).await {
                     Ok(embedding_job) => {
                         new_values = true;
                         if let Some(embedding_job) = embedding_job {
@@ -165,15 +712,30 @@ impl SemanticIndex {
 
         // Create a long-lived background task, which gets finished files and writes them to the
         // database
-        let vector_db = VectorDatabase::initialize(database_dir).await?;
+        let vector_db =
+            VectorDatabase::initialize_with_options(self.database_dir, self.database_options)
+                .await?;
         let mut finished_files_rx = long_lived_embedding_queue.finished_files_rx().await;
         tokio::spawn({
             let vector_db = vector_db.clone();
+            let events = events.clone();
             async move {
                 while let Some(finished_file) = finished_files_rx.recv().await.ok() {
-                    let result = vector_db.create_file_and_spans(finished_file).await;
+                    let path = {
+                        let unlocked = finished_file.lock().await;
+                        if unlocked.details.directory_state.is_cancelled() {
+                            continue;
+                        }
+                        unlocked.details.path.clone()
+                    };
+
+                    // The file's job completes once the file is dropped, so it is held until
+                    // after the write is reported
+                    let result = vector_db.create_file_and_spans(finished_file.clone()).await;
                     match result {
-                        Ok(_) => {}
+                        Ok(_) => {
+                            let _ = events.send(IndexEvent::FileWritten { path });
+                        }
                         Err(err) => {
                             log::error!("{:?}", err)
                         }
@@ -187,40 +749,272 @@ impl SemanticIndex {
             vector_db,
             parsers,
             parse_sender,
-            directory_state: HashMap::new(),
+            embedding_sender: drain_sender,
+            directory_state: Arc::new(std::sync::Mutex::new(HashMap::new())),
             embedding_provider,
+            parse_options: ParseOptions::default(),
+            max_in_flight_files: self.max_in_flight_files,
+            in_flight_files: Arc::new(Semaphore::new(self.max_in_flight_files)),
+            max_concurrent_directories: self.max_concurrent_directories,
+            directory_slots: Arc::new(Semaphore::new(self.max_concurrent_directories)),
+            index_readme: self.index_readme,
+            query_cache: Arc::new(QueryEmbeddingCache::new(DEFAULT_QUERY_CACHE_CAPACITY)),
+            embedding_paused,
+            watches: HashMap::new(),
+            events,
         })
     }
+}
+
+pub struct SemanticIndex {
+    vector_db: VectorDatabase,
+    parsers: ExtensionRegistry,
+    parse_sender: mpsc::Sender<ParseJob>,
+    embedding_sender: mpsc::Sender<EmbeddingJob>,
+    directory_state: DirectoryStates,
+    embedding_provider: Arc<dyn EmbeddingProvider>,
+    parse_options: ParseOptions,
+    max_in_flight_files: usize,
+    in_flight_files: Arc<Semaphore>,
+    max_concurrent_directories: usize,
+    directory_slots: Arc<Semaphore>,
+    index_readme: bool,
+    query_cache: Arc<QueryEmbeddingCache>,
+    embedding_paused: Arc<watch::Sender<bool>>,
+    watches: HashMap<PathBuf, JoinHandle<()>>,
+    events: broadcast::Sender<IndexEvent>,
+}
+
+impl SemanticIndex {
+    pub async fn new(database_dir: PathBuf) -> anyhow::Result<Self> {
+        SemanticIndex::new_with_database_options(database_dir, DatabaseOptions::default()).await
+    }
+
+    pub async fn new_with_database_options(
+        database_dir: PathBuf,
+        database_options: DatabaseOptions,
+    ) -> anyhow::Result<Self> {
+        SemanticIndex::builder(database_dir)
+            .database_options(database_options)
+            .build()
+            .await
+    }
+
+    /// Creates an index embedding with the given provider, rather than OpenAI, for example to
+    /// run offline or against another embedding model.
+    pub async fn new_with_provider(
+        database_dir: PathBuf,
+        embedding_provider: Arc<dyn EmbeddingProvider>,
+        database_options: DatabaseOptions,
+    ) -> anyhow::Result<Self> {
+        SemanticIndex::builder(database_dir)
+            .embedding_provider(embedding_provider)
+            .database_options(database_options)
+            .build()
+            .await
+    }
+
+    /// Returns a builder for configuring the index before it is created.
+    pub fn builder(database_dir: PathBuf) -> SemanticIndexBuilder {
+        SemanticIndexBuilder::new(database_dir)
+    }
+
+    /// Subscribes to events reported by long running operations, such as re-embedding
+    /// progress. Only events sent after subscribing are received.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<IndexEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sets the preprocessor applied to span content before it is embedded, for all
+    /// subsequent index calls.
+    pub fn set_preprocessor(&mut self, preprocessor: Arc<dyn ContentPreprocessor>) {
+        self.parse_options.preprocessor = preprocessor;
+    }
+
+    /// Sets the maximum number of files which can be in the pipeline (parsed but not yet
+    /// written) at once, bounding memory when walking very large directories.
+    pub fn set_max_in_flight_files(&mut self, max_in_flight_files: usize) {
+        let max_in_flight_files = max_in_flight_files.max(1);
+        // Files already in the pipeline hold permits from this semaphore, so it is resized
+        // rather than replaced
+        if max_in_flight_files > self.max_in_flight_files {
+            self.in_flight_files
+                .add_permits(max_in_flight_files - self.max_in_flight_files);
+        } else {
+            let excess = self.max_in_flight_files - max_in_flight_files;
+            let forgotten = self.in_flight_files.forget_permits(excess);
+            if forgotten < excess {
+                // The rest are forgotten as files in the pipeline release them
+                let in_flight_files = self.in_flight_files.clone();
+                tokio::spawn(async move {
+                    if let Ok(permits) = in_flight_files
+                        .acquire_many_owned((excess - forgotten) as u32)
+                        .await
+                    {
+                        permits.forget();
+                    }
+                });
+            }
+        }
+        self.max_in_flight_files = max_in_flight_files;
+    }
+
+    /// Sets the maximum number of directories which can be walking or embedding at once,
+    /// further index calls wait until a directory completes. This keeps bulk indexing within
+    /// the embedding provider's rate limits.
+    pub fn set_max_concurrent_directories(&mut self, max_concurrent_directories: usize) {
+        self.max_concurrent_directories = max_concurrent_directories.max(1);
+        self.directory_slots = Arc::new(Semaphore::new(self.max_concurrent_directories));
+    }
+
+    /// Pauses embedding, for example during a rate limit cool down. Parsed files stay queued
+    /// until embedding is resumed.
+    pub fn pause_embedding(&self) {
+        self.embedding_paused.send_replace(true);
+    }
+
+    pub fn resume_embedding(&self) {
+        self.embedding_paused.send_replace(false);
+    }
+
+    /// Always index the top level `README.md` of indexed directories, even though markdown is
+    /// not otherwise parsed. Its sections are slightly boosted in search, as they tend to
+    /// summarize intent well for conceptual queries.
+    pub fn set_index_readme(&mut self, index_readme: bool) {
+        self.index_readme = index_readme;
+    }
+
+    /// Loads additional extension to language mappings from a json config file, merged over
+    /// the built in strategies.
+    pub fn load_extension_config(&mut self, path: &std::path::Path) -> anyhow::Result<()> {
+        self.parsers.load_config(path)
+    }
+
+    /// Indexes files with extensions that have no parsing strategy as plain text, split into
+    /// windows of `chunk_bytes` overlapping by `overlap_bytes`, rather than skipping them.
+    /// `DEFAULT_PLAIN_TEXT_WINDOW` gives windows of around a few hundred tokens. Pass `None` to
+    /// skip them again.
+    pub fn set_plain_text_fallback(
+        &mut self,
+        fallback: Option<(usize, usize)>,
+    ) -> anyhow::Result<()> {
+        self.parsers
+            .set_fallback(
+                fallback.map(|(chunk_bytes, overlap_bytes)| ParsingStrategy::PlainText {
+                    chunk_bytes,
+                    overlap_bytes,
+                }),
+            )
+    }
+
+    /// Sets the maximum line length, above which files are skipped during indexing.
+    pub fn set_max_line_length(&mut self, max_line_length: usize) {
+        self.parse_options.max_line_length = max_line_length;
+    }
+
+    /// Sets the maximum number of spans indexed per file. Spans beyond the limit are dropped,
+    /// keeping those earliest in the file, and reported as `IndexEvent::SpansTruncated`.
+    pub fn set_max_spans_per_file(&mut self, max_spans_per_file: usize) {
+        self.parse_options.max_spans_per_file = max_spans_per_file;
+    }
+
+    /// Prepends each file's import statements to its spans before embedding. Enabling this
+    /// changes span shas, so previously indexed spans will be re-embedded.
+    pub fn set_include_imports(&mut self, include_imports: bool) {
+        self.parse_options.include_imports = include_imports;
+    }
+
+    /// Anchors each span by the lines it starts on, so results can be relocated with
+    /// `SearchOptions::relocate_spans` after edits shift their byte offsets.
+    pub fn set_line_anchors(&mut self, line_anchors: bool) {
+        self.parse_options.line_anchors = line_anchors;
+    }
 
     async fn walk_directory(
         &self,
         directory_state: Arc<DirectoryState>,
         directory: PathBuf,
         existing_embeddings: Arc<HashMap<Vec<u8>, Vec<f32>>>,
-    ) -> anyhow::Result<()> {
+        options: IndexOptions,
+    ) -> anyhow::Result<IndexSummary> {
+        let mut summary = IndexSummary::default();
+        let include = build_glob_set(&options.include)?;
+        let exclude = build_glob_set(&options.exclude)?;
+        let is_selected = |path: &Path| {
+            let relative = path.strip_prefix(&directory).unwrap_or(path);
+            (options.include.is_empty() || include.is_match(relative))
+                && !exclude.is_match(relative)
+        };
+
         let mut existing_paths = self.vector_db.get_files_for_directory(&directory).await?;
 
-        fn is_hidden(entry: &DirEntry) -> bool {
-            entry
-                .file_name()
-                .to_str()
-                .map(|s| s.starts_with("."))
-                .unwrap_or(false)
+        // Spans wrapped in another template version aren't reused by `existing_embeddings`, and
+        // every file is re-parsed, so that none are left framed the old way
+        let template_version = self.parse_options.template_version;
+        let previous_template_version = self
+            .vector_db
+            .swap_directory_template_version(&directory, template_version)
+            .await?
+            .unwrap_or(UNRECORDED_TEMPLATE_VERSION);
+        let template_changed =
+            !existing_paths.is_empty() && previous_template_version != template_version;
+        if template_changed {
+            log::warn!(
+                "{:?} was indexed with template version {}, re-embedding with version {}",
+                directory,
+                previous_template_version,
+                template_version
+            );
         }
 
-        fn is_target_dir(entry: &DirEntry) -> bool {
-            entry
-                .file_name()
-                .to_str()
-                .map(|s| s.starts_with("target"))
-                .unwrap_or(false)
-        }
+        let strategy_versions = if options.reparse_if_strategy_changed && !template_changed {
+            self.vector_db
+                .get_strategy_versions_for_directory(&directory)
+                .await?
+        } else {
+            HashMap::new()
+        };
+        let is_current = |path: &std::path::Path, strategy: &ParsingStrategy| {
+            strategy_versions
+                .get(path)
+                .is_some_and(|version| version.as_ref() == Some(&strategy.version()))
+        };
+
+        // Ignore files are honoured whether or not the directory is within a git repository
+        let excluded_directories = options
+            .excluded_directories
+            .iter()
+            .map(std::ffi::OsString::from)
+            .collect::<HashSet<_>>();
+        let excluded_globs = exclude.clone();
+        let root = directory.clone();
+        let walker = WalkBuilder::new(&directory)
+            .hidden(!options.include_hidden)
+            .require_git(false)
+            .filter_entry(move |entry| {
+                if entry.depth() == 0
+                    || !entry
+                        .file_type()
+                        .is_some_and(|file_type| file_type.is_dir())
+                {
+                    return true;
+                }
+
+                let relative = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+                !excluded_directories.contains(entry.file_name())
+                    && !excluded_globs.is_match(relative)
+            })
+            .build();
+        for entry in walker {
+            if directory_state.is_cancelled() {
+                log::debug!("indexing cancelled, stopping walk of {:?}", directory);
+                return anyhow::Ok(summary);
+            }
 
-        let walker = WalkDir::new(directory.clone()).into_iter();
-        for entry in walker.filter_entry(|e| !is_hidden(e) && !is_target_dir(e)) {
             if let Ok(entry) = entry {
                 let path = entry.path();
-                if path.is_file() && !path.is_symlink() {
+                // Previously indexed files no longer selected are removed along with deleted ones
+                if path.is_file() && !path.is_symlink() && is_selected(path) {
                     if let Some(extension) =
                         path.extension().and_then(|extension| extension.to_str())
                     {
@@ -229,19 +1023,43 @@ impl SemanticIndex {
                             .get_strategy_for_extension(extension.to_string())
                             .ok()
                         {
+                            if let Some(max_file_bytes) = options.max_file_bytes {
+                                let len = entry.metadata().map_or(0, |metadata| metadata.len());
+                                if len > max_file_bytes {
+                                    log::debug!(
+                                        "skipping {:?}, {} bytes is over the {} byte limit",
+                                        path,
+                                        len,
+                                        max_file_bytes
+                                    );
+                                    continue;
+                                }
+                            }
+
                             existing_paths.remove(&path.to_path_buf());
+                            if is_current(path, strategy) {
+                                summary.files_skipped += 1;
+                                continue;
+                            }
 
+                            let permit = self.in_flight_files.clone().acquire_owned().await?;
+                            directory_state.new_job();
                             let file_details = FileDetails {
                                 path: path.to_path_buf(),
                                 directory_state: directory_state.clone(),
+                                _permit: Some(Arc::new(permit)),
                             };
-                            self.parse_sender
-                                .send(Arc::new((
+                            queue_parse_job(
+                                &self.parse_sender,
+                                Arc::new((
                                     file_details,
                                     strategy.clone(),
                                     existing_embeddings.clone(),
-                                )))
-                                .await?;
+                                    self.parse_options.clone(),
+                                )),
+                            )
+                            .await?;
+                            summary.files_queued += 1;
                         }
                     }
 
@@ -268,66 +1086,3603 @@ impl SemanticIndex {
             }
         }
 
+        let readme = directory.join("README.md");
+        if self.index_readme && readme.is_file() {
+            existing_paths.remove(&readme);
+
+            if is_current(&readme, &ParsingStrategy::Readme) {
+                summary.files_skipped += 1;
+            } else {
+                let permit = self.in_flight_files.clone().acquire_owned().await?;
+                directory_state.new_job();
+                let file_details = FileDetails {
+                    path: readme,
+                    directory_state: directory_state.clone(),
+                    _permit: Some(Arc::new(permit)),
+                };
+                queue_parse_job(
+                    &self.parse_sender,
+                    Arc::new((
+                        file_details,
+                        ParsingStrategy::Readme,
+                        existing_embeddings.clone(),
+                        self.parse_options.clone(),
+                    )),
+                )
+                .await?;
+                summary.files_queued += 1;
+            }
+        }
+
         for path in existing_paths {
             self.vector_db.delete_file(&path).await?;
+            summary.files_removed += 1;
+        }
+
+        anyhow::Ok(summary)
+    }
+
+    /// Walks the directory once a directory slot is available, holding the slot until all of
+    /// the directory's jobs have completed.
+    async fn walk_directory_in_slot(
+        &self,
+        directory_state: Arc<DirectoryState>,
+        directory: PathBuf,
+        existing_embeddings: Arc<HashMap<Vec<u8>, Vec<f32>>>,
+        options: IndexOptions,
+    ) -> anyhow::Result<IndexSummary> {
+        // The walk itself counts as a job, so the directory reports as indexing until the walk
+        // completes, even if every file queued so far has already been written
+        directory_state.new_job();
+        let summary = async {
+            let permit = self.directory_slots.clone().acquire_owned().await?;
+            directory_state.hold_slot(permit);
+            self.walk_directory(
+                directory_state.clone(),
+                directory,
+                existing_embeddings,
+                options,
+            )
+            .await
+        }
+        .await;
+        directory_state.job_dropped();
+
+        if summary.is_err() || directory_state.status().outstanding().is_none() {
+            directory_state.release_slot();
         }
 
+        summary
+    }
+
+    /// Fails with `AlreadyIndexing` if the directory is still being indexed, as replacing its
+    /// state would lose track of its outstanding jobs.
+    fn ensure_not_indexing(&self, directory: &PathBuf) -> anyhow::Result<()> {
+        if let Some(directory_state) = self.directory_state.lock().unwrap().get(directory) {
+            if directory_state.status().outstanding().is_some() {
+                return Err(anyhow::Error::new(AlreadyIndexing {
+                    directory: directory.clone(),
+                }));
+            }
+        }
         anyhow::Ok(())
     }
 
-    pub async fn index_directory(&mut self, directory: PathBuf) -> anyhow::Result<Arc<Notify>> {
+    async fn prepare_directory(
+        &mut self,
+        directory: &PathBuf,
+    ) -> anyhow::Result<(Arc<DirectoryState>, Arc<HashMap<Vec<u8>, Vec<f32>>>)> {
+        self.ensure_not_indexing(directory)?;
+
         // Get or Create Directory Item in Vector Database
-        let directory_id = self.vector_db.get_or_create_directory(&directory).await?;
+        let directory_id = self.vector_db.get_or_create_directory(directory).await?;
         let directory_state = Arc::new(DirectoryState::new(directory_id));
 
         let existing_embeddings = Arc::new(
             self.vector_db
-                .get_embeddings_for_directory(&directory)
+                .get_embeddings_for_directory(directory, self.parse_options.template_version)
                 .await?,
         );
 
-        // TODO: Make this work for concurrent index calls
+        // Calls on the index are exclusive, so no other index of the directory can have begun
+        // since the check above
         self.directory_state
+            .lock()
+            .unwrap()
             .insert(directory.clone(), directory_state.clone());
 
+        anyhow::Ok((directory_state, existing_embeddings))
+    }
+
+    pub async fn index_directory(&mut self, directory: PathBuf) -> anyhow::Result<Arc<Notify>> {
+        self.index_directory_with_options(directory, IndexOptions::default())
+            .await
+    }
+
+    /// Indexes the directory as `index_directory` does, with options controlling which files
+    /// are parsed.
+    pub async fn index_directory_with_options(
+        &mut self,
+        directory: PathBuf,
+        options: IndexOptions,
+    ) -> anyhow::Result<Arc<Notify>> {
+        let (directory_state, existing_embeddings) = self.prepare_directory(&directory).await?;
+
         let _ = self
-            .walk_directory(directory_state.clone(), directory, existing_embeddings)
+            .walk_directory_in_slot(
+                directory_state.clone(),
+                directory,
+                existing_embeddings,
+                options,
+            )
             .await?;
 
         anyhow::Ok(directory_state.notify.clone())
     }
 
-    pub async fn search_directory(
-        &self,
-        directory: PathBuf,
-        n: usize,
-        search_query: &str,
-    ) -> anyhow::Result<Vec<SearchResult>> {
-        // Handle for calls to search before indexing is complete, by automatically kicking
-        // indexing off.
-        // let await = self.index_directory(directory.clone()).await;
-        // indexing.await;
-        log::debug!("searching {:?} for {:?}", &directory, &search_query);
+    /// Watches the directory, reindexing files as they are created or modified and removing
+    /// deleted files, to keep the index fresh while editing. Changes are reindexed once the
+    /// directory has been quiet for a short debounce, and the directory is watched until
+    /// `unwatch_directory` is called. Existing files are not indexed, so this is typically
+    /// called after `index_directory`.
+    pub async fn watch_directory(&mut self, directory: PathBuf) -> anyhow::Result<()> {
+        let directory_state = self
+            .directory_state
+            .lock()
+            .unwrap()
+            .get(&directory)
+            .filter(|directory_state| !directory_state.is_cancelled())
+            .cloned();
+        let (directory_state, existing_embeddings) = match directory_state {
+            Some(directory_state) => (
+                directory_state.clone(),
+                Arc::new(
+                    self.vector_db
+                        .get_embeddings_for_directory(
+                            &directory,
+                            self.parse_options.template_version,
+                        )
+                        .await?,
+                ),
+            ),
+            None => self.prepare_directory(&directory).await?,
+        };
 
-        if let Some(embedding) = self
-            .embedding_provider
-            .embed_query(search_query.to_string())
-            .await
-            .ok()
-        {
-            self.vector_db
-                .get_top_neighbours(directory, &embedding, n)
-                .await
-        } else {
-            Err(anyhow!("embedding provider failed to embed search query"))
+        let watch = DirectoryWatcher {
+            directory: directory.clone(),
+            directory_state,
+            existing_embeddings,
+            parsers: self.parsers.clone(),
+            parse_sender: self.parse_sender.clone(),
+            parse_options: self.parse_options.clone(),
+            in_flight_files: self.in_flight_files.clone(),
+            index_readme: self.index_readme,
+            vector_db: self.vector_db.clone(),
+        }
+        .spawn()?;
+
+        if let Some(previous) = self.watches.insert(directory, watch) {
+            previous.abort();
+        }
+
+        anyhow::Ok(())
+    }
+
+    /// Stops watching the directory, returning whether it was being watched. Changes already
+    /// picked up continue through the pipeline.
+    pub fn unwatch_directory(&mut self, directory: &PathBuf) -> bool {
+        match self.watches.remove(directory) {
+            Some(watch) => {
+                watch.abort();
+                true
+            }
+            None => false,
         }
     }
 
-    pub async fn get_status(&self, directory: PathBuf) -> IndexingStatus {
-        if let Some(directory_state) = self.directory_state.get(&directory) {
-            directory_state.status()
-        } else {
-            IndexingStatus::NotIndexed
+    /// Removes the directory from the index, purging its files and spans from the database.
+    /// Any in-progress indexing or watch is stopped first, and the directory reports
+    /// `NotIndexed` afterwards. Removing a directory which was never indexed is a no-op.
+    pub async fn remove_directory(&mut self, directory: PathBuf) -> anyhow::Result<()> {
+        self.unwatch_directory(&directory);
+
+        let directory_state = self.directory_state.lock().unwrap().remove(&directory);
+        if let Some(directory_state) = directory_state {
+            // Cancelling skips any files still in the pipeline, so they are not written back
+            directory_state.cancel();
         }
+
+        self.vector_db.delete_directory(&directory).await
+    }
+
+    /// Clears the directory from the index and indexes it afresh, re-embedding every span with
+    /// the current provider rather than reusing stored embeddings. This is the escape hatch for
+    /// when stored embeddings become inconsistent, such as after switching embedding model or
+    /// editing a parser query. Indexing in progress is cancelled, and a watched directory stays
+    /// watched. A configured embedding cache is still consulted, so should be cleared too if
+    /// the model has changed.
+    pub async fn reindex_directory(&mut self, directory: PathBuf) -> anyhow::Result<Arc<Notify>> {
+        let watched = self.watches.contains_key(&directory);
+        self.remove_directory(directory.clone()).await?;
+
+        let notify = self.index_directory(directory.clone()).await?;
+        if watched {
+            self.watch_directory(directory).await?;
+        }
+
+        anyhow::Ok(notify)
+    }
+
+    /// Indexes several directories, walking up to `max_concurrent_directories` at once. The
+    /// returned handle resolves with a summary per directory once all have finished indexing.
+    pub async fn index_directories(
+        &mut self,
+        directories: Vec<PathBuf>,
+    ) -> anyhow::Result<JoinHandle<HashMap<PathBuf, IndexSummary>>> {
+        // A directory listed twice would be prepared twice before either is walked, replacing
+        // the state of the first, so is only indexed once
+        let mut seen = HashSet::new();
+        let mut prepared = Vec::new();
+        for directory in directories {
+            if !seen.insert(directory.clone()) {
+                continue;
+            }
+
+            let (directory_state, existing_embeddings) = self.prepare_directory(&directory).await?;
+            prepared.push((directory, directory_state, existing_embeddings));
+        }
+
+        let index = &*self;
+        let walked = futures::stream::iter(prepared.into_iter().map(
+            |(directory, directory_state, existing_embeddings)| async move {
+                let summary = index
+                    .walk_directory_in_slot(
+                        directory_state.clone(),
+                        directory.clone(),
+                        existing_embeddings,
+                        IndexOptions::default(),
+                    )
+                    .await;
+                (directory, directory_state, summary)
+            },
+        ))
+        .buffer_unordered(self.max_concurrent_directories)
+        .collect::<Vec<_>>()
+        .await;
+
+        let mut walked_directories = Vec::new();
+        for (directory, directory_state, summary) in walked {
+            walked_directories.push((directory, directory_state, summary?));
+        }
+
+        anyhow::Ok(tokio::spawn(async move {
+            let mut summaries = HashMap::new();
+            for (directory, directory_state, summary) in walked_directories {
+                if summary.files_queued > 0 {
+                    directory_state.notify.notified().await;
+                }
+                summaries.insert(directory, summary);
+            }
+            summaries
+        }))
+    }
+
+    /// Re-embeds every file already indexed for the directory with the current embedding
+    /// provider, ignoring stored embeddings, for example when migrating to another embedding
+    /// model. Files are embedded through the same queue as indexing, so are batched, retried
+    /// and sanitized alike, and a file failing to embed is skipped rather than failing the
+    /// rest. Progress is reported as `IndexEvent::ReembedProgress` events, and the returned
+    /// handle resolves with a summary once every file has been re-embedded or dropped. Fails
+    /// with `AlreadyIndexing` if the directory is still being indexed.
+    pub async fn reembed_directory(
+        &mut self,
+        directory: PathBuf,
+    ) -> anyhow::Result<JoinHandle<anyhow::Result<ReembedSummary>>> {
+        self.ensure_not_indexing(&directory)?;
+
+        let paths = self.vector_db.get_files_for_directory(&directory).await?;
+        let directory_id = self.vector_db.get_or_create_directory(&directory).await?;
+        let directory_state = Arc::new(DirectoryState::new(directory_id));
+        self.directory_state
+            .lock()
+            .unwrap()
+            .insert(directory.clone(), directory_state.clone());
+
+        // Re-embedding counts as a job, as walking does, so the directory reports as indexing
+        // until every file has been queued
+        directory_state.new_job();
+
+        let readme = directory.join("README.md");
+        let files = paths
+            .into_iter()
+            .filter_map(|path| {
+                let strategy = if path == readme {
+                    ParsingStrategy::Readme
+                } else {
+                    let extension = path.extension()?.to_str()?.to_string();
+                    self.parsers
+                        .get_strategy_for_extension(extension)
+                        .ok()?
+                        .clone()
+                };
+                Some((path, strategy))
+            })
+            .collect::<Vec<(PathBuf, ParsingStrategy)>>();
+
+        let embedding_sender = self.embedding_sender.clone();
+        let parse_options = self.parse_options.clone();
+        let events = self.events.clone();
+        let mut written = self.events.subscribe();
+        anyhow::Ok(tokio::spawn(async move {
+            // Files are parsed up front, so progress can be reported against the total
+            let mut contexts = Vec::new();
+            for (path, strategy) in files {
+                let details = FileDetails {
+                    path: path.clone(),
+                    directory_state: directory_state.clone(),
+                    _permit: None,
+                };
+                directory_state.new_job();
+                match parse_file(details, &strategy, &parse_options).await {
+                    Ok(mut context) => {
+                        if let Some(spans) =
+                            context.truncate_spans(parse_options.max_spans_per_file)
+                        {
+                            let _ = events.send(IndexEvent::SpansTruncated {
+                                path: path.clone(),
+                                spans,
+                                max_spans_per_file: parse_options.max_spans_per_file,
+                            });
+                        }
+                        contexts.push(context);
+                    }
+                    Err(err) => {
+                        directory_state.job_dropped();
+                        log::warn!("skipping {:?} when re-embedding: {:?}", path, err)
+                    }
+                }
+            }
+
+            let total = contexts
+                .iter()
+                .map(|context| context.documents.len())
+                .sum::<usize>();
+            let mut span_counts = contexts
+                .iter()
+                .map(|context| (context.details.path.clone(), context.documents.len()))
+                .collect::<HashMap<PathBuf, usize>>();
+
+            // Parsed files carry no embeddings, so every span is embedded again
+            for context in contexts {
+                if directory_state.is_cancelled() {
+                    break;
+                }
+                let job = EmbeddingJob::Embed {
+                    file_context: Arc::new(Mutex::new(context)),
+                };
+                if embedding_sender.send(job).await.is_err() {
+                    directory_state.job_dropped();
+                    return Err(anyhow!(
+                        "indexing pipeline has stopped, files can no longer be embedded"
+                    ));
+                }
+            }
+            let _ = embedding_sender.send(EmbeddingJob::Flush).await;
+            directory_state.job_dropped();
+
+            // Files are counted as they are written, until every job has been written or dropped
+            let mut summary = ReembedSummary::default();
+            let mut record_written = |path: PathBuf| {
+                if let Some(spans) = span_counts.remove(&path) {
+                    summary.files_reembedded += 1;
+                    summary.spans_reembedded += spans;
+                    let _ = events.send(IndexEvent::ReembedProgress {
+                        directory: directory.clone(),
+                        spans_reembedded: summary.spans_reembedded,
+                        total,
+                    });
+                }
+            };
+            let mut job_count_rx = directory_state.job_count_rx.clone();
+            loop {
+                tokio::select! {
+                    event = written.recv() => match event {
+                        Ok(IndexEvent::FileWritten { path }) => record_written(path),
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = job_count_rx.wait_for(|count| *count == 0) => {
+                        // Files are reported as written before their jobs complete
+                        while let Ok(event) = written.try_recv() {
+                            if let IndexEvent::FileWritten { path } = event {
+                                record_written(path);
+                            }
+                        }
+                        break;
+                    }
+                }
+            }
+
+            anyhow::Ok(summary)
+        }))
+    }
+
+    /// Embeds and caches the query ahead of time, so a subsequent search for the same query
+    /// skips embedding. Useful for pre-embedding likely next queries in an interactive search.
+    pub async fn prefetch_query(&self, query: String) -> anyhow::Result<()> {
+        self.query_cache
+            .get_or_embed(&query, self.embedding_provider.as_ref())
+            .await?;
+        anyhow::Ok(())
+    }
+
+    /// Embeds the text with the configured embedding provider, without indexing or caching it,
+    /// for clients building their own features on the same embeddings.
+    pub async fn embed_text(&self, text: &str) -> anyhow::Result<Embedding> {
+        self.embedding_provider
+            .embed_texts(vec![text.to_string()])
+            .await?
+            .pop()
+            .ok_or(anyhow!("embedding provider failed to embed text"))
+    }
+
+    /// Lists the metrics which can be used to score search results.
+    pub fn available_metrics() -> Vec<MetricInfo> {
+        SimilarityMetric::ALL
+            .iter()
+            .map(|metric| MetricInfo {
+                name: metric.name().to_string(),
+                higher_is_better: metric.higher_is_better(),
+            })
+            .collect()
+    }
+
+    pub async fn search_directory(
+        &self,
+        directory: PathBuf,
+        n: usize,
+        search_query: &str,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        self.search_directory_with_options(directory, n, search_query, SearchOptions::default())
+            .await
+    }
+
+    pub async fn search_directory_with_options(
+        &self,
+        directory: PathBuf,
+        n: usize,
+        search_query: &str,
+        options: SearchOptions,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        // Handle for calls to search before indexing is complete, by automatically kicking
+        // indexing off.
+        // let await = self.index_directory(directory.clone()).await;
+        // indexing.await;
+        log::debug!("searching {:?} for {:?}", &directory, &search_query);
+
+        if let Some(embedding) = self
+            .query_cache
+            .get_or_embed(search_query, self.embedding_provider.as_ref())
+            .await
+            .ok()
+        {
+            let embedding = match &options.negative {
+                Some(negative) => {
+                    let negative = self
+                        .query_cache
+                        .get_or_embed(negative, self.embedding_provider.as_ref())
+                        .await?;
+                    exclude_negative(&embedding, &negative, NEGATIVE_QUERY_WEIGHT)
+                }
+                None => embedding,
+            };
+
+            let results = self
+                .vector_db
+                .get_top_neighbours_with_metric(
+                    directory,
+                    &embedding,
+                    n,
+                    options.metric,
+                    options.test_filter,
+                    options.min_similarity,
+                )
+                .await?;
+
+            let mut results = if options.filter_missing_files {
+                filter_missing_files(results).await
+            } else {
+                results
+            };
+
+            if options.relocate_spans {
+                results = relocate_anchored_spans(results).await;
+            }
+
+            if options.metric.higher_is_better() {
+                if let Some(weight) = options.entropy_penalty {
+                    let contents = read_span_contents(&results).await;
+                    results = penalize_low_entropy(results, &contents, weight);
+                }
+
+                if self.index_readme {
+                    results = boost_symbol_kind(results, README_SYMBOL_KIND, README_BOOST);
+                }
+
+                if let Some(path_query) = &options.path_query {
+                    results = boost_matching_paths(results, path_query, PATH_QUERY_BOOST);
+                }
+            }
+
+            if options.highlight_lines {
+                let contents = read_span_contents(&results).await;
+                for (result, content) in results.iter_mut().zip(contents) {
+                    let ranges = line_ranges(&content);
+                    let lines = ranges
+                        .iter()
+                        .map(|range| content[range.clone()].to_string())
+                        .collect::<Vec<String>>();
+                    if lines.is_empty() {
+                        continue;
+                    }
+
+                    let line_embeddings = self.embedding_provider.embed_texts(lines).await?;
+                    result.highlight = best_line(&embedding, &ranges, &line_embeddings);
+                }
+            }
+
+            // Results with content resolved from the blob store don't need reading
+            if options.with_content && results.iter().any(|result| result.content.is_none()) {
+                let contents = read_span_contents(&results).await;
+                for (result, content) in results.iter_mut().zip(contents) {
+                    result.content.get_or_insert(content);
+                }
+            }
+
+            if options.sort == SortOrder::FileThenPosition {
+                results.sort_by(|a, b| {
+                    a.path
+                        .cmp(&b.path)
+                        .then_with(|| a.start_byte.cmp(&b.start_byte))
+                });
+            }
+
+            anyhow::Ok(results)
+        } else {
+            Err(anyhow!("embedding provider failed to embed search query"))
+        }
+    }
+
+    /// Searches every indexed directory at once, ranking spans by similarity regardless of the
+    /// directory they belong to. Each result's path identifies the file, and so the directory,
+    /// it was found in.
+    pub async fn search_all(
+        &self,
+        n: usize,
+        search_query: &str,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        log::debug!("searching all directories for {:?}", &search_query);
+        let embedding = self
+            .query_cache
+            .get_or_embed(search_query, self.embedding_provider.as_ref())
+            .await?;
+        self.vector_db.get_top_neighbours_all(&embedding, n).await
+    }
+
+    /// Searches the directory a page at a time. Pass the `next_cursor` of one page to fetch the
+    /// next, which continues from the last result seen rather than an offset, so pages neither
+    /// repeat nor skip results if the index changes between requests.
+    pub async fn search_directory_page(
+        &self,
+        directory: PathBuf,
+        n: usize,
+        search_query: &str,
+        cursor: Option<String>,
+    ) -> anyhow::Result<SearchPage> {
+        let cursor = cursor
+            .map(|cursor| SearchCursor::decode(&cursor))
+            .transpose()?;
+        let embedding = self
+            .query_cache
+            .get_or_embed(search_query, self.embedding_provider.as_ref())
+            .await?;
+        self.vector_db
+            .get_top_neighbours_page(directory, &embedding, n, cursor)
+            .await
+    }
+
+    /// Searches the directory, sending results on the returned channel as they are fetched, so
+    /// the first results can be shown before the rest are ranked. Results are fetched a page
+    /// at a time, and the search stops early if the receiver is dropped, or once results fall
+    /// below `min_similarity`. Of the options, only `min_similarity`, `negative` and
+    /// `with_content` apply, as the rest rerank results once all of them are fetched. Pages are
+    /// ranked by cosine similarity, so searching by any other metric fails.
+    pub async fn search_directory_stream(
+        &self,
+        directory: PathBuf,
+        n: usize,
+        search_query: &str,
+        options: SearchOptions,
+    ) -> anyhow::Result<mpsc::Receiver<anyhow::Result<SearchResult>>> {
+        if options.metric != SimilarityMetric::Cosine {
+            return Err(anyhow!(
+                "streamed searches are ranked by cosine similarity, not {}",
+                options.metric.name()
+            ));
+        }
+
+        let embedding = self
+            .query_cache
+            .get_or_embed(search_query, self.embedding_provider.as_ref())
+            .await?;
+        let embedding = match &options.negative {
+            Some(negative) => {
+                let negative = self
+                    .query_cache
+                    .get_or_embed(negative, self.embedding_provider.as_ref())
+                    .await?;
+                exclude_negative(&embedding, &negative, NEGATIVE_QUERY_WEIGHT)
+            }
+            None => embedding,
+        };
+        let min_similarity = options.min_similarity;
+        let with_content = options.with_content;
+
+        let vector_db = self.vector_db.clone();
+        let (sender, receiver) = mpsc::channel(STREAM_PAGE_SIZE);
+        tokio::spawn(async move {
+            let mut cursor = None;
+            let mut remaining = n;
+            while remaining > 0 {
+                let page = vector_db
+                    .get_top_neighbours_page(
+                        directory.clone(),
+                        &embedding,
+                        remaining.min(STREAM_PAGE_SIZE),
+                        cursor,
+                    )
+                    .await;
+                let page = match page {
+                    Ok(page) => page,
+                    Err(err) => {
+                        let _ = sender.send(Err(err)).await;
+                        return;
+                    }
+                };
+
+                remaining = remaining.saturating_sub(page.results.len());
+                for mut result in page.results {
+                    // Pages are ranked by cosine similarity, so no later result clears the bar
+                    if let Some(min_similarity) = min_similarity {
+                        if !SimilarityMetric::Cosine.clears(result.similarity, min_similarity) {
+                            return;
+                        }
+                    }
+
+                    if with_content && result.content.is_none() {
+                        let contents = read_span_contents(std::slice::from_ref(&result)).await;
+                        result.content = contents.into_iter().next();
+                    }
+
+                    if sender.send(Ok(result)).await.is_err() {
+                        return;
+                    }
+                }
+
+                match page.next_cursor {
+                    Some(next_cursor) => match SearchCursor::decode(&next_cursor) {
+                        Ok(next_cursor) => cursor = Some(next_cursor),
+                        Err(err) => {
+                            let _ = sender.send(Err(err)).await;
+                            return;
+                        }
+                    },
+                    None => return,
+                }
+            }
+        });
+
+        anyhow::Ok(receiver)
+    }
+
+    /// Watches the search results for the directory as it is indexed, sending the top `n`
+    /// results for the query once up front, and again whenever files within the directory are
+    /// written. Files written in quick succession are coalesced into a single search, and the
+    /// watch ends once the receiver is dropped.
+    pub async fn watch_search(
+        &self,
+        directory: PathBuf,
+        search_query: &str,
+        n: usize,
+    ) -> anyhow::Result<mpsc::Receiver<anyhow::Result<Vec<SearchResult>>>> {
+        let embedding = self
+            .query_cache
+            .get_or_embed(search_query, self.embedding_provider.as_ref())
+            .await?;
+
+        let vector_db = self.vector_db.clone();
+        let mut events = self.events.subscribe();
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let results = vector_db
+                    .get_top_neighbours(directory.clone(), &embedding, n)
+                    .await;
+                if sender.send(results).await.is_err() {
+                    return;
+                }
+
+                // Wait for a file within the directory to be written, or the receiver to close
+                let mut changed = false;
+                while !changed {
+                    let event = tokio::select! {
+                        event = events.recv() => event,
+                        _ = sender.closed() => return,
+                    };
+                    changed = match event {
+                        Ok(IndexEvent::FileWritten { path }) => path.starts_with(&directory),
+                        Ok(_) => false,
+                        // Missed events may have included the directory's files
+                        Err(broadcast::error::RecvError::Lagged(_)) => true,
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    };
+                }
+
+                // Coalesce any other writes already reported
+                while events.try_recv().is_ok() {}
+            }
+        });
+
+        anyhow::Ok(receiver)
+    }
+
+    /// Attaches a natural language description to the span, which is embedded and searched
+    /// alongside the code, with matches returned at the described span's location and tagged
+    /// with the `description` symbol kind. Descriptions are discarded when the span's file is
+    /// re-indexed.
+    pub async fn set_span_description(
+        &self,
+        span_id: RecordId,
+        description: &str,
+    ) -> anyhow::Result<()> {
+        let embedding = self
+            .embedding_provider
+            .embed_texts(vec![description.to_string()])
+            .await?
+            .pop()
+            .ok_or(anyhow!("embedding provider returned no embeddings"))?;
+        self.vector_db
+            .set_span_description(span_id, get_sha(description), embedding)
+            .await
+    }
+
+    /// Returns each span sha shared by more than one span across the index, with its count,
+    /// for reporting on duplicated code.
+    pub async fn duplicate_shas(&self) -> anyhow::Result<Vec<(Vec<u8>, usize)>> {
+        self.vector_db.duplicate_shas().await
+    }
+
+    /// Scores every span within a single file against the query, returning them ranked by
+    /// relevance, for highlighting the most relevant parts of an open file.
+    pub async fn rank_spans_in_file(
+        &self,
+        file: PathBuf,
+        query: &str,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let embedding = self
+            .query_cache
+            .get_or_embed(query, self.embedding_provider.as_ref())
+            .await?;
+        self.vector_db.rank_file_spans(&file, &embedding).await
+    }
+
+    /// Searches the directory, returning alongside the results the number of candidate spans
+    /// of each symbol kind. Facets count every span passing the search's test filter and
+    /// minimum similarity, not only the top `n` returned, so don't depend on `n`.
+    pub async fn search_directory_with_facets(
+        &self,
+        directory: PathBuf,
+        n: usize,
+        search_query: &str,
+        options: SearchOptions,
+    ) -> anyhow::Result<(Vec<SearchResult>, HashMap<String, usize>)> {
+        let results = self
+            .search_directory_with_options(directory.clone(), n, search_query, options.clone())
+            .await?;
+
+        // Embeddings are cached, so the query isn't embedded again
+        let embedding = self
+            .query_cache
+            .get_or_embed(search_query, self.embedding_provider.as_ref())
+            .await?;
+        let embedding = match &options.negative {
+            Some(negative) => {
+                let negative = self
+                    .query_cache
+                    .get_or_embed(negative, self.embedding_provider.as_ref())
+                    .await?;
+                exclude_negative(&embedding, &negative, NEGATIVE_QUERY_WEIGHT)
+            }
+            None => embedding,
+        };
+        let facets = self
+            .vector_db
+            .get_symbol_kind_facets(
+                directory,
+                &embedding,
+                options.metric,
+                options.test_filter,
+                options.min_similarity,
+            )
+            .await?;
+
+        anyhow::Ok((results, facets))
+    }
+
+    /// Waits for indexing still in progress to be embedded and written, then closes the
+    /// database, flushing its writes to disk.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        // Spans already queued are embedded straight away, rather than after the flush interval
+        let (drained_tx, drained_rx) = oneshot::channel();
+        let drain = EmbeddingJob::Drain {
+            drained: drained_tx,
+        };
+        if self.embedding_sender.send(drain).await.is_ok() {
+            let _ = drained_rx.await;
+        }
+
+        // A file's job only completes once the writer has written it, or dropped it
+        let directory_states = self
+            .directory_state
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for directory_state in directory_states {
+            let mut job_count_rx = directory_state.job_count_rx.clone();
+            let _ = job_count_rx
+                .wait_for(|count| *count == 0 || directory_state.is_cancelled())
+                .await;
+        }
+
+        self.vector_db.shutdown().await
+    }
+
+    /// Watches the number of files persisted for a directory, as observed by the database
+    /// rather than the in-memory job accounting, so progress survives process boundaries.
+    pub async fn watch_persisted_files(
+        &self,
+        directory: PathBuf,
+    ) -> anyhow::Result<watch::Receiver<usize>> {
+        self.vector_db.watch_persisted_files(&directory).await
+    }
+
+    /// Returns a handle for cancelling indexing, usable while the index itself is busy.
+    pub fn canceller(&self) -> IndexCanceller {
+        IndexCanceller {
+            directory_state: self.directory_state.clone(),
+        }
+    }
+
+    /// Cancels indexing of the directory, see `IndexCanceller::cancel`.
+    pub fn cancel_indexing(&self, directory: PathBuf) -> anyhow::Result<()> {
+        self.canceller().cancel(&directory)
+    }
+
+    /// Returns the status of the directory, or of its nearest indexed ancestor if the directory
+    /// is itself a subdirectory of an indexed root.
+    pub async fn get_status(&self, directory: PathBuf) -> IndexingStatus {
+        let directory_states = self.directory_state.lock().unwrap();
+        if let Some(directory_state) = find_directory_state(&directory_states, &directory) {
+            self.directory_status(directory_state)
+        } else {
+            IndexingStatus::NotIndexed
+        }
+    }
+
+    fn directory_status(&self, directory_state: &DirectoryState) -> IndexingStatus {
+        match directory_state.status() {
+            IndexingStatus::Indexing { jobs_outstanding } if *self.embedding_paused.borrow() => {
+                IndexingStatus::Paused { jobs_outstanding }
+            }
+            status => status,
+        }
+    }
+
+    /// Lists every directory known to the index, sorted by path, with its status. Directories
+    /// persisted by a previous run, and not indexed since, are reported as `Indexed`.
+    pub async fn list_directories(&self) -> anyhow::Result<Vec<(PathBuf, IndexingStatus)>> {
+        let mut directories = self
+            .vector_db
+            .get_directories()
+            .await?
+            .into_iter()
+            .map(|directory| (directory, IndexingStatus::Indexed))
+            .collect::<HashMap<PathBuf, IndexingStatus>>();
+        for (directory, directory_state) in self.directory_state.lock().unwrap().iter() {
+            directories.insert(directory.clone(), self.directory_status(directory_state));
+        }
+
+        let mut directories = directories.into_iter().collect::<Vec<_>>();
+        directories.sort_by(|a, b| a.0.cmp(&b.0));
+        anyhow::Ok(directories)
+    }
+
+    /// Watches the directory's indexing status, sending it whenever the number of outstanding
+    /// jobs changes. The watch ends after sending a status with no jobs outstanding, such as
+    /// `Indexed`, or `NotIndexed` if the directory is removed while indexing.
+    pub fn watch_status(&self, directory: PathBuf) -> mpsc::Receiver<IndexingStatus> {
+        let directory_states = self.directory_state.clone();
+        let directory_state =
+            find_directory_state(&directory_states.lock().unwrap(), &directory).cloned();
+        let embedding_paused = self.embedding_paused.subscribe();
+
+        let (sender, receiver) = mpsc::channel(STATUS_BUFFER);
+        tokio::spawn(async move {
+            let directory_state = match directory_state {
+                Some(directory_state) => directory_state,
+                None => {
+                    let _ = sender.send(IndexingStatus::NotIndexed).await;
+                    return;
+                }
+            };
+
+            let mut job_count = directory_state.job_count_rx.clone();
+            loop {
+                let removed = !find_directory_state(&directory_states.lock().unwrap(), &directory)
+                    .is_some_and(|current| Arc::ptr_eq(current, &directory_state));
+                let status = match directory_state.status() {
+                    _ if removed => IndexingStatus::NotIndexed,
+                    IndexingStatus::Indexing { jobs_outstanding } if *embedding_paused.borrow() => {
+                        IndexingStatus::Paused { jobs_outstanding }
+                    }
+                    status => status,
+                };
+
+                let finished = status.outstanding().is_none();
+                if sender.send(status).await.is_err() || finished {
+                    return;
+                }
+
+                tokio::select! {
+                    changed = job_count.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                    }
+                    _ = sender.closed() => return,
+                }
+            }
+        });
+
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory_watcher::WATCH_DEBOUNCE;
+    use crate::embedding::base::{
+        BagOfWordsEmbeddingProvider, CountingEmbeddingProvider, FakeEmbeddingProvider,
+        SlowEmbeddingProvider,
+    };
+    use crate::parsers::strategy::{FileContext, TEMPLATE_VERSION};
+    use crate::runtime::build_runtime;
+    use surrealdb::sql::Thing;
+    use tempfile::tempdir;
+
+    fn search_result(path: PathBuf) -> SearchResult {
+        SearchResult {
+            id: Thing::from(("span", "0")),
+            path,
+            start_byte: 0,
+            end_byte: 10,
+            start_line: None,
+            start_col: None,
+            end_line: None,
+            end_col: None,
+            similarity: 1.0,
+            symbol_kind: None,
+            highlight: None,
+            anchor: None,
+            content: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_missing_files() {
+        let tmp_dir = tempdir().unwrap();
+        let kept = tmp_dir.path().join("kept.rs");
+        let deleted = tmp_dir.path().join("deleted.rs");
+        std::fs::write(&kept, "struct Kept {}").unwrap();
+        std::fs::write(&deleted, "struct Deleted {}").unwrap();
+        std::fs::remove_file(&deleted).unwrap();
+
+        let results = vec![search_result(deleted), search_result(kept.clone())];
+        let filtered = filter_missing_files(results).await;
+
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].path, kept);
+    }
+
+    #[test]
+    fn test_find_directory_state_for_subdirectory() {
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+
+        let mut directory_states = HashMap::new();
+        directory_states.insert(PathBuf::from("/repo"), directory_state);
+
+        let found = find_directory_state(&directory_states, &PathBuf::from("/repo/src")).unwrap();
+        assert_eq!(found.status().outstanding(), Some(1));
+        assert!(find_directory_state(&directory_states, &PathBuf::from("/other")).is_none());
+    }
+
+    async fn _test_get_status_for_subdirectory() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let repo = tempdir().unwrap();
+        std::fs::create_dir(repo.path().join("src")).unwrap();
+        std::fs::write(repo.path().join("src/lib.rs"), "struct Repo {}\n").unwrap();
+
+        let notify = index
+            .index_directory(repo.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // The subdirectory reports the status of the root it was indexed under
+        assert!(matches!(
+            index.get_status(repo.path().join("src")).await,
+            IndexingStatus::Indexed
+        ));
+        assert!(matches!(
+            index.get_status(database_dir.path().to_path_buf()).await,
+            IndexingStatus::NotIndexed
+        ));
+    }
+
+    #[test]
+    fn test_get_status_for_subdirectory() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_get_status_for_subdirectory())
+    }
+
+    async fn _test_in_flight_files_bounded() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(SlowEmbeddingProvider {
+                delay: Duration::from_millis(100),
+            }),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+        index.set_max_in_flight_files(2);
+
+        let directory = tempdir().unwrap();
+        for i in 0..10 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        // Sample the jobs outstanding while the directory indexes, which the slow provider
+        // holds in the pipeline long enough to observe
+        let directory_states = index.directory_state.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let monitor = tokio::spawn({
+            let done = done.clone();
+            async move {
+                let mut max_outstanding = 0;
+                while !done.load(Ordering::SeqCst) {
+                    for directory_state in directory_states.lock().unwrap().values() {
+                        let outstanding = directory_state.status().outstanding().unwrap_or(0);
+                        max_outstanding = max_outstanding.max(outstanding);
+                    }
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                max_outstanding
+            }
+        });
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(30), notify.notified())
+            .await
+            .unwrap();
+        done.store(true, Ordering::SeqCst);
+
+        // At most two files are in the pipeline at once, alongside the walk itself
+        let max_outstanding = monitor.await.unwrap();
+        assert!(
+            (2..=3).contains(&max_outstanding),
+            "{max_outstanding} jobs outstanding"
+        );
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 10);
+    }
+
+    #[test]
+    fn test_in_flight_files_bounded() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_in_flight_files_bounded())
+    }
+
+    async fn _test_set_max_in_flight_files_while_indexing() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+        index.set_max_in_flight_files(2);
+
+        // Permits held as files in the pipeline hold them
+        let in_flight_files = index.in_flight_files.clone();
+        let held = in_flight_files.clone().acquire_many_owned(2).await.unwrap();
+
+        // Raising the limit lets only the difference through while files are in flight
+        index.set_max_in_flight_files(4);
+        assert_eq!(in_flight_files.available_permits(), 2);
+        drop(held);
+        assert_eq!(in_flight_files.available_permits(), 4);
+
+        // Lowering it takes effect as the files in flight release their permits
+        let held = in_flight_files.clone().acquire_many_owned(4).await.unwrap();
+        index.set_max_in_flight_files(0);
+        assert_eq!(in_flight_files.available_permits(), 0);
+        drop(held);
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while in_flight_files.available_permits() != 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(in_flight_files.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_set_max_in_flight_files_while_indexing() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_set_max_in_flight_files_while_indexing())
+    }
+
+    #[tokio::test]
+    async fn test_new_retries_database_initialization() {
+        let database_dir = tempdir().unwrap();
+
+        // Hold the database lock, as a previous agent which is shutting down would
+        let locked = surrealdb::Surreal::new::<surrealdb::engine::local::RocksDb>(
+            database_dir.path().join("temp.db"),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(locked);
+        });
+
+        let options = DatabaseOptions {
+            initialize_attempts: 10,
+            initialize_backoff: Duration::from_millis(50),
+            ..DatabaseOptions::default()
+        };
+        assert!(SemanticIndex::new_with_database_options(
+            database_dir.path().to_path_buf(),
+            options
+        )
+        .await
+        .is_ok());
+    }
+
+    #[test]
+    fn test_available_metrics() {
+        let metrics = SemanticIndex::available_metrics();
+        assert!(metrics.contains(&MetricInfo {
+            name: "cosine".to_string(),
+            higher_is_better: true,
+        }));
+        assert!(metrics.contains(&MetricInfo {
+            name: "euclidean".to_string(),
+            higher_is_better: false,
+        }));
+        assert!(metrics.contains(&MetricInfo {
+            name: "dot".to_string(),
+            higher_is_better: true,
+        }));
+        for metric in SimilarityMetric::ALL {
+            assert_eq!(SimilarityMetric::from_name(metric.name()), Some(metric));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_dropped_saturates() {
+        let directory_state = Arc::new(DirectoryState::new("id0".to_string()));
+        directory_state.new_job();
+
+        // Drop more file contexts than jobs were created for, on a separate task
+        let dropped = tokio::spawn({
+            let directory_state = directory_state.clone();
+            async move {
+                for i in 0..3 {
+                    drop(FileContext {
+                        details: FileDetails {
+                            path: PathBuf::from(format!("/tmp/foo{i}")),
+                            directory_state: directory_state.clone(),
+                            _permit: None,
+                        },
+                        documents: vec![],
+                        embeddings: vec![],
+                        strategy_version: None,
+                        template_version: None,
+                        source: Arc::from(""),
+                    });
+                }
+            }
+        });
+        assert!(dropped.await.is_ok());
+
+        assert!(matches!(directory_state.status(), IndexingStatus::Indexed));
+        directory_state.new_job();
+        assert_eq!(directory_state.status().outstanding(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_index_directories() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new(database_dir.path().to_path_buf())
+            .await
+            .unwrap();
+
+        let directories = (0..3).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+        let paths = directories
+            .iter()
+            .map(|directory| directory.path().to_path_buf())
+            .collect::<Vec<PathBuf>>();
+
+        let summaries = index
+            .index_directories(paths.clone())
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        assert_eq!(summaries.len(), 3);
+        for path in paths {
+            assert_eq!(summaries.get(&path), Some(&IndexSummary::default()));
+        }
+    }
+
+    async fn _test_index_with_injected_provider() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let file_path = directory.path().join("foo.rs");
+        std::fs::write(&file_path, "struct CodeContextParser {}\n").unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 1, "parser")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, file_path);
+    }
+
+    #[test]
+    fn test_index_with_injected_provider() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_index_with_injected_provider())
+    }
+
+    async fn _test_reindex_reuses_embeddings() {
+        let database_dir = tempdir().unwrap();
+        let provider = Arc::new(CountingEmbeddingProvider::default());
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            provider.clone(),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let file_path = directory.path().join("foo.rs");
+
+        for (content, expected_texts) in [
+            ("struct Foo {}\n\nstruct Bar {}\n", 2),
+            // Only the added struct is embedded on re-index
+            ("struct Foo {}\n\nstruct Bar {}\n\nstruct Baz {}\n", 3),
+            // Nothing is embedded, but the removed struct is no longer returned
+            ("struct Foo {}\n", 3),
+        ] {
+            std::fs::write(&file_path, content).unwrap();
+            let notify = index
+                .index_directory(directory.path().to_path_buf())
+                .await
+                .unwrap();
+            tokio::time::timeout(Duration::from_secs(10), notify.notified())
+                .await
+                .unwrap();
+
+            assert_eq!(
+                provider.texts.load(std::sync::atomic::Ordering::SeqCst),
+                expected_texts
+            );
+        }
+
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 10, "struct")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn test_reindex_reuses_embeddings() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_reindex_reuses_embeddings())
+    }
+
+    async fn _test_template_change_reembeds() {
+        let database_dir = tempdir().unwrap();
+        let provider = Arc::new(CountingEmbeddingProvider::default());
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            provider.clone(),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        std::fs::write(directory.path().join("foo.rs"), "struct Foo {}\n").unwrap();
+
+        let options = IndexOptions {
+            reparse_if_strategy_changed: true,
+            ..IndexOptions::default()
+        };
+        for (template_version, expected_texts) in [
+            (TEMPLATE_VERSION, 1),
+            (TEMPLATE_VERSION, 1),
+            // Neither the file's content nor its strategy changed, but its span is re-embedded
+            (TEMPLATE_VERSION + 1, 2),
+            (TEMPLATE_VERSION + 1, 2),
+        ] {
+            index.parse_options.template_version = template_version;
+            let notify = index
+                .index_directory_with_options(directory.path().to_path_buf(), options.clone())
+                .await
+                .unwrap();
+            if index
+                .get_status(directory.path().to_path_buf())
+                .await
+                .outstanding()
+                .is_some()
+            {
+                tokio::time::timeout(Duration::from_secs(10), notify.notified())
+                    .await
+                    .unwrap();
+            }
+
+            assert_eq!(
+                provider.texts.load(std::sync::atomic::Ordering::SeqCst),
+                expected_texts
+            );
+        }
+    }
+
+    #[test]
+    fn test_template_change_reembeds() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_template_change_reembeds())
+    }
+
+    async fn _test_relocate_spans() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+        index.set_line_anchors(true);
+
+        let directory = tempdir().unwrap();
+        let file_path = directory.path().join("foo.rs");
+        std::fs::write(&file_path, "struct CodeContextParser {}\n").unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // Shift the span without re-indexing
+        std::fs::write(&file_path, "\nstruct CodeContextParser {}\n").unwrap();
+
+        let options = SearchOptions {
+            relocate_spans: true,
+            ..SearchOptions::default()
+        };
+        let results = index
+            .search_directory_with_options(directory.path().to_path_buf(), 1, "parser", options)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].start_byte, 1);
+        assert_eq!(results[0].end_byte, 28);
+    }
+
+    #[test]
+    fn test_relocate_spans() {
+        build_runtime().unwrap().block_on(_test_relocate_spans())
+    }
+
+    async fn _test_search_with_content() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let content = "struct CodeContextParser {}\n";
+        std::fs::write(directory.path().join("foo.rs"), content).unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 1, "parser")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].content, None);
+
+        let options = SearchOptions {
+            with_content: true,
+            ..SearchOptions::default()
+        };
+        let results = index
+            .search_directory_with_options(directory.path().to_path_buf(), 1, "parser", options)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].content.as_deref(),
+            Some("struct CodeContextParser {}")
+        );
+    }
+
+    #[test]
+    fn test_search_with_content() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_with_content())
+    }
+
+    async fn _test_walk_honours_gitignore() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let ignored = directory.path().join("node_modules");
+        std::fs::create_dir(&ignored).unwrap();
+        std::fs::write(directory.path().join(".gitignore"), "node_modules/\n").unwrap();
+        std::fs::write(ignored.join("vendored.rs"), "struct Vendored {}\n").unwrap();
+        std::fs::write(directory.path().join(".hidden.rs"), "struct Hidden {}\n").unwrap();
+        std::fs::write(directory.path().join("kept.rs"), "struct Kept {}\n").unwrap();
+
+        // Only the file which is neither ignored nor hidden is queued for parsing
+        let summaries = index
+            .index_directories(vec![directory.path().to_path_buf()])
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(summaries[&directory.path().to_path_buf()].files_queued, 1);
+
+        let options = IndexOptions {
+            include_hidden: true,
+            ..IndexOptions::default()
+        };
+        let notify = index
+            .index_directory_with_options(directory.path().to_path_buf(), options)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // Including hidden files still leaves ignored files out
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(
+            files,
+            std::collections::HashSet::from([
+                directory.path().join("kept.rs"),
+                directory.path().join(".hidden.rs"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_walk_honours_gitignore() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_walk_honours_gitignore())
+    }
+
+    async fn _test_max_file_bytes() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let small = directory.path().join("small.rs");
+        let large = directory.path().join("large.rs");
+        std::fs::write(&small, "struct Small {}\n").unwrap();
+        // Padded with comments, so the file is over the limit with a single span to embed
+        std::fs::write(
+            &large,
+            format!(
+                "struct Large {{}}\n{}",
+                "//\n".repeat(DEFAULT_MAX_FILE_BYTES as usize / 3)
+            ),
+        )
+        .unwrap();
+
+        // Only the file within the default limit is queued
+        let summaries = index
+            .index_directories(vec![directory.path().to_path_buf()])
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(summaries[&directory.path().to_path_buf()].files_queued, 1);
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(files, std::collections::HashSet::from([small.clone()]));
+
+        // Lifting the limit indexes the large file too
+        let options = IndexOptions {
+            max_file_bytes: None,
+            ..IndexOptions::default()
+        };
+        let notify = index
+            .index_directory_with_options(directory.path().to_path_buf(), options)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(files, std::collections::HashSet::from([small, large]));
+    }
+
+    #[test]
+    fn test_max_file_bytes() {
+        build_runtime().unwrap().block_on(_test_max_file_bytes())
+    }
+
+    async fn _test_exclude_globs_prune_directories() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let generated = directory.path().join("src").join("generated");
+        std::fs::create_dir_all(&generated).unwrap();
+        std::fs::write(generated.join("tables.rs"), "struct Tables {}\n").unwrap();
+        std::fs::write(
+            directory.path().join("src").join("lib.rs"),
+            "struct Lib {}\n",
+        )
+        .unwrap();
+        std::fs::write(directory.path().join("bundle.min.rs"), "struct Bundle {}\n").unwrap();
+
+        // Patterns matching only the generated directory skip everything beneath it
+        let options = IndexOptions {
+            exclude: vec!["**/generated".to_string(), "*.min.rs".to_string()],
+            ..IndexOptions::default()
+        };
+        let notify = index
+            .index_directory_with_options(directory.path().to_path_buf(), options)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(
+            files,
+            std::collections::HashSet::from([directory.path().join("src").join("lib.rs")])
+        );
+
+        // Patterns are matched against paths relative to the directory, not absolute paths
+        let absolute = directory.path().join("src").join("**");
+        let options = IndexOptions {
+            exclude: vec![absolute.to_string_lossy().to_string()],
+            ..IndexOptions::default()
+        };
+        let notify = index
+            .index_directory_with_options(directory.path().to_path_buf(), options)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 3);
+    }
+
+    #[test]
+    fn test_exclude_globs_prune_directories() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_exclude_globs_prune_directories())
+    }
+
+    async fn _test_list_directories() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(index.list_directories().await.unwrap().is_empty());
+
+        let directory = tempdir().unwrap();
+        std::fs::write(directory.path().join("foo.rs"), "struct Foo {}\n").unwrap();
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let directories = index.list_directories().await.unwrap();
+        assert_eq!(directories.len(), 1);
+        assert_eq!(directories[0].0, directory.path().to_path_buf());
+        assert!(matches!(directories[0].1, IndexingStatus::Indexed));
+        index.shutdown().await.unwrap();
+
+        // Directories indexed by a previous run are listed once the database is reopened
+        let index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+        let directories = index.list_directories().await.unwrap();
+        assert_eq!(directories.len(), 1);
+        assert_eq!(directories[0].0, directory.path().to_path_buf());
+        assert!(matches!(directories[0].1, IndexingStatus::Indexed));
+    }
+
+    #[test]
+    fn test_list_directories() {
+        build_runtime().unwrap().block_on(_test_list_directories())
+    }
+
+    async fn _test_shutdown_writes_indexing_in_progress() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(SlowEmbeddingProvider {
+                delay: Duration::from_millis(100),
+            }),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        for i in 0..5 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        // Shutting down without waiting for indexing to complete still writes every file
+        let _ = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert!(matches!(
+            index.get_status(directory.path().to_path_buf()).await,
+            IndexingStatus::Indexing { .. }
+        ));
+        tokio::time::timeout(Duration::from_secs(30), index.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+        drop(index);
+
+        let index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 5);
+    }
+
+    #[test]
+    fn test_shutdown_writes_indexing_in_progress() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_shutdown_writes_indexing_in_progress())
+    }
+
+    async fn _test_walk_skips_excluded_directories() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let node_modules = directory.path().join("web").join("node_modules");
+        std::fs::create_dir_all(&node_modules).unwrap();
+        std::fs::write(node_modules.join("left_pad.rs"), "fn left_pad() {}\n").unwrap();
+        std::fs::write(directory.path().join("web").join("app.rs"), "fn app() {}\n").unwrap();
+
+        // Dependencies are skipped by default, even without an ignore file
+        let summaries = index
+            .index_directories(vec![directory.path().to_path_buf()])
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(summaries[&directory.path().to_path_buf()].files_queued, 1);
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(
+            files,
+            std::collections::HashSet::from([directory.path().join("web").join("app.rs")])
+        );
+
+        // Clearing the excluded directories indexes them
+        let options = IndexOptions {
+            excluded_directories: vec![],
+            ..IndexOptions::default()
+        };
+        let notify = index
+            .index_directory_with_options(directory.path().to_path_buf(), options)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert!(files.contains(&node_modules.join("left_pad.rs")));
+    }
+
+    #[test]
+    fn test_walk_skips_excluded_directories() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_walk_skips_excluded_directories())
+    }
+
+    async fn _test_walk_honours_nested_gitignore() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let crate_dir = directory.path().join("crates").join("parser");
+        let generated = crate_dir.join("generated");
+        let target_spec = directory.path().join("target_spec");
+        std::fs::create_dir_all(&generated).unwrap();
+        std::fs::create_dir(&target_spec).unwrap();
+        std::fs::write(crate_dir.join(".gitignore"), "generated/\n").unwrap();
+        std::fs::write(generated.join("tables.rs"), "struct Tables {}\n").unwrap();
+        std::fs::write(crate_dir.join("lib.rs"), "struct Parser {}\n").unwrap();
+        std::fs::write(target_spec.join("spec.rs"), "struct Spec {}\n").unwrap();
+
+        // Files ignored by a nested .gitignore are never queued, while directories merely
+        // named like build output are walked
+        let summaries = index
+            .index_directories(vec![directory.path().to_path_buf()])
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(summaries[&directory.path().to_path_buf()].files_queued, 2);
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(
+            files,
+            std::collections::HashSet::from([
+                crate_dir.join("lib.rs"),
+                target_spec.join("spec.rs")
+            ])
+        );
+    }
+
+    #[test]
+    fn test_walk_honours_nested_gitignore() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_walk_honours_nested_gitignore())
+    }
+
+    async fn _test_include_exclude_globs() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let src = directory.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("lib.rs"), "struct Lib {}\n").unwrap();
+        std::fs::write(src.join("lib_test.rs"), "struct LibTest {}\n").unwrap();
+        std::fs::write(directory.path().join("build.rs"), "struct Build {}\n").unwrap();
+
+        // Test files are excluded, while every other supported file is kept
+        let options = IndexOptions {
+            exclude: vec!["*_test.rs".to_string()],
+            ..IndexOptions::default()
+        };
+        let notify = index
+            .index_directory_with_options(directory.path().to_path_buf(), options)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(
+            files,
+            std::collections::HashSet::from([
+                src.join("lib.rs"),
+                directory.path().join("build.rs")
+            ])
+        );
+
+        // Narrowing the includes drops files indexed previously which no longer match
+        let options = IndexOptions {
+            include: vec!["src/**".to_string()],
+            exclude: vec!["*_test.rs".to_string()],
+            ..IndexOptions::default()
+        };
+        let notify = index
+            .index_directory_with_options(directory.path().to_path_buf(), options)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(files, std::collections::HashSet::from([src.join("lib.rs")]));
+
+        // Invalid patterns fail the walk
+        let options = IndexOptions {
+            include: vec!["src/[".to_string()],
+            ..IndexOptions::default()
+        };
+        assert!(index
+            .index_directory_with_options(directory.path().to_path_buf(), options)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_include_exclude_globs() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_include_exclude_globs())
+    }
+
+    /// Panics on lookup, taking down the embedding task which consults it.
+    struct PanickingEmbeddingCache;
+
+    #[async_trait::async_trait]
+    impl EmbeddingCache for PanickingEmbeddingCache {
+        async fn get(&self, _sha: &[u8]) -> Option<Vec<f32>> {
+            panic!("embedding cache failed");
+        }
+
+        async fn insert(&self, _sha: &[u8], _embedding: &[f32]) -> anyhow::Result<()> {
+            anyhow::Ok(())
+        }
+    }
+
+    async fn _test_index_fails_once_embedding_task_stopped() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::builder(database_dir.path().to_path_buf())
+            .embedding_provider(Arc::new(FakeEmbeddingProvider))
+            .embedding_cache(Arc::new(PanickingEmbeddingCache))
+            .build()
+            .await
+            .unwrap();
+
+        // The file is dropped as the embedding task panics, rather than hanging the directory
+        let directory = tempdir().unwrap();
+        std::fs::write(directory.path().join("foo.rs"), "struct Foo {}\n").unwrap();
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // The parse task stops once it sees the embedding task has, so further indexing fails
+        tokio::time::timeout(Duration::from_secs(10), index.parse_sender.closed())
+            .await
+            .unwrap();
+        let other = tempdir().unwrap();
+        std::fs::write(other.path().join("bar.rs"), "struct Bar {}\n").unwrap();
+        let err = index
+            .index_directory(other.path().to_path_buf())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("indexing pipeline has stopped"));
+        assert!(matches!(
+            index.get_status(other.path().to_path_buf()).await,
+            IndexingStatus::Indexed
+        ));
+    }
+
+    #[test]
+    fn test_index_fails_once_embedding_task_stopped() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_index_fails_once_embedding_task_stopped())
+    }
+
+    async fn _test_search_min_similarity() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let tokenize_path = directory.path().join("tokenize.rs");
+        std::fs::write(&tokenize_path, "struct Tokenize {}\n").unwrap();
+        std::fs::write(directory.path().join("other.rs"), "struct Unrelated {}\n").unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 2, "tokenize")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].path, tokenize_path);
+        let threshold = (results[0].similarity + results[1].similarity) / 2.0;
+
+        // Narrowing the threshold returns fewer than `n` results, down to none at all
+        for (min_similarity, expected) in [(threshold, vec![tokenize_path.clone()]), (1.01, vec![])]
+        {
+            let options = SearchOptions {
+                min_similarity: Some(min_similarity),
+                ..SearchOptions::default()
+            };
+            let results = index
+                .search_directory_with_options(
+                    directory.path().to_path_buf(),
+                    2,
+                    "tokenize",
+                    options,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                results
+                    .into_iter()
+                    .map(|result| result.path)
+                    .collect::<Vec<PathBuf>>(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_search_min_similarity() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_min_similarity())
+    }
+
+    async fn _test_search_negative() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let a_path = directory.path().join("a.rs");
+        let b_path = directory.path().join("b.rs");
+        std::fs::write(
+            &a_path,
+            "struct A { database: u8, tests: u8, database_tests: u8 }\n",
+        )
+        .unwrap();
+        std::fs::write(&b_path, "struct B { database: u8 }\n").unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // Mentioning the database twice, a.rs ranks first on its own
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 2, "database")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].path, a_path);
+
+        let options = SearchOptions {
+            negative: Some("tests".to_string()),
+            ..SearchOptions::default()
+        };
+        let results = index
+            .search_directory_with_options(directory.path().to_path_buf(), 2, "database", options)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].path, b_path);
+        assert_eq!(results[1].path, a_path);
+    }
+
+    #[test]
+    fn test_search_negative() {
+        build_runtime().unwrap().block_on(_test_search_negative())
+    }
+
+    async fn _test_search_sort_order() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        std::fs::write(
+            directory.path().join("a.rs"),
+            "struct A { database: u8 }\n\nstruct C { database_pool: u8 }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.path().join("b.rs"),
+            "struct B { database: u8, database_pool: u8 }\n\nstruct D { pool: u8 }\n",
+        )
+        .unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let relevance = index
+            .search_directory(directory.path().to_path_buf(), 3, "database pool")
+            .await
+            .unwrap();
+        assert_eq!(relevance.len(), 3);
+
+        let options = SearchOptions {
+            sort: SortOrder::FileThenPosition,
+            ..SearchOptions::default()
+        };
+        let results = index
+            .search_directory_with_options(
+                directory.path().to_path_buf(),
+                3,
+                "database pool",
+                options,
+            )
+            .await
+            .unwrap();
+
+        // The same results are selected, with each file's results contiguous and in order
+        let locations = |results: &[SearchResult]| {
+            results
+                .iter()
+                .map(|result| (result.path.clone(), result.start_byte))
+                .collect::<Vec<_>>()
+        };
+        let mut expected = locations(&relevance);
+        expected.sort();
+        assert_eq!(locations(&results), expected);
+        for window in results.windows(2) {
+            assert!(
+                window[0].path < window[1].path
+                    || (window[0].path == window[1].path
+                        && window[0].start_byte < window[1].start_byte)
+            );
+        }
+    }
+
+    #[test]
+    fn test_search_sort_order() {
+        build_runtime().unwrap().block_on(_test_search_sort_order())
+    }
+
+    async fn _test_embed_text() {
+        let database_dir = tempdir().unwrap();
+        let index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let embedding = index.embed_text("parse the statement").await.unwrap();
+        let expected = BagOfWordsEmbeddingProvider
+            .embed_texts(vec!["parse the statement".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(embedding.len(), expected[0].len());
+        assert_eq!(embedding, expected[0]);
+
+        // Nothing is written to the index
+        assert!(index.list_directories().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_embed_text() {
+        build_runtime().unwrap().block_on(_test_embed_text())
+    }
+
+    async fn _test_search_all() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let first = tempdir().unwrap();
+        let second = tempdir().unwrap();
+        let first_path = first.path().join("a.rs");
+        let second_path = second.path().join("b.rs");
+        std::fs::write(&first_path, "struct A { database: u8 }\n").unwrap();
+        std::fs::write(
+            &second_path,
+            "struct B { database: u8, database_pool: u8 }\n",
+        )
+        .unwrap();
+
+        let summaries = index
+            .index_directories(vec![
+                first.path().to_path_buf(),
+                second.path().to_path_buf(),
+            ])
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(summaries.len(), 2);
+
+        // Spans from both directories are ranked together, each carrying the file it is from
+        let results = index.search_all(10, "database pool").await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].path, second_path);
+        assert_eq!(results[1].path, first_path);
+        assert!(results[0].similarity > results[1].similarity);
+
+        let results = index.search_all(1, "database pool").await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, second_path);
+    }
+
+    #[test]
+    fn test_search_all() {
+        build_runtime().unwrap().block_on(_test_search_all())
+    }
+
+    async fn _test_reparse_if_strategy_changed() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        std::fs::write(
+            directory.path().join("foo.rs"),
+            "struct Foo {}\n\ntrait Bar {}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.path().join("bar.py"),
+            "def parse(content):\n    return content.split()\n",
+        )
+        .unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // Add trait captures to the rust query, and edit the python file without re-indexing
+        let config_path = database_dir.path().join("extensions.json");
+        std::fs::write(
+            &config_path,
+            r#"{ "rs": { "language": "rust", "query": "(struct_item) @item (trait_item) @item" } }"#,
+        )
+        .unwrap();
+        index.load_extension_config(&config_path).unwrap();
+        std::fs::write(
+            directory.path().join("bar.py"),
+            "def parse(content):\n    return content.split()\n\n\nclass Parser:\n    pass\n",
+        )
+        .unwrap();
+
+        let options = IndexOptions {
+            reparse_if_strategy_changed: true,
+            ..IndexOptions::default()
+        };
+        let notify = index
+            .index_directory_with_options(directory.path().to_path_buf(), options)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 10, "parser")
+            .await
+            .unwrap();
+        let symbol_kinds = |file_name: &str| {
+            let mut symbol_kinds = results
+                .iter()
+                .filter(|result| result.path.ends_with(file_name))
+                .filter_map(|result| result.symbol_kind.clone())
+                .collect::<Vec<String>>();
+            symbol_kinds.sort();
+            symbol_kinds
+        };
+
+        // The rust file is re-parsed with the new query, the python file is left as indexed
+        assert_eq!(
+            symbol_kinds("foo.rs"),
+            vec!["struct_item".to_string(), "trait_item".to_string()]
+        );
+        assert_eq!(
+            symbol_kinds("bar.py"),
+            vec!["function_definition".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_reparse_if_strategy_changed() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_reparse_if_strategy_changed())
+    }
+
+    async fn _test_max_spans_per_file() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+        index.set_max_spans_per_file(10);
+        let mut events = index.subscribe_events();
+
+        let directory = tempdir().unwrap();
+        let file_path = directory.path().join("generated.rs");
+        let content = (0..50)
+            .map(|i| format!("struct Generated{i} {{}}\n"))
+            .collect::<String>();
+        std::fs::write(&file_path, content).unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 100, "generated")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 10);
+
+        let mut truncated = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let IndexEvent::SpansTruncated { .. } = event {
+                truncated.push(event);
+            }
+        }
+        assert_eq!(
+            truncated,
+            vec![IndexEvent::SpansTruncated {
+                path: file_path,
+                spans: 50,
+                max_spans_per_file: 10,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_max_spans_per_file() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_max_spans_per_file())
+    }
+
+    async fn _test_cancel_indexing() {
+        let database_dir = tempdir().unwrap();
+        let provider = Arc::new(CountingEmbeddingProvider::default());
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            provider.clone(),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        for i in 0..50 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        // Hold the files in the pipeline, so they are outstanding when cancelled
+        index.pause_embedding();
+        index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        index
+            .canceller()
+            .cancel(&directory.path().to_path_buf())
+            .unwrap();
+        index.resume_embedding();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        assert!(matches!(
+            index.get_status(directory.path().to_path_buf()).await,
+            IndexingStatus::Cancelled
+        ));
+        assert_eq!(provider.texts.load(std::sync::atomic::Ordering::SeqCst), 0);
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 100, "struct")
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+
+        // Indexing again starts afresh
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 100, "struct")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 50);
+        assert!(index
+            .cancel_indexing(PathBuf::from("/not/indexed"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_cancel_indexing() {
+        build_runtime().unwrap().block_on(_test_cancel_indexing())
+    }
+
+    async fn _test_remove_directory() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        std::fs::write(directory.path().join("foo.rs"), "struct Foo {}\n").unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        index
+            .remove_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            index.get_status(directory.path().to_path_buf()).await,
+            IndexingStatus::NotIndexed
+        ));
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 10, "struct")
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+
+        // Removing is idempotent, and a no-op for directories never indexed
+        index
+            .remove_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        index
+            .remove_directory(PathBuf::from("/not/indexed"))
+            .await
+            .unwrap();
+
+        // The directory can be indexed afresh once removed
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 10, "struct")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn test_remove_directory() {
+        build_runtime().unwrap().block_on(_test_remove_directory())
+    }
+
+    async fn _test_reembed_directory() {
+        let database_dir = tempdir().unwrap();
+        let provider = Arc::new(CountingEmbeddingProvider::default());
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            provider.clone(),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        for i in 0..5 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n\nenum Bar{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+        let indexed = provider.texts.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(indexed, 10);
+
+        let mut events = index.subscribe_events();
+        let summary = index
+            .reembed_directory(directory.path().to_path_buf())
+            .await
+            .unwrap()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            summary,
+            ReembedSummary {
+                files_reembedded: 5,
+                spans_reembedded: 10,
+            }
+        );
+        assert_eq!(
+            provider.texts.load(std::sync::atomic::Ordering::SeqCst),
+            indexed + 10
+        );
+
+        let mut progress = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let IndexEvent::ReembedProgress {
+                spans_reembedded,
+                total,
+                ..
+            } = event
+            {
+                assert_eq!(total, 10);
+                progress.push(spans_reembedded);
+            }
+        }
+        assert_eq!(progress, vec![2, 4, 6, 8, 10]);
+
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 100, "struct")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 10);
+    }
+
+    #[test]
+    fn test_reembed_directory() {
+        build_runtime().unwrap().block_on(_test_reembed_directory())
+    }
+
+    async fn _test_reembed_directory_while_indexing() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        for i in 0..5 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // Re-embedding is refused while the directory is indexing
+        index.pause_embedding();
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        let err = index
+            .reembed_directory(directory.path().to_path_buf())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<AlreadyIndexing>().is_some());
+        index.resume_embedding();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // And indexing is refused while the directory is re-embedding
+        index.pause_embedding();
+        let handle = index
+            .reembed_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        let err = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<AlreadyIndexing>().is_some());
+        index.resume_embedding();
+        let summary = tokio::time::timeout(Duration::from_secs(10), handle)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.files_reembedded, 5);
+    }
+
+    #[test]
+    fn test_reembed_directory_while_indexing() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_reembed_directory_while_indexing())
+    }
+
+    /// Records the size of each batch of texts it is asked to embed.
+    #[derive(Default)]
+    struct BatchRecordingProvider {
+        batches: std::sync::Mutex<Vec<usize>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EmbeddingProvider for BatchRecordingProvider {
+        async fn embed_texts(&self, texts: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+            self.batches.lock().unwrap().push(texts.len());
+            anyhow::Ok(texts.iter().map(|_| vec![1.0, 0.0]).collect())
+        }
+
+        async fn embed_query(&self, _query: String) -> anyhow::Result<Vec<f32>> {
+            anyhow::Ok(vec![1.0, 0.0])
+        }
+    }
+
+    async fn _test_builder_applies_config() {
+        let database_dir = tempdir().unwrap();
+        let provider = Arc::new(BatchRecordingProvider::default());
+        let mut index = SemanticIndex::builder(database_dir.path().to_path_buf())
+            .embedding_provider(provider.clone())
+            .embedding_batch_size(2)
+            .flush_interval(Duration::from_millis(50))
+            .max_concurrent_directories(2)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(index.max_concurrent_directories, 2);
+
+        let directory = tempdir().unwrap();
+        std::fs::write(
+            directory.path().join("foo.rs"),
+            "struct Foo {}\n\nstruct Bar {}\n\nstruct Baz {}\n\nstruct Qux {}\n\nstruct Quux {}\n",
+        )
+        .unwrap();
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let mut batches = provider.batches.lock().unwrap().clone();
+        batches.sort();
+        assert_eq!(batches, vec![1, 2, 2]);
+    }
+
+    #[test]
+    fn test_builder_applies_config() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_builder_applies_config())
+    }
+
+    async fn _test_search_directory_stream() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        for i in 0..25 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // More results than fit in a single page are streamed, without repeats
+        let mut receiver = index
+            .search_directory_stream(
+                directory.path().to_path_buf(),
+                23,
+                "struct",
+                SearchOptions::default(),
+            )
+            .await
+            .unwrap();
+        let mut ids = std::collections::HashSet::new();
+        while let Some(result) = receiver.recv().await {
+            ids.insert(result.unwrap().id);
+        }
+        assert_eq!(ids.len(), 23);
+
+        let mut receiver = index
+            .search_directory_stream(
+                directory.path().to_path_buf(),
+                100,
+                "struct",
+                SearchOptions {
+                    with_content: true,
+                    ..SearchOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        let mut streamed = 0;
+        while let Some(result) = receiver.recv().await {
+            let result = result.unwrap();
+            assert!(result.content.unwrap().starts_with("struct Foo"));
+            streamed += 1;
+        }
+        assert_eq!(streamed, 25);
+
+        // Other metrics can't be paged, so are refused rather than ignored
+        let err = index
+            .search_directory_stream(
+                directory.path().to_path_buf(),
+                10,
+                "struct",
+                SearchOptions {
+                    metric: SimilarityMetric::Euclidean,
+                    ..SearchOptions::default()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cosine"));
+    }
+
+    #[test]
+    fn test_search_directory_stream() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_directory_stream())
+    }
+
+    async fn _test_search_directory_stream_negative() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        std::fs::write(directory.path().join("alpha.rs"), "struct Alpha {}\n").unwrap();
+        std::fs::write(directory.path().join("beta.rs"), "struct Beta {}\n").unwrap();
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // Spans similar to the negative term are streamed after the rest
+        let mut receiver = index
+            .search_directory_stream(
+                directory.path().to_path_buf(),
+                2,
+                "struct",
+                SearchOptions {
+                    negative: Some("Alpha".to_string()),
+                    ..SearchOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+        let mut paths = Vec::new();
+        while let Some(result) = receiver.recv().await {
+            paths.push(result.unwrap().path);
+        }
+        assert_eq!(
+            paths,
+            vec![
+                directory.path().join("beta.rs"),
+                directory.path().join("alpha.rs")
+            ]
+        );
+    }
+
+    async fn _test_plain_text_fallback() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        std::fs::write(
+            directory.path().join("notes.txt"),
+            "abcdefghi\n".repeat(500),
+        )
+        .unwrap();
+
+        // Windows of 1000 bytes overlapping by 200 start every 800 bytes
+        index
+            .set_plain_text_fallback(Some(DEFAULT_PLAIN_TEXT_WINDOW))
+            .unwrap();
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+        let results = index
+            .search_directory(directory.path().to_path_buf(), 100, "notes")
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 6);
+
+        // Overlap as large as the window is refused
+        assert!(index.set_plain_text_fallback(Some((100, 100))).is_err());
+    }
+
+    #[test]
+    fn test_plain_text_fallback() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_plain_text_fallback())
+    }
+
+    #[test]
+    fn test_search_directory_stream_negative() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_directory_stream_negative())
+    }
+
+    async fn _test_index_directory_while_indexing() {
+        let database_dir = tempdir().unwrap();
+        let provider = Arc::new(CountingEmbeddingProvider::default());
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            provider.clone(),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        for i in 0..10 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        // Hold the files in the pipeline, so the first index is still in progress
+        index.pause_embedding();
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        let outstanding = index
+            .get_status(directory.path().to_path_buf())
+            .await
+            .outstanding();
+        assert!(outstanding.is_some());
+
+        let err = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<AlreadyIndexing>().is_some());
+        assert_eq!(
+            index
+                .get_status(directory.path().to_path_buf())
+                .await
+                .outstanding(),
+            outstanding
+        );
+
+        // The first index still completes, and its notify fires
+        index.resume_embedding();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+        assert!(matches!(
+            index.get_status(directory.path().to_path_buf()).await,
+            IndexingStatus::Indexed
+        ));
+        assert_eq!(provider.texts.load(std::sync::atomic::Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn test_index_directory_while_indexing() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_index_directory_while_indexing())
+    }
+
+    async fn _test_reindex_directory() {
+        let database_dir = tempdir().unwrap();
+        let provider = Arc::new(CountingEmbeddingProvider::default());
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            provider.clone(),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        for i in 0..3 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        for _ in 0..2 {
+            let notify = index
+                .index_directory(directory.path().to_path_buf())
+                .await
+                .unwrap();
+            tokio::time::timeout(Duration::from_secs(10), notify.notified())
+                .await
+                .unwrap();
+        }
+
+        // Indexing again reuses the stored embeddings
+        assert_eq!(provider.texts.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        let notify = index
+            .reindex_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // Every span is embedded again, and the directory holds the same files as before
+        assert_eq!(provider.texts.load(std::sync::atomic::Ordering::SeqCst), 6);
+        let files = index
+            .vector_db
+            .get_files_for_directory(&directory.path().to_path_buf())
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 3);
+        assert!(matches!(
+            index.get_status(directory.path().to_path_buf()).await,
+            IndexingStatus::Indexed
+        ));
+    }
+
+    #[test]
+    fn test_reindex_directory() {
+        build_runtime().unwrap().block_on(_test_reindex_directory())
+    }
+
+    async fn _test_concurrent_index_directory() {
+        let database_dir = tempdir().unwrap();
+        let index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        for i in 0..10 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        // Shared as the server shares it, with indexing held so neither call can finish first
+        index.pause_embedding();
+        let index = Arc::new(Mutex::new(index));
+        let calls = (0..2)
+            .map(|_| {
+                let index = index.clone();
+                let directory = directory.path().to_path_buf();
+                tokio::spawn(async move { index.lock().await.index_directory(directory).await })
+            })
+            .collect::<Vec<_>>();
+        let mut notifies = Vec::new();
+        let mut rejected = 0;
+        for call in calls {
+            match call.await.unwrap() {
+                Ok(notify) => notifies.push(notify),
+                Err(err) => {
+                    assert!(err.downcast_ref::<AlreadyIndexing>().is_some());
+                    rejected += 1;
+                }
+            }
+        }
+        assert_eq!(notifies.len(), 1);
+        assert_eq!(rejected, 1);
+
+        // The accepted call's waiters are still woken once indexing completes
+        index.lock().await.resume_embedding();
+        tokio::time::timeout(Duration::from_secs(10), notifies[0].notified())
+            .await
+            .unwrap();
+
+        // Listing a directory twice indexes it once, with both entries tracked by one state
+        let summaries = index
+            .lock()
+            .await
+            .index_directories(vec![
+                directory.path().to_path_buf(),
+                directory.path().to_path_buf(),
+            ])
+            .await
+            .unwrap();
+        let summaries = tokio::time::timeout(Duration::from_secs(10), summaries)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert!(matches!(
+            index
+                .lock()
+                .await
+                .get_status(directory.path().to_path_buf())
+                .await,
+            IndexingStatus::Indexed
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_index_directory() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_concurrent_index_directory())
+    }
+
+    async fn _test_watch_search() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        let mut results = index
+            .watch_search(directory.path().to_path_buf(), "tokenize", 5)
+            .await
+            .unwrap();
+
+        // Nothing is indexed yet
+        let initial = tokio::time::timeout(Duration::from_secs(10), results.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(initial.is_empty());
+
+        let file_path = directory.path().join("foo.rs");
+        std::fs::write(&file_path, "struct Tokenize {}\n").unwrap();
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        let updated = tokio::time::timeout(Duration::from_secs(10), results.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].path, file_path);
+    }
+
+    #[test]
+    fn test_watch_search() {
+        build_runtime().unwrap().block_on(_test_watch_search())
+    }
+
+    async fn _test_watch_status() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(FakeEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        for i in 0..5 {
+            std::fs::write(
+                directory.path().join(format!("foo{i}.rs")),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        index.pause_embedding();
+        index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        let mut statuses = index.watch_status(directory.path().to_path_buf());
+        let first = statuses.recv().await.unwrap();
+        assert!(matches!(first, IndexingStatus::Paused { .. }));
+
+        // Updates stream until the directory is indexed, which is the final status
+        index.resume_embedding();
+        let mut last = first;
+        while let Some(status) = tokio::time::timeout(Duration::from_secs(10), statuses.recv())
+            .await
+            .unwrap()
+        {
+            last = status;
+        }
+        assert!(matches!(last, IndexingStatus::Indexed));
+
+        // Removing the directory mid-index ends the stream
+        index.pause_embedding();
+        std::fs::write(directory.path().join("bar.rs"), "struct Bar {}\n").unwrap();
+        index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        let mut statuses = index.watch_status(directory.path().to_path_buf());
+        assert!(statuses.recv().await.unwrap().outstanding().is_some());
+        index
+            .remove_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        let mut last = None;
+        while let Some(status) = tokio::time::timeout(Duration::from_secs(10), statuses.recv())
+            .await
+            .unwrap()
+        {
+            last = Some(status);
+        }
+        assert!(matches!(last, Some(IndexingStatus::NotIndexed)));
+        index.resume_embedding();
+    }
+
+    #[test]
+    fn test_watch_status() {
+        build_runtime().unwrap().block_on(_test_watch_status())
+    }
+
+    async fn _test_watch_directory() {
+        let database_dir = tempdir().unwrap();
+        let provider = Arc::new(CountingEmbeddingProvider::default());
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            provider.clone(),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        index
+            .watch_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+
+        async fn wait_for_results(index: &SemanticIndex, directory: &PathBuf, expected: usize) {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    let results = index
+                        .search_directory(directory.clone(), 10, "struct")
+                        .await
+                        .unwrap();
+                    if results.len() == expected {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+
+        // Repeated saves within the debounce are only embedded once
+        let file_path = directory.path().join("foo.rs");
+        for _ in 0..5 {
+            std::fs::write(&file_path, "struct CodeContextParser {}\n").unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        wait_for_results(&index, &directory.path().to_path_buf(), 1).await;
+        assert_eq!(provider.texts.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        std::fs::remove_file(&file_path).unwrap();
+        wait_for_results(&index, &directory.path().to_path_buf(), 0).await;
+
+        // Changes are ignored once unwatched
+        assert!(index.unwatch_directory(&directory.path().to_path_buf()));
+        assert!(!index.unwatch_directory(&directory.path().to_path_buf()));
+        std::fs::write(&file_path, "struct CodeContextParser {}\n").unwrap();
+        tokio::time::sleep(WATCH_DEBOUNCE * 3).await;
+        assert_eq!(provider.texts.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_watch_directory() {
+        build_runtime().unwrap().block_on(_test_watch_directory())
+    }
+
+    #[test]
+    fn test_search_during_bulk_index() {
+        // Run on a single threaded runtime, where a background loop which never yields would
+        // block the search entirely. A thread is spawned for the larger stack surrealdb needs.
+        let stack_size = 20 * 1024 * 1024;
+        std::thread::Builder::new()
+            .stack_size(stack_size)
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(async {
+                        let database_dir = tempdir().unwrap();
+                        let mut index = SemanticIndex::new_with_provider(
+                            database_dir.path().to_path_buf(),
+                            Arc::new(SlowEmbeddingProvider {
+                                delay: Duration::from_millis(100),
+                            }),
+                            DatabaseOptions::default(),
+                        )
+                        .await
+                        .unwrap();
+
+                        let directory = tempdir().unwrap();
+                        for i in 0..500 {
+                            std::fs::write(
+                                directory.path().join(format!("foo{i}.rs")),
+                                format!("struct Foo{i} {{}}\n"),
+                            )
+                            .unwrap();
+                        }
+
+                        let path = directory.path().to_path_buf();
+                        let notify = index.index_directory(path.clone()).await.unwrap();
+
+                        // The slow provider keeps embedding going well after the walk, so the
+                        // search runs alongside indexing
+                        let search = async {
+                            let results = index.search_directory(path.clone(), 5, "struct").await;
+                            (results, index.get_status(path.clone()).await)
+                        };
+                        let (searched, indexed) = tokio::join!(
+                            tokio::time::timeout(Duration::from_secs(30), search),
+                            tokio::time::timeout(Duration::from_secs(60), notify.notified()),
+                        );
+
+                        let (results, status) = searched.unwrap();
+                        assert!(results.is_ok());
+                        assert!(matches!(status, IndexingStatus::Indexing { .. }));
+                        assert!(indexed.is_ok());
+                    })
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    async fn _test_search_directory_with_facets() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(BagOfWordsEmbeddingProvider),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let directory = tempdir().unwrap();
+        std::fs::write(
+            directory.path().join("lib.rs"),
+            "struct Database {}\n\nstruct Config {}\n\nstruct Results {}\n\nenum Format {}\n",
+        )
+        .unwrap();
+        let notify = index
+            .index_directory(directory.path().to_path_buf())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), notify.notified())
+            .await
+            .unwrap();
+
+        // Facets count every candidate, however few results are returned
+        for n in [1, 10] {
+            let (results, facets) = index
+                .search_directory_with_facets(
+                    directory.path().to_path_buf(),
+                    n,
+                    "parse config",
+                    SearchOptions::default(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(results.len(), n.min(4));
+            assert_eq!(facets.get("struct_item"), Some(&3));
+            assert_eq!(facets.get("enum_item"), Some(&1));
+        }
+    }
+
+    #[test]
+    fn test_search_directory_with_facets() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_search_directory_with_facets())
+    }
+
+    #[test]
+    fn test_symbol_kind_facets() {
+        let results = ["function_item", "function_item", "struct_item"]
+            .iter()
+            .map(|symbol_kind| SearchResult {
+                symbol_kind: Some(symbol_kind.to_string()),
+                ..search_result(PathBuf::from("/tmp/foo.rs"))
+            })
+            .collect::<Vec<SearchResult>>();
+
+        let facets = symbol_kind_facets(&results);
+        assert_eq!(facets.get("function_item"), Some(&2));
+        assert_eq!(facets.get("struct_item"), Some(&1));
+        assert_eq!(facets.get("enum_item"), None);
+    }
+
+    async fn _test_directory_slots_bounded() {
+        let database_dir = tempdir().unwrap();
+        let mut index = SemanticIndex::new_with_provider(
+            database_dir.path().to_path_buf(),
+            Arc::new(SlowEmbeddingProvider {
+                delay: Duration::from_millis(100),
+            }),
+            DatabaseOptions::default(),
+        )
+        .await
+        .unwrap();
+        index.set_max_concurrent_directories(2);
+
+        let directories = (0..5).map(|_| tempdir().unwrap()).collect::<Vec<_>>();
+        for (i, directory) in directories.iter().enumerate() {
+            std::fs::write(
+                directory.path().join("foo.rs"),
+                format!("struct Foo{i} {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        // Sample the directories holding a slot, that is walking or embedding, while they index
+        let directory_states = index.directory_state.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let monitor = tokio::spawn({
+            let done = done.clone();
+            async move {
+                let mut max_active = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let active = directory_states
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .filter(|directory_state| directory_state.slot.lock().unwrap().is_some())
+                        .count();
+                    max_active = max_active.max(active);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                max_active
+            }
+        });
+
+        let handle = index
+            .index_directories(
+                directories
+                    .iter()
+                    .map(|directory| directory.path().to_path_buf())
+                    .collect(),
+            )
+            .await
+            .unwrap();
+        let summaries = tokio::time::timeout(Duration::from_secs(30), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        done.store(true, Ordering::SeqCst);
+
+        assert_eq!(summaries.len(), 5);
+        let max_active = monitor.await.unwrap();
+        assert!(
+            (1..=2).contains(&max_active),
+            "{max_active} directories active"
+        );
+    }
+
+    #[test]
+    fn test_directory_slots_bounded() {
+        build_runtime()
+            .unwrap()
+            .block_on(_test_directory_slots_bounded())
     }
 }
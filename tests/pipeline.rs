@@ -0,0 +1,69 @@
+use auden::embedding::base::BagOfWordsEmbeddingProvider;
+use auden::runtime::build_runtime;
+use auden::semantic_index::{DatabaseOptions, SemanticIndex};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::tempdir;
+
+/// Files indexed by the pipeline test, each holding a single impl with distinct words.
+const FILES: [(&str, &str); 3] = [
+    (
+        "lexer.rs",
+        "impl Lexer {\n    fn split_words(input: &str) -> Vec<&str> {\n        input.split_whitespace().collect()\n    }\n}\n",
+    ),
+    (
+        "parser.rs",
+        "impl Parser {\n    fn parse_statement(line: &str) -> Statement {\n        Statement::new(line)\n    }\n}\n",
+    ),
+    (
+        "storage.rs",
+        "impl Storage {\n    fn persist_rows(rows: &[Row]) {\n        for row in rows {\n            row.save();\n        }\n    }\n}\n",
+    ),
+];
+
+async fn _test_index_then_search() {
+    let database_dir = tempdir().unwrap();
+    let mut index = SemanticIndex::new_with_provider(
+        database_dir.path().to_path_buf(),
+        Arc::new(BagOfWordsEmbeddingProvider),
+        DatabaseOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    let directory = tempdir().unwrap();
+    for (name, content) in FILES {
+        std::fs::write(directory.path().join(name), content).unwrap();
+    }
+
+    let notify = index
+        .index_directory(directory.path().to_path_buf())
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), notify.notified())
+        .await
+        .unwrap();
+
+    let results = index
+        .search_directory(directory.path().to_path_buf(), 3, "parse statement")
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 3);
+
+    // The impl's span is returned first, covering the whole impl
+    let (_, content) = FILES[1];
+    assert_eq!(results[0].path, directory.path().join("parser.rs"));
+    assert_eq!(results[0].start_byte, 0);
+    assert_eq!(results[0].end_byte, content.trim_end().len());
+    assert_eq!(results[0].start_line, Some(0));
+    assert_eq!(results[0].start_col, Some(0));
+    assert_eq!(results[0].end_line, Some(4));
+    assert_eq!(results[0].end_col, Some(1));
+    assert_eq!(results[0].symbol_kind.as_deref(), Some("impl_item"));
+    assert!(results[0].similarity > results[1].similarity);
+}
+
+#[test]
+fn test_index_then_search() {
+    build_runtime().unwrap().block_on(_test_index_then_search())
+}